@@ -0,0 +1,351 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::classifier::{PacketInfo, Policy, Protocol, Reason, Verdict, classify_info};
+use crate::connection_tracker::ConnectionTracker;
+use crate::local_addr::LocalAddresses;
+use crate::packet_io::{PacketIoError, PacketMeta, PacketSource};
+
+/// Process ID given to the stand-in process owning the tracked ports
+const OFFLINE_PID: u32 = u32::MAX;
+
+/// Connection tracker in which `profile` owns the given local UDP ports on every address
+///
+/// Stands in for WMI when no live process can be queried.
+pub fn offline_tracker(profile: &str, udp_ports: &[u16]) -> ConnectionTracker {
+	let tracker = ConnectionTracker::new();
+	tracker.add_process(OFFLINE_PID, Arc::from(profile));
+	for port in udp_ports {
+		let local = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), *port);
+		tracker.add_udp_endpoint(OFFLINE_PID, local);
+	}
+	tracker
+}
+
+/// Packet of a capture with the verdict it received
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayedPacket {
+	/// Position of the packet in the capture, starting at 1
+	pub index: u64,
+	pub timestamp: Duration,
+	pub len: usize,
+	/// Header fields, unless the packet could not be parsed
+	pub info: Option<PacketInfo>,
+	pub verdict: Verdict,
+	pub profile: Option<Arc<str>>,
+}
+
+impl fmt::Display for ReplayedPacket {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"#{} {}.{:06} ",
+			self.index,
+			self.timestamp.as_secs(),
+			self.timestamp.subsec_micros()
+		)?;
+		match &self.info {
+			Some(info) => write!(
+				f,
+				"{} {} -> {} [L{}]",
+				info.protocol, info.src, info.dst, info.payload_len
+			)?,
+			None => write!(f, "[{} bytes]", self.len)?,
+		}
+		write!(
+			f,
+			" {} [{}]",
+			self.verdict,
+			self.profile.as_deref().unwrap_or("-")
+		)
+	}
+}
+
+/// Packets exchanged with one remote peer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerReport {
+	pub passed: u64,
+	pub blocked: u64,
+}
+
+/// Outcome of running a capture through the classifier
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+	/// Packets read from the capture
+	pub total: u64,
+	pub passed: u64,
+	pub blocked: u64,
+	/// Packets per verdict reason
+	pub reasons: BTreeMap<Reason, u64>,
+	/// Packets per remote peer address
+	pub peers: BTreeMap<IpAddr, PeerReport>,
+	/// Every packet that would have been dropped, in capture order
+	pub blocked_packets: Vec<ReplayedPacket>,
+}
+
+impl Report {
+	fn record(&mut self, packet: ReplayedPacket, peer: Option<IpAddr>) {
+		self.total += 1;
+		*self.reasons.entry(packet.verdict.reason()).or_default() += 1;
+		let pass = packet.verdict.is_pass();
+		if let Some(peer) = peer {
+			let peer = self.peers.entry(peer).or_default();
+			if pass {
+				peer.passed += 1;
+			} else {
+				peer.blocked += 1;
+			}
+		}
+		if pass {
+			self.passed += 1;
+		} else {
+			self.blocked += 1;
+			self.blocked_packets.push(packet);
+		}
+	}
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(
+			f,
+			"{} packets: {} passed, {} blocked",
+			self.total, self.passed, self.blocked
+		)?;
+
+		writeln!(f, "\nBy reason:")?;
+		for (reason, count) in &self.reasons {
+			writeln!(f, "  {:<16} {:>8}", reason.as_str(), count)?;
+		}
+
+		writeln!(f, "\nBy peer:")?;
+		writeln!(f, "  {:<40} {:>8} {:>8}", "address", "passed", "blocked")?;
+		for (addr, peer) in &self.peers {
+			writeln!(
+				f,
+				"  {:<40} {:>8} {:>8}",
+				addr.to_string(),
+				peer.passed,
+				peer.blocked
+			)?;
+		}
+
+		writeln!(f, "\nBlocked packets:")?;
+		for packet in &self.blocked_packets {
+			writeln!(f, "  {}", packet)?;
+		}
+		Ok(())
+	}
+}
+
+/// Classify every packet of `source` as the live packet loop would
+///
+/// `local` holds the addresses of the machine the capture was taken on, which tell the
+/// direction of the records whose format does not.
+pub fn analyze(
+	mut source: impl PacketSource, tracker: &ConnectionTracker, policy: &Policy,
+	local: &LocalAddresses,
+) -> Result<Report, PacketIoError> {
+	let mut report = Report::default();
+	let mut buffer = vec![0u8; u16::MAX as usize];
+	while let Some(packet) = source.recv(&mut buffer)? {
+		let index = report.total + 1;
+		// Records cut to the snaplen keep their headers, which is all classification needs
+		let parsed = if packet.meta.truncated {
+			PacketInfo::parse_lax(&packet.data)
+		} else {
+			PacketInfo::parse(&packet.data)
+		};
+		let (info, verdict, profile, peer) = match parsed {
+			Ok(info) => {
+				// Without the direction of a pcapng record, it is told from the addresses,
+				// or else from which end uses a tracked port
+				let outbound = if packet.meta.direction_known {
+					packet.meta.outbound
+				} else {
+					local.is_outbound(&info).unwrap_or_else(|| {
+						info.protocol == Protocol::Udp && tracker.udp_owner(info.src).is_some()
+					})
+				};
+				let meta = PacketMeta {
+					outbound,
+					..packet.meta
+				};
+				let classification = classify_info(&info, &meta, tracker, policy);
+				let peer = info.remote(meta.outbound).ip();
+				(
+					Some(info),
+					classification.verdict,
+					classification.profile,
+					Some(peer),
+				)
+			}
+			// Live, a packet cut short is let through unclassified rather than dropped
+			Err(_) if packet.meta.truncated => (None, Verdict::Pass(Reason::Truncated), None, None),
+			// Unparsable packets are never re-injected
+			Err(reason) => (None, Verdict::Drop(reason), None, None),
+		};
+		let packet = ReplayedPacket {
+			index,
+			timestamp: packet.meta.timestamp,
+			len: packet.data.len(),
+			info,
+			verdict,
+			profile,
+		};
+		report.record(packet, peer);
+	}
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use etherparse::PacketBuilder;
+	use pcap_file::TsResolution;
+
+	use super::*;
+	use crate::capture::{CaptureFormat, CaptureWriter, CapturedPacket};
+	use crate::classifier::{HEARTBEAT_SIZES, MATCHMAKING_SIZES};
+	use crate::config::GTA5_ENHANCED_PROFILE;
+	use crate::packet_io::PcapReplaySource;
+
+	const PEER: [u8; 4] = [203, 0, 113, 7];
+	const LOCAL: [u8; 4] = [192, 168, 1, 2];
+
+	/// Inbound UDP packet from the peer to the game port carrying `payload_len` bytes
+	fn inbound_udp(payload_len: usize) -> Vec<u8> {
+		let builder = PacketBuilder::ipv4(PEER, LOCAL, 64).udp(50000, 6672);
+		let mut data = Vec::with_capacity(builder.size(payload_len));
+		builder.write(&mut data, &vec![0; payload_len]).unwrap();
+		data
+	}
+
+	/// Capture of `packets` in `format` with metadata `meta`, each cut to `snaplen` bytes
+	fn capture_as(
+		format: CaptureFormat, packets: &[Vec<u8>], snaplen: usize, meta: PacketMeta,
+	) -> Vec<u8> {
+		let mut writer = CaptureWriter::new(Vec::new(), format, TsResolution::MicroSecond).unwrap();
+		for data in packets {
+			writer
+				.write(&CapturedPacket {
+					data: &data[..data.len().min(snaplen)],
+					orig_len: data.len(),
+					meta,
+					verdict: Verdict::PassAndCapture(Reason::Matchmaking),
+					pid: None,
+					profile: None,
+				})
+				.unwrap();
+		}
+		writer.into_inner()
+	}
+
+	/// pcapng capture of inbound `packets`, each cut to `snaplen` bytes
+	fn capture(packets: &[Vec<u8>], snaplen: usize) -> Vec<u8> {
+		capture_as(
+			CaptureFormat::Pcapng,
+			packets,
+			snaplen,
+			PacketMeta::default(),
+		)
+	}
+
+	fn analyze_capture(capture: &[u8]) -> Report {
+		let tracker = offline_tracker(GTA5_ENHANCED_PROFILE, &[6672]);
+		let source = PcapReplaySource::new(capture).unwrap();
+		analyze(source, &tracker, &Policy::default(), &LocalAddresses::new()).unwrap()
+	}
+
+	#[test]
+	fn classify_packets_cut_to_the_snaplen() {
+		let heartbeat = inbound_udp(HEARTBEAT_SIZES[2]);
+		let matchmaking = inbound_udp(MATCHMAKING_SIZES[0]);
+		let report = analyze_capture(&capture(&[heartbeat, matchmaking], 64));
+
+		assert_eq!(report.total, 2);
+		assert_eq!(report.passed, 1);
+		assert_eq!(report.blocked, 1);
+		assert_eq!(report.reasons[&Reason::Heartbeat], 1);
+		assert_eq!(report.reasons[&Reason::Matchmaking], 1);
+		let blocked = &report.blocked_packets[0];
+		assert_eq!(blocked.len, 64);
+		assert_eq!(blocked.info.unwrap().payload_len, MATCHMAKING_SIZES[0]);
+	}
+
+	#[test]
+	fn packets_cut_within_their_headers_are_truncated() {
+		let report = analyze_capture(&capture(&[inbound_udp(MATCHMAKING_SIZES[0])], 24));
+
+		assert_eq!(report.total, 1);
+		assert_eq!(report.passed, 1);
+		assert_eq!(report.blocked, 0);
+		assert_eq!(report.reasons[&Reason::Truncated], 1);
+		assert!(!report.reasons.contains_key(&Reason::Malformed));
+	}
+
+	#[test]
+	fn whole_packets_are_parsed_strictly() {
+		// An IPv4 header announcing more bytes than the record holds, without a shorter snaplen
+		let mut malformed = inbound_udp(MATCHMAKING_SIZES[0]);
+		malformed.truncate(60);
+		let report = analyze_capture(&capture(&[malformed], 64));
+
+		assert_eq!(report.blocked, 1);
+		assert_eq!(report.reasons[&Reason::Malformed], 1);
+	}
+
+	/// Packet between two public addresses, both on the game port
+	fn public_udp() -> Vec<u8> {
+		let builder = PacketBuilder::ipv4([203, 0, 113, 7], [198, 51, 100, 4], 64).udp(6672, 6672);
+		let mut data = Vec::with_capacity(builder.size(12));
+		builder.write(&mut data, &[0; 12]).unwrap();
+		data
+	}
+
+	/// Remote peers of the packets of `capture`, as seen from `local`
+	fn peers(capture: &[u8], local: &LocalAddresses) -> Vec<IpAddr> {
+		let tracker = offline_tracker(GTA5_ENHANCED_PROFILE, &[6672]);
+		let source = PcapReplaySource::new(capture).unwrap();
+		let report = analyze(source, &tracker, &Policy::default(), local).unwrap();
+		report.peers.into_keys().collect()
+	}
+
+	#[test]
+	fn local_addresses_override_the_direction() {
+		// Both ends are public and use the game port, so only the local addresses tell them apart
+		let capture = capture_as(
+			CaptureFormat::Pcap,
+			&[public_udp()],
+			usize::MAX,
+			PacketMeta::default(),
+		);
+
+		let local: LocalAddresses = [IpAddr::from([198, 51, 100, 4])].into_iter().collect();
+		assert_eq!(peers(&capture, &local), [IpAddr::from([203, 0, 113, 7])]);
+		// Without them, the sender of a packet from a tracked port is taken to be this machine
+		assert_eq!(
+			peers(&capture, &LocalAddresses::new()),
+			[IpAddr::from([198, 51, 100, 4])]
+		);
+	}
+
+	#[test]
+	fn pcapng_direction_flags_override_the_addresses() {
+		let local: LocalAddresses = [IpAddr::from([198, 51, 100, 4])].into_iter().collect();
+		let outbound = PacketMeta {
+			outbound: true,
+			..Default::default()
+		};
+		let sent = capture_as(CaptureFormat::Pcapng, &[public_udp()], usize::MAX, outbound);
+		assert_eq!(peers(&sent, &local), [IpAddr::from([198, 51, 100, 4])]);
+
+		let received = capture(&[public_udp()], usize::MAX);
+		assert_eq!(
+			peers(&received, &LocalAddresses::new()),
+			[IpAddr::from([203, 0, 113, 7])]
+		);
+	}
+}
@@ -0,0 +1,311 @@
+pub mod output;
+pub mod recorder;
+pub mod rotate;
+pub mod selector;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+pub use output::{CaptureOutput, Compression};
+use pcap_file::pcap::{PcapHeader, PcapPacket, PcapWriter};
+use pcap_file::pcapng::PcapNgWriter;
+use pcap_file::pcapng::blocks::enhanced_packet::{EnhancedPacketBlock, EnhancedPacketOption};
+use pcap_file::pcapng::blocks::interface_description::{
+	InterfaceDescriptionBlock, InterfaceDescriptionOption,
+};
+use pcap_file::{DataLink, Endianness, PcapError, TsResolution};
+pub use recorder::FlightRecorder;
+pub use rotate::{RotatingCapture, Rotation};
+pub use selector::CaptureSelector;
+
+use crate::classifier::Verdict;
+use crate::packet_io::PacketMeta;
+
+/// Largest packet a capture records in full
+const SNAPLEN: u32 = 65535;
+
+/// pcapng `epb_flags` direction bits
+pub(crate) const EPB_INBOUND: u32 = 0b01;
+pub(crate) const EPB_OUTBOUND: u32 = 0b10;
+
+/// File format of packet captures
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CaptureFormat {
+	/// Legacy pcap, readable by every tool
+	#[default]
+	Pcap,
+	/// pcapng with per-packet direction flags and verdict comments
+	Pcapng,
+}
+
+impl CaptureFormat {
+	/// File extension of the format, without the dot
+	pub fn extension(&self) -> &'static str {
+		match self {
+			CaptureFormat::Pcap => "pcap",
+			CaptureFormat::Pcapng => "pcapng",
+		}
+	}
+
+	/// Format implied by the extension of `path`, pcap unless it is `.pcapng`
+	pub fn from_path(path: &Path) -> Self {
+		match path.extension() {
+			Some(extension) if extension.eq_ignore_ascii_case("pcapng") => CaptureFormat::Pcapng,
+			_ => CaptureFormat::Pcap,
+		}
+	}
+}
+
+impl fmt::Display for CaptureFormat {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(self.extension()) }
+}
+
+impl FromStr for CaptureFormat {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.trim().to_ascii_lowercase().as_str() {
+			"pcap" => Ok(CaptureFormat::Pcap),
+			"pcapng" => Ok(CaptureFormat::Pcapng),
+			_ => Err(format!(
+				"unknown capture format `{}`, expected pcap or pcapng",
+				s
+			)),
+		}
+	}
+}
+
+/// Packet handed to a capture together with why it was captured
+#[derive(Debug, Clone, Copy)]
+pub struct CapturedPacket<'a> {
+	/// Raw IP packet, possibly cut to the snaplen
+	pub data: &'a [u8],
+	/// Length of the whole packet
+	pub orig_len: usize,
+	pub meta: PacketMeta,
+	pub verdict: Verdict,
+	/// PID of the tracked process owning the packet
+	pub pid: Option<u32>,
+	/// Game profile of the tracked process owning the packet
+	pub profile: Option<&'a str>,
+}
+
+impl CapturedPacket<'_> {
+	/// Human-readable summary of the verdict, e.g. `BLOCKED (matchmaking) pid=42 profile=rdr2`
+	pub fn comment(&self) -> String {
+		let mut comment = self.verdict.to_string();
+		if let Some(pid) = self.pid {
+			comment.push_str(&format!(" pid={}", pid));
+		}
+		if let Some(profile) = self.profile {
+			comment.push_str(&format!(" profile={}", profile));
+		}
+		comment
+	}
+}
+
+/// Writer of raw IP packet captures in pcap or pcapng format
+pub enum CaptureWriter<W: Write> {
+	Pcap(PcapWriter<W>),
+	Pcapng(PcapNgWriter<W>),
+}
+
+impl CaptureWriter<File> {
+	/// Create or truncate a capture file
+	pub fn create(
+		path: impl AsRef<Path>, format: CaptureFormat, resolution: TsResolution,
+	) -> Result<Self, PcapError> {
+		let file = File::create(path.as_ref()).map_err(PcapError::IoError)?;
+		Self::new(file, format, resolution)
+	}
+}
+
+impl<W: Write> CaptureWriter<W> {
+	/// Start a capture on `writer` with timestamps of the given resolution, writing the file headers
+	pub fn new(writer: W, format: CaptureFormat, resolution: TsResolution) -> Result<Self, PcapError> {
+		match format {
+			CaptureFormat::Pcap => {
+				let header = PcapHeader {
+					version_major: 2,
+					version_minor: 4,
+					ts_correction: 0,
+					ts_accuracy: 0,
+					snaplen: SNAPLEN,
+					datalink: DataLink::RAW,
+					ts_resolution: resolution,
+					endianness: Endianness::native(),
+				};
+				Ok(CaptureWriter::Pcap(PcapWriter::with_header(
+					writer, header,
+				)?))
+			}
+			CaptureFormat::Pcapng => {
+				let mut pcapng = PcapNgWriter::with_endianness(writer, Endianness::native())?;
+				let mut interface = InterfaceDescriptionBlock {
+					linktype: DataLink::RAW,
+					snaplen: SNAPLEN,
+					options: vec![InterfaceDescriptionOption::IfName(Cow::Borrowed(
+						"lobbyguard",
+					))],
+				};
+				if resolution == TsResolution::NanoSecond {
+					interface
+						.options
+						.push(InterfaceDescriptionOption::IfTsResol(9));
+				}
+				pcapng.write_pcapng_block(interface)?;
+				Ok(CaptureWriter::Pcapng(pcapng))
+			}
+		}
+	}
+
+	/// Format of the capture
+	pub fn format(&self) -> CaptureFormat {
+		match self {
+			CaptureWriter::Pcap(_) => CaptureFormat::Pcap,
+			CaptureWriter::Pcapng(_) => CaptureFormat::Pcapng,
+		}
+	}
+
+	/// Append a packet to the capture, returning the number of bytes written
+	pub fn write(&mut self, packet: &CapturedPacket<'_>) -> Result<usize, PcapError> {
+		let len = packet.orig_len.max(packet.data.len()) as u32;
+		match self {
+			CaptureWriter::Pcap(pcap) => {
+				pcap.write_packet(&PcapPacket::new(packet.meta.timestamp, len, packet.data))
+			}
+			CaptureWriter::Pcapng(pcapng) => {
+				let direction = if packet.meta.outbound {
+					EPB_OUTBOUND
+				} else {
+					EPB_INBOUND
+				};
+				let mut block = EnhancedPacketBlock::default();
+				block.timestamp = packet.meta.timestamp;
+				block.original_len = len;
+				block.data = Cow::Borrowed(packet.data);
+				block.options = vec![
+					EnhancedPacketOption::Flags(direction),
+					EnhancedPacketOption::Comment(Cow::Owned(packet.comment())),
+				];
+				pcapng.write_pcapng_block(block)
+			}
+		}
+	}
+
+	/// Flush buffered data to the underlying writer
+	pub fn flush(&mut self) -> Result<(), PcapError> {
+		match self {
+			CaptureWriter::Pcap(pcap) => pcap.flush(),
+			CaptureWriter::Pcapng(pcapng) => pcapng.get_mut().flush().map_err(PcapError::IoError),
+		}
+	}
+
+	/// Return the underlying writer
+	pub fn into_inner(self) -> W {
+		match self {
+			CaptureWriter::Pcap(pcap) => pcap.into_writer(),
+			CaptureWriter::Pcapng(pcapng) => pcapng.into_inner(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use pcap_file::pcapng::{Block, PcapNgReader};
+
+	use super::*;
+	use crate::classifier::Reason;
+
+	/// Enhanced Packet Block read back from a pcapng capture
+	#[derive(Debug, PartialEq, Eq)]
+	struct ReadPacket {
+		flags: Vec<u32>,
+		comments: Vec<String>,
+		original_len: u32,
+		data: Vec<u8>,
+	}
+
+	fn read_packets(capture: &[u8]) -> Vec<ReadPacket> {
+		let mut reader = PcapNgReader::new(capture).unwrap();
+		let mut packets = Vec::new();
+		while let Some(block) = reader.next_block() {
+			let Block::EnhancedPacket(packet) = block.unwrap() else {
+				continue;
+			};
+			let mut flags = Vec::new();
+			let mut comments = Vec::new();
+			for option in &packet.options {
+				match option {
+					EnhancedPacketOption::Flags(value) => flags.push(*value),
+					EnhancedPacketOption::Comment(comment) => comments.push(comment.to_string()),
+					_ => {}
+				}
+			}
+			packets.push(ReadPacket {
+				flags,
+				comments,
+				original_len: packet.original_len,
+				data: packet.data.to_vec(),
+			});
+		}
+		packets
+	}
+
+	#[test]
+	fn pcapng_packets_carry_direction_and_verdict() {
+		let data = [0x45; 40];
+		let inbound = CapturedPacket {
+			data: &data,
+			orig_len: 40,
+			meta: PacketMeta {
+				timestamp: Duration::from_secs(1),
+				..Default::default()
+			},
+			verdict: Verdict::DropAndCapture(Reason::Matchmaking),
+			pid: Some(42),
+			profile: Some("rdr2"),
+		};
+		let outbound = CapturedPacket {
+			data: &data[..24],
+			orig_len: 1200,
+			meta: PacketMeta {
+				timestamp: Duration::from_secs(2),
+				outbound: true,
+				..Default::default()
+			},
+			verdict: Verdict::PassAndCapture(Reason::Heartbeat),
+			pid: None,
+			profile: None,
+		};
+		let mut writer =
+			CaptureWriter::new(Vec::new(), CaptureFormat::Pcapng, TsResolution::MicroSecond).unwrap();
+		writer.write(&inbound).unwrap();
+		writer.write(&outbound).unwrap();
+
+		let packets = read_packets(&writer.into_inner());
+		assert_eq!(
+			packets,
+			[
+				ReadPacket {
+					flags: vec![EPB_INBOUND],
+					comments: vec!["BLOCKED (matchmaking) pid=42 profile=rdr2".to_owned()],
+					original_len: 40,
+					data: data.to_vec(),
+				},
+				ReadPacket {
+					flags: vec![EPB_OUTBOUND],
+					comments: vec!["PASSED (heartbeat)".to_owned()],
+					original_len: 1200,
+					data: data[..24].to_vec(),
+				},
+			]
+		);
+	}
+}
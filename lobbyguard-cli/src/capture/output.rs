@@ -0,0 +1,135 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use flate2::write::GzEncoder;
+
+/// Compression applied to capture files as they are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Compression {
+	/// Plain capture files, readable while they are written
+	#[default]
+	None,
+	Gzip,
+	/// Zstandard, requires the `zstd` feature
+	Zstd,
+}
+
+impl Compression {
+	/// Extension appended to compressed file names, without the dot
+	pub fn extension(&self) -> Option<&'static str> {
+		match self {
+			Compression::None => None,
+			Compression::Gzip => Some("gz"),
+			Compression::Zstd => Some("zst"),
+		}
+	}
+
+	/// Compression implied by the extension of `path`, none unless it is `.gz` or `.zst`
+	pub fn from_path(path: &Path) -> Self {
+		match path.extension() {
+			Some(extension) if extension.eq_ignore_ascii_case("gz") => Compression::Gzip,
+			Some(extension) if extension.eq_ignore_ascii_case("zst") => Compression::Zstd,
+			_ => Compression::None,
+		}
+	}
+}
+
+impl fmt::Display for Compression {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Compression::None => "none",
+			Compression::Gzip => "gzip",
+			Compression::Zstd => "zstd",
+		})
+	}
+}
+
+impl FromStr for Compression {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.trim().to_ascii_lowercase().as_str() {
+			"none" => Ok(Compression::None),
+			"gzip" | "gz" => Ok(Compression::Gzip),
+			"zstd" | "zst" => Ok(Compression::Zstd),
+			_ => Err(format!(
+				"unknown compression `{}`, expected none, gzip or zstd",
+				s
+			)),
+		}
+	}
+}
+
+/// Compressor in front of a capture file
+enum Encoder {
+	/// Written straight through, so the file is complete after every packet
+	Plain(File),
+	Gzip(GzEncoder<BufWriter<File>>),
+	#[cfg(feature = "zstd")]
+	Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+/// Capture file, compressing what is written to it
+pub struct CaptureOutput {
+	encoder: Encoder,
+}
+
+impl CaptureOutput {
+	/// Create or truncate the file at `path`
+	pub fn create(path: &Path, compression: Compression) -> io::Result<Self> {
+		let file = File::create(path)?;
+		let encoder = match compression {
+			Compression::None => Encoder::Plain(file),
+			Compression::Gzip => Encoder::Gzip(GzEncoder::new(
+				BufWriter::new(file),
+				flate2::Compression::default(),
+			)),
+			#[cfg(feature = "zstd")]
+			Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(
+				BufWriter::new(file),
+				zstd::DEFAULT_COMPRESSION_LEVEL,
+			)?),
+			#[cfg(not(feature = "zstd"))]
+			Compression::Zstd => {
+				return Err(io::Error::new(
+					io::ErrorKind::Unsupported,
+					"zstd compression requires the `zstd` feature",
+				));
+			}
+		};
+		Ok(Self { encoder })
+	}
+
+	/// Terminate the compressed stream and flush everything to the file
+	pub fn finish(self) -> io::Result<()> {
+		match self.encoder {
+			Encoder::Plain(mut file) => file.flush(),
+			Encoder::Gzip(encoder) => encoder.finish()?.flush(),
+			#[cfg(feature = "zstd")]
+			Encoder::Zstd(encoder) => encoder.finish()?.flush(),
+		}
+	}
+}
+
+impl Write for CaptureOutput {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match &mut self.encoder {
+			Encoder::Plain(file) => file.write(buf),
+			Encoder::Gzip(encoder) => encoder.write(buf),
+			#[cfg(feature = "zstd")]
+			Encoder::Zstd(encoder) => encoder.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match &mut self.encoder {
+			Encoder::Plain(file) => file.flush(),
+			Encoder::Gzip(encoder) => encoder.flush(),
+			#[cfg(feature = "zstd")]
+			Encoder::Zstd(encoder) => encoder.flush(),
+		}
+	}
+}
@@ -0,0 +1,243 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use pcap_file::{PcapError, TsResolution};
+
+use super::{CaptureFormat, CaptureWriter, CapturedPacket};
+use crate::classifier::Verdict;
+use crate::packet_io::PacketMeta;
+
+/// Memory the recorder uses when only a time window is given
+pub const DEFAULT_MAX_BYTES: usize = 64 << 20;
+
+/// Packet held by the recorder
+#[derive(Debug, Clone)]
+struct RecordedPacket {
+	data: Box<[u8]>,
+	orig_len: usize,
+	meta: PacketMeta,
+	verdict: Verdict,
+	pid: Option<u32>,
+	profile: Option<Arc<str>>,
+}
+
+#[derive(Debug, Default)]
+struct Ring {
+	packets: VecDeque<RecordedPacket>,
+	/// Sum of the packet lengths in `packets`
+	bytes: usize,
+}
+
+/// Bounded in-memory ring of the most recent captured packets
+///
+/// Shared between the packet loop, which records, and whoever dumps it on demand.
+#[derive(Debug)]
+pub struct FlightRecorder {
+	/// Drop packets older than this relative to the newest one, or to the time of a dump
+	max_age: Option<Duration>,
+	/// Drop the oldest packets once their data exceeds this many bytes
+	max_bytes: usize,
+	/// Timestamp resolution of the dumps
+	resolution: TsResolution,
+	ring: Mutex<Ring>,
+}
+
+impl FlightRecorder {
+	/// Recorder keeping at most `max_bytes` of packets no older than `max_age`
+	pub fn new(max_age: Option<Duration>, max_bytes: usize, resolution: TsResolution) -> Self {
+		Self {
+			max_age,
+			max_bytes,
+			resolution,
+			ring: Mutex::new(Ring::default()),
+		}
+	}
+
+	/// Add a packet, evicting those that fall out of the window
+	pub fn record(&self, packet: &CapturedPacket<'_>) {
+		if packet.data.len() > self.max_bytes {
+			return;
+		}
+		let mut guard = self.ring.lock().unwrap_or_else(PoisonError::into_inner);
+		let ring = &mut *guard;
+		ring.bytes += packet.data.len();
+		ring.packets.push_back(RecordedPacket {
+			data: packet.data.into(),
+			orig_len: packet.orig_len,
+			meta: packet.meta,
+			verdict: packet.verdict,
+			pid: packet.pid,
+			profile: packet.profile.map(Arc::from),
+		});
+
+		let newest = packet.meta.timestamp;
+		while let Some(oldest) = ring.packets.front() {
+			let expired = self
+				.max_age
+				.is_some_and(|max_age| oldest.meta.timestamp + max_age < newest);
+			if ring.bytes <= self.max_bytes && !expired {
+				break;
+			}
+			ring.bytes -= oldest.data.len();
+			ring.packets.pop_front();
+		}
+	}
+
+	/// Number of packets held
+	pub fn len(&self) -> usize {
+		self
+			.ring
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.packets
+			.len()
+	}
+
+	/// Whether no packet is held
+	pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+	/// Bytes of packet data held
+	pub fn bytes(&self) -> usize {
+		self
+			.ring
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.bytes
+	}
+
+	/// Drop the packets older than the window at `now`, since the Unix epoch, and copy the rest
+	fn snapshot(&self, now: Duration) -> VecDeque<RecordedPacket> {
+		let mut guard = self.ring.lock().unwrap_or_else(PoisonError::into_inner);
+		let ring = &mut *guard;
+		if let Some(max_age) = self.max_age {
+			while let Some(oldest) = ring.packets.front()
+				&& oldest.meta.timestamp + max_age < now
+			{
+				ring.bytes -= oldest.data.len();
+				ring.packets.pop_front();
+			}
+		}
+		ring.packets.clone()
+	}
+
+	/// Write the packets held to a new capture file, returning how many were written
+	///
+	/// Packets that aged out of the window while no new ones arrived are left out. The ring
+	/// is copied first so the packet loop is not held up by the disk.
+	pub fn dump(&self, path: &Path, format: CaptureFormat) -> Result<usize, PcapError> {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default();
+		let packets = self.snapshot(now);
+		let mut writer = CaptureWriter::create(path, format, self.resolution)?;
+		for packet in &packets {
+			writer.write(&CapturedPacket {
+				data: &packet.data,
+				orig_len: packet.orig_len,
+				meta: packet.meta,
+				verdict: packet.verdict,
+				pid: packet.pid,
+				profile: packet.profile.as_deref(),
+			})?;
+		}
+		writer.flush()?;
+		Ok(packets.len())
+	}
+}
+
+/// File name for a dump taken now, e.g. `lobbyguard-1700000000.pcapng`
+pub fn dump_path(format: CaptureFormat) -> PathBuf {
+	let secs = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs();
+	PathBuf::from(format!("lobbyguard-{}.{}", secs, format.extension()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::classifier::Reason;
+
+	/// Record a packet of `len` bytes captured `secs` after the epoch
+	fn record(recorder: &FlightRecorder, len: usize, secs: u64) {
+		let data = vec![0; len];
+		recorder.record(&CapturedPacket {
+			data: &data,
+			orig_len: len,
+			meta: PacketMeta {
+				timestamp: Duration::from_secs(secs),
+				..Default::default()
+			},
+			verdict: Verdict::Pass(Reason::Untracked),
+			pid: None,
+			profile: None,
+		});
+	}
+
+	/// Capture times of the packets held, in seconds
+	fn held(recorder: &FlightRecorder, now: u64) -> Vec<u64> {
+		let packets = recorder.snapshot(Duration::from_secs(now));
+		packets
+			.iter()
+			.map(|packet| packet.meta.timestamp.as_secs())
+			.collect()
+	}
+
+	#[test]
+	fn evict_the_oldest_packets_over_the_byte_limit() {
+		let recorder = FlightRecorder::new(None, 250, TsResolution::MicroSecond);
+		for secs in 1..=3 {
+			record(&recorder, 100, secs);
+		}
+		assert_eq!((recorder.len(), recorder.bytes()), (2, 200));
+		// Too large to ever fit, it leaves the ring untouched
+		record(&recorder, 251, 4);
+		record(&recorder, 50, 5);
+		assert_eq!((recorder.len(), recorder.bytes()), (3, 250));
+		assert_eq!(held(&recorder, 5), [2, 3, 5]);
+	}
+
+	#[test]
+	fn evict_packets_older_than_the_window() {
+		let recorder = FlightRecorder::new(
+			Some(Duration::from_secs(10)),
+			DEFAULT_MAX_BYTES,
+			TsResolution::MicroSecond,
+		);
+		for secs in [100, 105, 112] {
+			record(&recorder, 100, secs);
+		}
+		// Out of the window of the newest packet
+		assert_eq!(held(&recorder, 112), [105, 112]);
+		// Aged out since, though nothing new arrived
+		assert_eq!(held(&recorder, 120), [112]);
+		assert_eq!((recorder.len(), recorder.bytes()), (1, 100));
+		assert!(held(&recorder, 123).is_empty());
+		assert!(recorder.is_empty());
+	}
+
+	#[test]
+	fn dump_leaves_out_packets_older_than_the_window() {
+		let recorder = FlightRecorder::new(
+			Some(Duration::from_secs(60)),
+			DEFAULT_MAX_BYTES,
+			TsResolution::MicroSecond,
+		);
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap()
+			.as_secs();
+		record(&recorder, 100, now - 120);
+		record(&recorder, 100, now - 90);
+		let path =
+			std::env::temp_dir().join(format!("lobbyguard-recorder-{}.pcapng", std::process::id()));
+		let written = recorder.dump(&path, CaptureFormat::Pcapng);
+		let _ = std::fs::remove_file(&path);
+
+		assert_eq!(written.unwrap(), 0);
+		assert!(recorder.is_empty());
+	}
+}
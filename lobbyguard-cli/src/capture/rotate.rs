@@ -0,0 +1,482 @@
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use pcap_file::{PcapError, TsResolution};
+
+use super::output::{CaptureOutput, Compression};
+use super::{CaptureFormat, CaptureWriter, CapturedPacket};
+
+/// When a capture moves on to a new file and how many files it keeps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rotation {
+	/// Start a new file once this many bytes of packets were written to the current one
+	pub max_bytes: Option<u64>,
+	/// Start a new file once the current one has been open this long
+	pub max_age: Option<Duration>,
+	/// Delete the oldest files so that at most this many remain
+	pub max_files: Option<usize>,
+}
+
+impl Rotation {
+	/// Whether the capture is split into numbered files
+	pub fn is_enabled(&self) -> bool { self.max_bytes.is_some() || self.max_age.is_some() }
+}
+
+/// Capture written to `capture.pcap`, or to `capture-000.pcap`, `capture-001.pcap`… when rotated
+pub struct RotatingCapture {
+	/// Path given by the user, from which file names are derived
+	path: PathBuf,
+	format: CaptureFormat,
+	compression: Compression,
+	rotation: Rotation,
+	resolution: TsResolution,
+	/// Number of the next file to open, following those of earlier runs
+	next_index: u32,
+	writer: Option<CaptureWriter<CaptureOutput>>,
+	/// Bytes written to the current file, before compression
+	written: u64,
+	opened_at: Instant,
+	/// Files kept on disk, oldest first, including those of earlier runs
+	files: VecDeque<PathBuf>,
+}
+
+impl RotatingCapture {
+	/// Open the first file of a capture with timestamps of the given resolution
+	///
+	/// A rotated capture continues the numbering of the files already on disk.
+	pub fn create(
+		path: impl Into<PathBuf>, format: CaptureFormat, compression: Compression, rotation: Rotation,
+		resolution: TsResolution,
+	) -> Result<Self, PcapError> {
+		let mut capture = Self {
+			path: path.into(),
+			format,
+			compression,
+			rotation,
+			resolution,
+			next_index: 0,
+			writer: None,
+			written: 0,
+			opened_at: Instant::now(),
+			files: VecDeque::new(),
+		};
+		if rotation.is_enabled() {
+			capture.find_existing();
+		}
+		capture.open()?;
+		Ok(capture)
+	}
+
+	/// Take over the numbered files of the capture left by earlier runs
+	fn find_existing(&mut self) {
+		let dir = match self.path.parent() {
+			Some(dir) if !dir.as_os_str().is_empty() => dir,
+			_ => Path::new("."),
+		};
+		let (Some(stem), Ok(entries)) = (self.path.file_stem(), fs::read_dir(dir)) else {
+			return;
+		};
+		let Some(prefix) = stem.to_str().map(|stem| format!("{}-", stem)) else {
+			return;
+		};
+		let mut existing: Vec<_> = entries
+			.filter_map(Result::ok)
+			.filter_map(|entry| {
+				let name = entry.file_name();
+				let digits = name.to_str()?.strip_prefix(&prefix)?.split('.').next()?;
+				let index = digits.parse::<u32>().ok()?;
+				let path = self.file_path(index);
+				(path.file_name() == Some(name.as_os_str())).then_some((index, path))
+			})
+			.collect();
+		existing.sort_unstable();
+		if let Some((last, _)) = existing.last() {
+			debug!(
+				"Continuing after {} capture files of earlier runs",
+				existing.len()
+			);
+			self.next_index = last + 1;
+		}
+		self.files = existing.into_iter().map(|(_, path)| path).collect();
+	}
+
+	/// Path of file number `index` of the capture
+	pub fn file_path(&self, index: u32) -> PathBuf {
+		let mut name = OsString::new();
+		if self.rotation.is_enabled() {
+			name.push(self.path.file_stem().unwrap_or_default());
+			name.push(format!("-{:03}.", index));
+			name.push(
+				self
+					.path
+					.extension()
+					.unwrap_or(OsStr::new(self.format.extension())),
+			);
+		} else {
+			name.push(self.path.file_name().unwrap_or_default());
+		}
+		if let Some(extension) = self.compression.extension() {
+			name.push(".");
+			name.push(extension);
+		}
+		self.path.with_file_name(name)
+	}
+
+	/// Append a packet, first moving on to a new file if the current one is full
+	pub fn write(&mut self, packet: &CapturedPacket<'_>) -> Result<(), PcapError> {
+		if self.writer.is_some() && self.is_due() {
+			self.close()?;
+		}
+		let writer = match self.writer {
+			Some(ref mut writer) => writer,
+			None => self.open()?,
+		};
+		self.written += writer.write(packet)? as u64;
+		Ok(())
+	}
+
+	/// Flush buffered packets of the current file
+	pub fn flush(&mut self) -> Result<(), PcapError> {
+		match self.writer.as_mut() {
+			Some(writer) => writer.flush(),
+			None => Ok(()),
+		}
+	}
+
+	/// Complete the current file, terminating its compressed stream
+	pub fn finish(mut self) -> Result<(), PcapError> { self.close() }
+
+	/// Whether the current file reached a rotation limit
+	fn is_due(&self) -> bool {
+		self
+			.rotation
+			.max_bytes
+			.is_some_and(|max_bytes| self.written >= max_bytes)
+			|| self
+				.rotation
+				.max_age
+				.is_some_and(|max_age| self.opened_at.elapsed() >= max_age)
+	}
+
+	fn open(&mut self) -> Result<&mut CaptureWriter<CaptureOutput>, PcapError> {
+		let path = self.file_path(self.next_index);
+		let output = CaptureOutput::create(&path, self.compression).map_err(PcapError::IoError)?;
+		let writer = CaptureWriter::new(output, self.format, self.resolution)?;
+		debug!("Capturing packets to {:?}", path);
+		self.next_index += 1;
+		self.written = 0;
+		self.opened_at = Instant::now();
+		self.files.push_back(path);
+		if let Some(max_files) = self.rotation.max_files {
+			while self.files.len() > max_files.max(1) {
+				let Some(oldest) = self.files.pop_front() else {
+					break;
+				};
+				if let Err(e) = fs::remove_file(&oldest) {
+					warn!("Failed to remove old capture file {:?}: {}", oldest, e);
+				}
+			}
+		}
+		Ok(self.writer.insert(writer))
+	}
+
+	fn close(&mut self) -> Result<(), PcapError> {
+		match self.writer.take() {
+			Some(writer) => writer.into_inner().finish().map_err(PcapError::IoError),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Drop for RotatingCapture {
+	fn drop(&mut self) {
+		if let Err(e) = self.close() {
+			warn!("Failed to complete capture file: {}", e);
+		}
+	}
+}
+
+/// Parse a byte size such as `500000`, `64K`, `100M` or `2G` (powers of 1024)
+pub fn parse_size(s: &str) -> Result<u64, String> {
+	let s = s.trim();
+	let upper = s.to_ascii_uppercase();
+	let digits = upper.trim_end_matches('B').trim_end_matches('I').trim_end();
+	let (number, multiplier) = match digits.chars().last() {
+		Some('K') => (&digits[..digits.len() - 1], 1 << 10),
+		Some('M') => (&digits[..digits.len() - 1], 1 << 20),
+		Some('G') => (&digits[..digits.len() - 1], 1 << 30),
+		_ => (digits, 1),
+	};
+	number
+		.trim()
+		.parse::<u64>()
+		.ok()
+		.and_then(|number| number.checked_mul(multiplier))
+		.filter(|size| *size > 0)
+		.ok_or_else(|| format!("invalid size `{}`, expected e.g. 100M", s))
+}
+
+/// Parse a duration such as `90`, `30s`, `15m`, `2h` or `1d`, in seconds without a unit
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+	let s = s.trim();
+	let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+		Some(index) => s.split_at(index),
+		None => (s, "s"),
+	};
+	let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+		"s" => 1,
+		"m" => 60,
+		"h" => 60 * 60,
+		"d" => 24 * 60 * 60,
+		_ => 0,
+	};
+	number
+		.parse::<u64>()
+		.ok()
+		.and_then(|number| number.checked_mul(multiplier))
+		.filter(|secs| *secs > 0)
+		.map(Duration::from_secs)
+		.ok_or_else(|| format!("invalid duration `{}`, expected e.g. 30m", s))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::classifier::{Reason, Verdict};
+	use crate::packet_io::PacketMeta;
+
+	/// Empty directory of its own for the capture files of one test
+	fn test_dir(name: &str) -> PathBuf {
+		let dir =
+			std::env::temp_dir().join(format!("lobbyguard-rotate-{}-{}", std::process::id(), name));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/// Names of the files in `dir`, sorted
+	fn file_names(dir: &Path) -> Vec<String> {
+		let mut names: Vec<_> = fs::read_dir(dir)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+			.collect();
+		names.sort_unstable();
+		names
+	}
+
+	fn write_packets(capture: &mut RotatingCapture, count: usize) {
+		let data = [0x45; 40];
+		let packet = CapturedPacket {
+			data: &data,
+			orig_len: data.len(),
+			meta: PacketMeta::default(),
+			verdict: Verdict::PassAndCapture(Reason::Heartbeat),
+			pid: None,
+			profile: None,
+		};
+		for _ in 0..count {
+			capture.write(&packet).unwrap();
+		}
+	}
+
+	fn create(path: &Path, compression: Compression, rotation: Rotation) -> RotatingCapture {
+		RotatingCapture::create(
+			path,
+			CaptureFormat::Pcap,
+			compression,
+			rotation,
+			TsResolution::MicroSecond,
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn number_rotated_files() {
+		let dir = test_dir("numbering");
+		let rotation = Rotation {
+			max_bytes: Some(1 << 20),
+			..Default::default()
+		};
+		let rotated = create(&dir.join("capture.pcap"), Compression::None, rotation);
+		assert_eq!(rotated.file_path(0), dir.join("capture-000.pcap"));
+		assert_eq!(rotated.file_path(12), dir.join("capture-012.pcap"));
+		assert_eq!(rotated.file_path(1234), dir.join("capture-1234.pcap"));
+		let compressed = create(&dir.join("capture.pcap"), Compression::Gzip, rotation);
+		assert_eq!(compressed.file_path(7), dir.join("capture-007.pcap.gz"));
+		let bare = create(&dir.join("capture"), Compression::None, rotation);
+		assert_eq!(bare.file_path(1), dir.join("capture-001.pcap"));
+		let single = create(
+			&dir.join("single.pcap"),
+			Compression::Gzip,
+			Rotation::default(),
+		);
+		assert_eq!(single.file_path(3), dir.join("single.pcap.gz"));
+		drop((rotated, compressed, bare, single));
+
+		// The bare capture shares the file names of the first one and continues after it
+		assert_eq!(
+			file_names(&dir),
+			[
+				"capture-000.pcap",
+				"capture-000.pcap.gz",
+				"capture-001.pcap",
+				"single.pcap.gz"
+			]
+		);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn rotate_by_size() {
+		let dir = test_dir("size");
+		let rotation = Rotation {
+			max_bytes: Some(1),
+			..Default::default()
+		};
+		let mut capture = create(&dir.join("capture.pcap"), Compression::None, rotation);
+		write_packets(&mut capture, 3);
+		capture.finish().unwrap();
+
+		assert_eq!(
+			file_names(&dir),
+			["capture-000.pcap", "capture-001.pcap", "capture-002.pcap"]
+		);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn rotate_by_age() {
+		let dir = test_dir("age");
+		let rotation = Rotation {
+			max_age: Some(Duration::from_millis(20)),
+			..Default::default()
+		};
+		let mut capture = create(&dir.join("capture.pcap"), Compression::None, rotation);
+		write_packets(&mut capture, 2);
+		std::thread::sleep(Duration::from_millis(30));
+		write_packets(&mut capture, 1);
+		capture.finish().unwrap();
+
+		assert_eq!(file_names(&dir), ["capture-000.pcap", "capture-001.pcap"]);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn delete_the_oldest_files() {
+		let dir = test_dir("retention");
+		let rotation = Rotation {
+			max_bytes: Some(1),
+			max_files: Some(2),
+			..Default::default()
+		};
+		let mut capture = create(&dir.join("capture.pcap"), Compression::None, rotation);
+		write_packets(&mut capture, 5);
+		capture.finish().unwrap();
+
+		assert_eq!(file_names(&dir), ["capture-003.pcap", "capture-004.pcap"]);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn continue_after_the_files_of_earlier_runs() {
+		let dir = test_dir("restart");
+		for name in [
+			"capture-abc.pcap",
+			"capture-+09.pcap",
+			"capture-009.pcap.gz",
+			"other-009.pcap",
+		] {
+			fs::write(dir.join(name), b"").unwrap();
+		}
+		let rotation = Rotation {
+			max_bytes: Some(1),
+			max_files: Some(3),
+			..Default::default()
+		};
+		let mut capture = create(&dir.join("capture.pcap"), Compression::None, rotation);
+		write_packets(&mut capture, 2);
+		capture.finish().unwrap();
+		let mut capture = create(&dir.join("capture.pcap"), Compression::None, rotation);
+		write_packets(&mut capture, 3);
+		capture.finish().unwrap();
+
+		assert_eq!(
+			file_names(&dir),
+			[
+				"capture-+09.pcap",
+				"capture-002.pcap",
+				"capture-003.pcap",
+				"capture-004.pcap",
+				"capture-009.pcap.gz",
+				"capture-abc.pcap",
+				"other-009.pcap"
+			]
+		);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn parse_sizes() {
+		let cases = [
+			("500000", 500_000),
+			("64K", 64 << 10),
+			("64k", 64 << 10),
+			("100M", 100 << 20),
+			("100MB", 100 << 20),
+			("100MiB", 100 << 20),
+			(" 2G ", 2 << 30),
+			("3 G", 3 << 30),
+		];
+		for (s, size) in cases {
+			assert_eq!(parse_size(s), Ok(size), "{}", s);
+		}
+		for s in [
+			"",
+			"M",
+			"0",
+			"0K",
+			"-5",
+			"1.5M",
+			"10X",
+			"10T",
+			"18446744073709551616",
+			"18446744073709551615K",
+			"17179869184G",
+		] {
+			assert!(parse_size(s).is_err(), "{}", s);
+		}
+	}
+
+	#[test]
+	fn parse_durations() {
+		let cases = [
+			("90", 90),
+			("30s", 30),
+			("15m", 15 * 60),
+			("2H", 2 * 60 * 60),
+			("1d", 24 * 60 * 60),
+			(" 5 m ", 5 * 60),
+		];
+		for (s, secs) in cases {
+			assert_eq!(parse_duration(s), Ok(Duration::from_secs(secs)), "{}", s);
+		}
+		for s in [
+			"",
+			"m",
+			"0",
+			"0s",
+			"-5m",
+			"1.5h",
+			"5w",
+			"5ms",
+			"18446744073709551616",
+			"18446744073709551615d",
+		] {
+			assert!(parse_duration(s).is_err(), "{}", s);
+		}
+	}
+}
@@ -0,0 +1,248 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::cidr::{Cidr, CidrTable};
+use crate::classifier::{PacketInfo, Verdict};
+use crate::filter::ast::Filter;
+use crate::packet_io::PacketMeta;
+
+/// Verdicts whose packets are captured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum VerdictSelection {
+	#[default]
+	All,
+	/// Only packets with a drop verdict
+	Blocked,
+	/// Only packets with a pass verdict
+	Passed,
+}
+
+impl VerdictSelection {
+	/// Whether packets with `verdict` are selected
+	pub fn matches(&self, verdict: Verdict) -> bool {
+		match self {
+			VerdictSelection::All => true,
+			VerdictSelection::Blocked => !verdict.is_pass(),
+			VerdictSelection::Passed => verdict.is_pass(),
+		}
+	}
+}
+
+impl fmt::Display for VerdictSelection {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			VerdictSelection::All => "all",
+			VerdictSelection::Blocked => "blocked",
+			VerdictSelection::Passed => "passed",
+		})
+	}
+}
+
+impl FromStr for VerdictSelection {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.trim().to_ascii_lowercase().as_str() {
+			"all" => Ok(VerdictSelection::All),
+			"blocked" => Ok(VerdictSelection::Blocked),
+			"passed" => Ok(VerdictSelection::Passed),
+			_ => Err(format!(
+				"unknown verdict selection `{}`, expected all, blocked or passed",
+				s
+			)),
+		}
+	}
+}
+
+/// Which of the packets marked for capture by their verdict are actually kept
+///
+/// Every condition must hold; the default selects every marked packet.
+#[derive(Debug, Default)]
+pub struct CaptureSelector {
+	pub verdicts: VerdictSelection,
+	/// Only packets from or to these networks, unless empty
+	pub peers: CidrTable<()>,
+	/// Only packets whose IP length lies in this range
+	pub sizes: Option<RangeInclusive<usize>>,
+	/// Only packets matching this WinDivert filter, e.g. `tcp or udp.DstPort == 6672`
+	pub filter: Option<Filter>,
+}
+
+impl CaptureSelector {
+	/// Selector keeping the packets that meet every given condition
+	pub fn new(
+		verdicts: VerdictSelection, peers: impl IntoIterator<Item = Cidr>,
+		sizes: Option<RangeInclusive<usize>>, filter: Option<Filter>,
+	) -> Self {
+		Self {
+			verdicts,
+			peers: peers.into_iter().collect(),
+			sizes,
+			filter,
+		}
+	}
+
+	/// Whether the raw IP packet `data` with `meta`, header fields `info` and `verdict` is kept
+	pub fn matches(
+		&self, data: &[u8], meta: &PacketMeta, info: &PacketInfo, verdict: Verdict,
+	) -> bool {
+		self.verdicts.matches(verdict)
+			&& (self.peers.is_empty()
+				|| self.peers.contains(info.src.ip())
+				|| self.peers.contains(info.dst.ip()))
+			&& self
+				.sizes
+				.as_ref()
+				.is_none_or(|sizes| sizes.contains(&data.len()))
+			&& self
+				.filter
+				.as_ref()
+				.is_none_or(|filter| filter.matches_ip(data, meta))
+	}
+}
+
+/// Parse an inclusive packet size range: `576`, `100-1200`, `100-` or `-1200`
+pub fn parse_size_range(s: &str) -> Result<RangeInclusive<usize>, String> {
+	let invalid = || format!("invalid size range `{}`, expected e.g. 100-1200", s);
+	let bound = |bound: &str, default: usize| match bound.trim() {
+		"" => Ok(default),
+		bound => bound.parse::<usize>().map_err(|_| invalid()),
+	};
+	let (start, end) = match s.split_once('-') {
+		Some((start, end)) => (bound(start, 0)?, bound(end, usize::MAX)?),
+		None => {
+			let size = bound(s, 0)?;
+			(size, size)
+		}
+	};
+	if start > end || s.trim().is_empty() || s.trim() == "-" {
+		return Err(invalid());
+	}
+	Ok(start..=end)
+}
+
+#[cfg(test)]
+mod tests {
+	use etherparse::PacketBuilder;
+
+	use super::*;
+	use crate::classifier::Reason;
+
+	/// UDP packet from 203.0.113.7:6672 to 192.168.1.2:6672 carrying `payload_len` bytes
+	fn udp(payload_len: usize) -> Vec<u8> {
+		let builder = PacketBuilder::ipv4([203, 0, 113, 7], [192, 168, 1, 2], 64).udp(6672, 6672);
+		let mut data = Vec::with_capacity(builder.size(payload_len));
+		builder.write(&mut data, &vec![0; payload_len]).unwrap();
+		data
+	}
+
+	fn selector(
+		verdicts: &str, peers: &[&str], sizes: Option<&str>, filter: Option<&str>,
+	) -> CaptureSelector {
+		CaptureSelector::new(
+			verdicts.parse().unwrap(),
+			peers.iter().map(|peer| peer.parse().unwrap()),
+			sizes.map(|sizes| parse_size_range(sizes).unwrap()),
+			filter.map(|filter| filter.parse().unwrap()),
+		)
+	}
+
+	#[test]
+	fn match_packets() {
+		const BLOCKED: Verdict = Verdict::DropAndCapture(Reason::Matchmaking);
+		const PASSED: Verdict = Verdict::PassAndCapture(Reason::Heartbeat);
+		// 20 bytes of IPv4 and 8 of UDP headers make a 219 byte packet
+		let data = udp(191);
+		let info = PacketInfo::parse(&data).unwrap();
+		let cases = [
+			(selector("all", &[], None, None), BLOCKED, true),
+			(selector("all", &[], None, None), PASSED, true),
+			(selector("blocked", &[], None, None), BLOCKED, true),
+			(selector("blocked", &[], None, None), PASSED, false),
+			(selector("passed", &[], None, None), BLOCKED, false),
+			(selector("passed", &[], None, None), PASSED, true),
+			(
+				selector("all", &["203.0.113.0/24"], None, None),
+				PASSED,
+				true,
+			),
+			(selector("all", &["192.168.1.2"], None, None), PASSED, true),
+			(
+				selector("all", &["198.51.100.0/24", "2001:db8::/32"], None, None),
+				PASSED,
+				false,
+			),
+			(selector("all", &[], Some("219"), None), PASSED, true),
+			(selector("all", &[], Some("100-219"), None), PASSED, true),
+			(selector("all", &[], Some("220-"), None), PASSED, false),
+			(selector("all", &[], Some("-218"), None), PASSED, false),
+			(
+				selector("all", &[], None, Some("udp.DstPort == 6672")),
+				PASSED,
+				true,
+			),
+			(
+				selector("all", &[], None, Some("tcp or outbound")),
+				PASSED,
+				false,
+			),
+			(
+				selector("blocked", &["203.0.113.7"], Some("200-300"), Some("udp")),
+				BLOCKED,
+				true,
+			),
+			(
+				selector("blocked", &["203.0.113.7"], Some("200-300"), Some("tcp")),
+				BLOCKED,
+				false,
+			),
+		];
+		for (index, (selector, verdict, expected)) in cases.into_iter().enumerate() {
+			assert_eq!(
+				selector.matches(&data, &PacketMeta::default(), &info, verdict),
+				expected,
+				"case {}: {:?} {}",
+				index,
+				selector,
+				verdict
+			);
+		}
+	}
+
+	#[test]
+	fn parse_size_ranges() {
+		let cases = [
+			("576", 576..=576),
+			("100-1200", 100..=1200),
+			(" 100 - 1200 ", 100..=1200),
+			("100-", 100..=usize::MAX),
+			("-1200", 0..=1200),
+			("0-0", 0..=0),
+		];
+		for (s, range) in cases {
+			assert_eq!(parse_size_range(s), Ok(range), "{}", s);
+		}
+	}
+
+	#[test]
+	fn parse_size_ranges_rejects_bad_input() {
+		for s in [
+			"",
+			" ",
+			"-",
+			"1200-100",
+			"100-1200-1500",
+			"--1200",
+			"100K",
+			"1K-2K",
+			"100-1200B",
+			"0x100",
+			"1.5-2",
+			"-1-5",
+			"18446744073709551616",
+		] {
+			assert!(parse_size_range(s).is_err(), "{}", s);
+		}
+	}
+}
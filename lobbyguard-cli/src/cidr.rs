@@ -0,0 +1,426 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `192.0.2.0/24` or `2001:db8::/32`
+///
+/// A bare address is a network containing only that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Cidr {
+	/// Network address with every host bit cleared
+	addr: IpAddr,
+	prefix_len: u8,
+}
+
+impl Cidr {
+	/// Create a network, clearing the host bits of `addr`
+	pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, CidrError> {
+		let max_len = max_prefix_len(&addr);
+		if prefix_len > max_len {
+			return Err(CidrError(format!(
+				"prefix length {} exceeds {} for {}",
+				prefix_len, max_len, addr
+			)));
+		}
+		// An IPv4-mapped network is kept as IPv4 without the 96 bits of the mapping prefix
+		let (addr, prefix_len) = match addr.to_canonical() {
+			IpAddr::V4(v4) if addr.is_ipv6() && prefix_len >= 96 => (IpAddr::V4(v4), prefix_len - 96),
+			IpAddr::V4(_) => (addr, prefix_len),
+			canonical => (canonical, prefix_len),
+		};
+		let max_len = max_prefix_len(&addr);
+		Ok(Self {
+			addr: from_bits(&addr, mask(to_bits(&addr), prefix_len, max_len)),
+			prefix_len,
+		})
+	}
+
+	/// Network containing only `addr`
+	pub fn host(addr: IpAddr) -> Self {
+		let addr = addr.to_canonical();
+		Self {
+			prefix_len: max_prefix_len(&addr),
+			addr,
+		}
+	}
+
+	/// Network address
+	pub fn addr(&self) -> IpAddr { self.addr }
+
+	/// Number of leading bits fixed by the network
+	pub fn prefix_len(&self) -> u8 { self.prefix_len }
+
+	/// Whether `addr` lies inside the network
+	pub fn contains(&self, addr: IpAddr) -> bool {
+		let addr = addr.to_canonical();
+		addr.is_ipv4() == self.addr.is_ipv4()
+			&& mask(to_bits(&addr), self.prefix_len, max_prefix_len(&addr)) == to_bits(&self.addr)
+	}
+}
+
+impl fmt::Display for Cidr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}", self.addr, self.prefix_len)
+	}
+}
+
+impl From<IpAddr> for Cidr {
+	fn from(addr: IpAddr) -> Self { Self::host(addr) }
+}
+
+/// Error raised for a malformed CIDR string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CidrError(String);
+
+impl fmt::Display for CidrError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "invalid CIDR: {}", self.0) }
+}
+
+impl std::error::Error for CidrError {}
+
+impl FromStr for Cidr {
+	type Err = CidrError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		let (addr, prefix_len) = match s.split_once('/') {
+			Some((addr, prefix_len)) => (
+				addr,
+				Some(
+					prefix_len
+						.parse::<u8>()
+						.map_err(|_| CidrError(format!("bad prefix length in `{}`", s)))?,
+				),
+			),
+			None => (s, None),
+		};
+		let addr: IpAddr = addr
+			.parse()
+			.map_err(|_| CidrError(format!("bad address in `{}`", s)))?;
+		match prefix_len {
+			Some(prefix_len) => Cidr::new(addr, prefix_len),
+			None => Ok(Cidr::host(addr)),
+		}
+	}
+}
+
+impl Serialize for Cidr {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		s.parse().map_err(serde::de::Error::custom)
+	}
+}
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+	match addr {
+		IpAddr::V4(_) => 32,
+		IpAddr::V6(_) => 128,
+	}
+}
+
+fn to_bits(addr: &IpAddr) -> u128 {
+	match addr {
+		IpAddr::V4(v4) => u32::from(*v4).into(),
+		IpAddr::V6(v6) => u128::from(*v6),
+	}
+}
+
+fn from_bits(family: &IpAddr, bits: u128) -> IpAddr {
+	match family {
+		IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+		IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+	}
+}
+
+/// Clear every bit after the first `prefix_len` of an address `width` bits wide
+fn mask(bits: u128, prefix_len: u8, width: u8) -> u128 {
+	let host_bits = u32::from(width - prefix_len);
+	bits
+		.checked_shr(host_bits)
+		.and_then(|network| network.checked_shl(host_bits))
+		.unwrap_or(0)
+}
+
+/// Longest-prefix-match table of one address family
+///
+/// Networks are grouped by prefix length, so a lookup is one hash probe per
+/// distinct prefix length in the table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PrefixTable<V> {
+	width: u8,
+	by_len: BTreeMap<u8, HashMap<u128, V>>,
+}
+
+impl<V> PrefixTable<V> {
+	fn new(width: u8) -> Self {
+		Self {
+			width,
+			by_len: BTreeMap::new(),
+		}
+	}
+
+	fn matches(&self, bits: u128) -> impl Iterator<Item = (u8, &V)> {
+		self
+			.by_len
+			.iter()
+			.rev()
+			.filter_map(move |(prefix_len, networks)| {
+				networks
+					.get(&mask(bits, *prefix_len, self.width))
+					.map(|value| (*prefix_len, value))
+			})
+	}
+
+	fn remove(&mut self, prefix_len: u8, network: u128) -> Option<V> {
+		let networks = self.by_len.get_mut(&prefix_len)?;
+		let value = networks.remove(&network);
+		if networks.is_empty() {
+			self.by_len.remove(&prefix_len);
+		}
+		value
+	}
+}
+
+/// Map from IPv4/IPv6 networks to values with longest-prefix-match lookup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CidrTable<V> {
+	v4: PrefixTable<V>,
+	v6: PrefixTable<V>,
+}
+
+impl<V> Default for CidrTable<V> {
+	fn default() -> Self { Self::new() }
+}
+
+impl<V> CidrTable<V> {
+	/// Create an empty table
+	pub fn new() -> Self {
+		Self {
+			v4: PrefixTable::new(32),
+			v6: PrefixTable::new(128),
+		}
+	}
+
+	fn table(&self, addr: &IpAddr) -> &PrefixTable<V> {
+		match addr {
+			IpAddr::V4(_) => &self.v4,
+			IpAddr::V6(_) => &self.v6,
+		}
+	}
+
+	fn table_mut(&mut self, addr: &IpAddr) -> &mut PrefixTable<V> {
+		match addr {
+			IpAddr::V4(_) => &mut self.v4,
+			IpAddr::V6(_) => &mut self.v6,
+		}
+	}
+
+	/// Add a network, returning the value it previously mapped to
+	pub fn insert(&mut self, cidr: Cidr, value: V) -> Option<V> {
+		self
+			.table_mut(&cidr.addr)
+			.by_len
+			.entry(cidr.prefix_len)
+			.or_default()
+			.insert(to_bits(&cidr.addr), value)
+	}
+
+	/// Remove a network, returning the value it mapped to
+	pub fn remove(&mut self, cidr: &Cidr) -> Option<V> {
+		self
+			.table_mut(&cidr.addr)
+			.remove(cidr.prefix_len, to_bits(&cidr.addr))
+	}
+
+	/// Every network containing `addr` and its value, most specific first
+	pub fn matches(&self, addr: IpAddr) -> impl Iterator<Item = (Cidr, &V)> {
+		let addr = addr.to_canonical();
+		self
+			.table(&addr)
+			.matches(to_bits(&addr))
+			.filter_map(move |(prefix_len, value)| Some((Cidr::new(addr, prefix_len).ok()?, value)))
+	}
+
+	/// Most specific network containing `addr` and its value
+	pub fn lookup(&self, addr: IpAddr) -> Option<(Cidr, &V)> { self.matches(addr).next() }
+
+	/// Whether any network contains `addr`
+	pub fn contains(&self, addr: IpAddr) -> bool { self.lookup(addr).is_some() }
+
+	/// Every network and its value, IPv4 first
+	pub fn iter(&self) -> impl Iterator<Item = (Cidr, &V)> {
+		let v4 = self.v4.by_len.iter().flat_map(|(prefix_len, networks)| {
+			networks.iter().map(move |(bits, value)| {
+				let cidr = Cidr {
+					addr: IpAddr::V4(Ipv4Addr::from(*bits as u32)),
+					prefix_len: *prefix_len,
+				};
+				(cidr, value)
+			})
+		});
+		let v6 = self.v6.by_len.iter().flat_map(|(prefix_len, networks)| {
+			networks.iter().map(move |(bits, value)| {
+				let cidr = Cidr {
+					addr: IpAddr::V6(Ipv6Addr::from(*bits)),
+					prefix_len: *prefix_len,
+				};
+				(cidr, value)
+			})
+		});
+		v4.chain(v6)
+	}
+
+	/// Keep only the networks for which `keep` returns true
+	pub fn retain(&mut self, mut keep: impl FnMut(&Cidr, &V) -> bool) {
+		for (family, table) in [
+			(IpAddr::V4(Ipv4Addr::UNSPECIFIED), &mut self.v4),
+			(IpAddr::V6(Ipv6Addr::UNSPECIFIED), &mut self.v6),
+		] {
+			table.by_len.retain(|prefix_len, networks| {
+				networks.retain(|bits, value| {
+					let cidr = Cidr {
+						addr: from_bits(&family, *bits),
+						prefix_len: *prefix_len,
+					};
+					keep(&cidr, value)
+				});
+				!networks.is_empty()
+			});
+		}
+	}
+
+	/// Number of networks in the table
+	pub fn len(&self) -> usize {
+		[&self.v4, &self.v6]
+			.iter()
+			.flat_map(|table| table.by_len.values())
+			.map(HashMap::len)
+			.sum()
+	}
+
+	/// Whether the table holds no network
+	pub fn is_empty(&self) -> bool { self.v4.by_len.is_empty() && self.v6.by_len.is_empty() }
+}
+
+impl<V> FromIterator<(Cidr, V)> for CidrTable<V> {
+	fn from_iter<I: IntoIterator<Item = (Cidr, V)>>(iter: I) -> Self {
+		let mut table = Self::new();
+		for (cidr, value) in iter {
+			table.insert(cidr, value);
+		}
+		table
+	}
+}
+
+impl<V: Default> FromIterator<Cidr> for CidrTable<V> {
+	fn from_iter<I: IntoIterator<Item = Cidr>>(iter: I) -> Self {
+		let mut table = Self::new();
+		for cidr in iter {
+			table.insert(cidr, V::default());
+		}
+		table
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn cidr(s: &str) -> Cidr { s.parse().unwrap() }
+
+	fn ip(s: &str) -> IpAddr { s.parse().unwrap() }
+
+	#[test]
+	fn parse_clears_host_bits() {
+		assert_eq!(cidr("192.0.2.77/24").to_string(), "192.0.2.0/24");
+		assert_eq!(cidr("10.1.2.3/0").to_string(), "0.0.0.0/0");
+		assert_eq!(cidr("2001:db8:1:2::5/33").to_string(), "2001:db8::/33");
+		assert_eq!(cidr("198.51.100.9").to_string(), "198.51.100.9/32");
+		assert_eq!(cidr("2001:db8::1").to_string(), "2001:db8::1/128");
+	}
+
+	#[test]
+	fn parse_rejects_bad_input() {
+		assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+		assert!("2001:db8::/129".parse::<Cidr>().is_err());
+		assert!("::ffff:10.0.0.0/129".parse::<Cidr>().is_err());
+		assert!("10.0.0/8".parse::<Cidr>().is_err());
+		assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+	}
+
+	#[test]
+	fn v4_mapped_networks_become_ipv4() {
+		assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+		assert_eq!(cidr("::ffff:192.0.2.1"), cidr("192.0.2.1/32"));
+		assert_eq!(cidr("::ffff:192.0.2.1/96"), cidr("0.0.0.0/0"));
+		// Shorter prefixes reach outside the mapped range and stay IPv6
+		assert_eq!(cidr("::ffff:10.0.0.0/80").to_string(), "::/80");
+	}
+
+	#[test]
+	fn contains_masks_host_bits() {
+		let network = cidr("192.0.2.0/25");
+		assert!(network.contains(ip("192.0.2.0")));
+		assert!(network.contains(ip("192.0.2.127")));
+		assert!(!network.contains(ip("192.0.2.128")));
+		assert!(network.contains(ip("::ffff:192.0.2.5")));
+		assert!(!network.contains(ip("2001:db8::")));
+		assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+		assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+	}
+
+	#[test]
+	fn lookup_prefers_the_longest_prefix() {
+		let table: CidrTable<&str> = [
+			(cidr("10.0.0.0/8"), "wide"),
+			(cidr("10.1.0.0/16"), "narrow"),
+			(cidr("2001:db8::/32"), "v6"),
+		]
+		.into_iter()
+		.collect();
+		assert_eq!(
+			table.lookup(ip("10.1.2.3")),
+			Some((cidr("10.1.0.0/16"), &"narrow"))
+		);
+		assert_eq!(
+			table.lookup(ip("10.2.0.1")),
+			Some((cidr("10.0.0.0/8"), &"wide"))
+		);
+		assert_eq!(
+			table.lookup(ip("::ffff:10.1.0.1")),
+			Some((cidr("10.1.0.0/16"), &"narrow"))
+		);
+		assert_eq!(
+			table.lookup(ip("2001:db8::1")),
+			Some((cidr("2001:db8::/32"), &"v6"))
+		);
+		assert_eq!(table.lookup(ip("11.0.0.1")), None);
+		let matches: Vec<_> = table
+			.matches(ip("10.1.9.9"))
+			.map(|(cidr, _)| cidr)
+			.collect();
+		assert_eq!(matches, [cidr("10.1.0.0/16"), cidr("10.0.0.0/8")]);
+	}
+
+	#[test]
+	fn table_insert_remove_and_retain() {
+		let mut table = CidrTable::new();
+		assert_eq!(table.insert(cidr("::ffff:10.0.0.0/104"), 1), None);
+		assert_eq!(table.insert(cidr("10.0.0.0/8"), 2), Some(1));
+		table.insert(cidr("2001:db8::/32"), 3);
+		assert_eq!(table.len(), 2);
+		table.retain(|_, value| *value != 3);
+		assert_eq!(table.iter().collect::<Vec<_>>(), [(cidr("10.0.0.0/8"), &2)]);
+		assert_eq!(table.remove(&cidr("10.0.0.0/8")), Some(2));
+		assert!(table.is_empty());
+	}
+}
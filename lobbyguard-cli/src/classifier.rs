@@ -0,0 +1,766 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::SystemTime;
+
+use etherparse::{
+	Ipv6Header, LaxNetSlice, LaxSlicedPacket, NetSlice, SlicedPacket, TransportSlice,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::cidr::{Cidr, CidrTable};
+use crate::connection_tracker::ConnectionTracker;
+use crate::packet_io::PacketMeta;
+
+/// Packet size constants for GTA Online traffic classification
+pub const HEARTBEAT_SIZES: [usize; 3] = [12, 18, 63];
+pub const MATCHMAKING_SIZES: [usize; 4] = [191, 207, 223, 239];
+/// UDP port used by GTA Online for peer-to-peer session traffic
+pub const GAME_PORT: u16 = 6672;
+/// UDP ports used by GTA Online session traffic
+pub const GAME_UDP_PORTS: [RangeInclusive<u16>; 2] = [6672..=6672, 61455..=61458];
+/// TCP ports of the GTA Online web services
+pub const GAME_TCP_PORTS: [RangeInclusive<u16>; 2] = [80..=80, 443..=443];
+
+/// Rules the classifier applies to traffic of processes tracked under one game profile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfilePolicy {
+	/// Name of the game profile
+	pub name: Arc<str>,
+	/// Local UDP port carrying session traffic
+	pub game_port: u16,
+	/// UDP payload sizes of heartbeat packets, which are always passed
+	pub heartbeat_sizes: Vec<usize>,
+	/// UDP payload sizes of matchmaking packets
+	pub matchmaking_sizes: Vec<usize>,
+}
+
+/// Known-bad peer whose traffic to tracked processes is dropped
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BlockedPeer {
+	/// When the entry stops applying, if ever
+	pub expires: Option<SystemTime>,
+	/// Free-text note on why the peer is blocked
+	pub note: Option<Arc<str>>,
+}
+
+impl BlockedPeer {
+	/// Whether the entry no longer applies at `now`
+	pub fn is_expired(&self, now: SystemTime) -> bool {
+		self.expires.is_some_and(|expires| expires <= now)
+	}
+}
+
+/// How strictly the game UDP traffic of tracked processes is filtered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+	/// Pass everything, as if lobbyguard was not running
+	Open,
+	/// Pass only heartbeats, keeping the session to ourselves
+	#[default]
+	Solo,
+	/// Pass heartbeats and every packet of allowlisted peers
+	FriendsOnly,
+	/// Drop all game UDP traffic, heartbeats included
+	Lockdown,
+}
+
+impl Mode {
+	/// Every mode, in declaration order
+	pub const ALL: [Mode; 4] = [Mode::Open, Mode::Solo, Mode::FriendsOnly, Mode::Lockdown];
+
+	/// Short machine-friendly name of the mode
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Mode::Open => "open",
+			Mode::Solo => "solo",
+			Mode::FriendsOnly => "friends-only",
+			Mode::Lockdown => "lockdown",
+		}
+	}
+}
+
+impl fmt::Display for Mode {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(self.as_str()) }
+}
+
+impl FromStr for Mode {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Mode::ALL
+			.into_iter()
+			.find(|mode| mode.as_str().eq_ignore_ascii_case(s.trim()))
+			.ok_or_else(|| {
+				let names: Vec<_> = Mode::ALL.iter().map(Mode::as_str).collect();
+				format!("unknown mode `{}`, expected one of {}", s, names.join(", "))
+			})
+	}
+}
+
+/// [`Mode`] that can be switched while packets are being classified
+#[derive(Debug, Default)]
+pub struct AtomicMode(AtomicU8);
+
+impl AtomicMode {
+	/// Hold `mode` until switched
+	pub fn new(mode: Mode) -> Self { Self(AtomicU8::new(mode as u8)) }
+
+	/// Current mode
+	pub fn load(&self) -> Mode { Mode::ALL[usize::from(self.0.load(Ordering::Relaxed))] }
+
+	/// Switch to `mode`, returning the previous one
+	pub fn swap(&self, mode: Mode) -> Mode {
+		Mode::ALL[usize::from(self.0.swap(mode as u8, Ordering::Relaxed))]
+	}
+}
+
+impl Clone for AtomicMode {
+	fn clone(&self) -> Self { Self::new(self.load()) }
+}
+
+/// Rules the classifier applies to traffic of tracked processes
+///
+/// Shared between the packet loop and its controllers; the mode and the
+/// allowlist may be changed at any time.
+#[derive(Debug)]
+pub struct Policy {
+	/// Current filtering mode
+	pub mode: AtomicMode,
+	/// Rules of every active game profile
+	pub profiles: Vec<ProfilePolicy>,
+	/// Peers whose traffic to tracked processes is passed in friends-only mode
+	pub allowlist: RwLock<CidrTable<()>>,
+	/// Peers whose traffic to tracked processes is always dropped
+	pub blocklist: CidrTable<BlockedPeer>,
+}
+
+impl Policy {
+	/// Current filtering mode
+	pub fn mode(&self) -> Mode { self.mode.load() }
+
+	/// Switch to `mode`, returning the previous one
+	pub fn set_mode(&self, mode: Mode) -> Mode { self.mode.swap(mode) }
+
+	/// Rules of the profile named `name`
+	pub fn profile(&self, name: &str) -> Option<&ProfilePolicy> {
+		self.profiles.iter().find(|profile| &*profile.name == name)
+	}
+
+	/// Whether `addr` is covered by the allowlist
+	pub fn is_allowlisted(&self, addr: IpAddr) -> bool {
+		self
+			.allowlist
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.contains(addr)
+	}
+
+	/// Every allowlisted network
+	pub fn allowlist(&self) -> Vec<Cidr> {
+		let allowlist = self.allowlist.read().unwrap_or_else(PoisonError::into_inner);
+		allowlist.iter().map(|(cidr, _)| cidr).collect()
+	}
+
+	/// Add a network to the allowlist, returning whether it was not listed yet
+	pub fn allow(&self, cidr: Cidr) -> bool {
+		self
+			.allowlist
+			.write()
+			.unwrap_or_else(PoisonError::into_inner)
+			.insert(cidr, ())
+			.is_none()
+	}
+
+	/// Remove a network from the allowlist, returning whether it was listed
+	pub fn disallow(&self, cidr: &Cidr) -> bool {
+		self
+			.allowlist
+			.write()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(cidr)
+			.is_some()
+	}
+
+	/// Most specific unexpired blocklist entry covering `addr`
+	pub fn blocked(&self, addr: IpAddr) -> Option<&BlockedPeer> {
+		let mut matches = self.blocklist.matches(addr).peekable();
+		matches.peek()?;
+		let now = SystemTime::now();
+		matches
+			.map(|(_, peer)| peer)
+			.find(|peer| !peer.is_expired(now))
+	}
+}
+
+/// Why a packet received its verdict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Reason {
+	/// Not owned by a tracked process
+	Untracked,
+	/// Game traffic of a tracked process while in open mode
+	Open,
+	/// Game traffic of a tracked process while in lockdown mode
+	Lockdown,
+	/// Traffic of a tracked process with a blocklisted peer
+	Blocklisted,
+	/// Traffic of a tracked process with an allowlisted peer
+	Allowlisted,
+	/// Heartbeat on the game port of a tracked process
+	Heartbeat,
+	/// Matchmaking request on the game port of a tracked process
+	Matchmaking,
+	/// Any other UDP traffic of a tracked process
+	TrackedUdp,
+	/// TCP traffic of a tracked process, which is never blocked
+	TcpPassthrough,
+	/// Headers could not be parsed
+	Malformed,
+	/// Not a UDP or TCP packet over IPv4/IPv6
+	Unsupported,
+	/// Cut short by the receive buffer, so let through unclassified
+	Truncated,
+}
+
+impl Reason {
+	/// Every reason, in declaration order
+	pub const ALL: [Reason; 12] = [
+		Reason::Untracked,
+		Reason::Open,
+		Reason::Lockdown,
+		Reason::Blocklisted,
+		Reason::Allowlisted,
+		Reason::Heartbeat,
+		Reason::Matchmaking,
+		Reason::TrackedUdp,
+		Reason::TcpPassthrough,
+		Reason::Malformed,
+		Reason::Unsupported,
+		Reason::Truncated,
+	];
+
+	/// Short machine-friendly name of the reason
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Reason::Untracked => "untracked",
+			Reason::Open => "open",
+			Reason::Lockdown => "lockdown",
+			Reason::Blocklisted => "blocklisted",
+			Reason::Allowlisted => "allowlisted",
+			Reason::Heartbeat => "heartbeat",
+			Reason::Matchmaking => "matchmaking",
+			Reason::TrackedUdp => "tracked-udp",
+			Reason::TcpPassthrough => "tcp-passthrough",
+			Reason::Malformed => "malformed",
+			Reason::Unsupported => "unsupported",
+			Reason::Truncated => "truncated",
+		}
+	}
+}
+
+impl fmt::Display for Reason {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(self.as_str()) }
+}
+
+/// Outcome of classifying a single packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Verdict {
+	/// Re-inject the packet
+	Pass(Reason),
+	/// Re-inject the packet and write it to the capture
+	PassAndCapture(Reason),
+	/// Discard the packet
+	Drop(Reason),
+	/// Discard the packet but write it to the capture
+	DropAndCapture(Reason),
+}
+
+impl Verdict {
+	/// Whether the packet should be re-injected
+	pub fn is_pass(&self) -> bool { matches!(self, Verdict::Pass(_) | Verdict::PassAndCapture(_)) }
+
+	/// Whether the packet should be written to the capture
+	pub fn is_capture(&self) -> bool {
+		matches!(
+			self,
+			Verdict::PassAndCapture(_) | Verdict::DropAndCapture(_)
+		)
+	}
+
+	/// Why the packet received this verdict
+	pub fn reason(&self) -> Reason {
+		match *self {
+			Verdict::Pass(reason)
+			| Verdict::PassAndCapture(reason)
+			| Verdict::Drop(reason)
+			| Verdict::DropAndCapture(reason) => reason,
+		}
+	}
+}
+
+impl fmt::Display for Verdict {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let action = if self.is_pass() { "PASSED" } else { "BLOCKED" };
+		write!(f, "{} ({})", action, self.reason())
+	}
+}
+
+/// Transport protocol of a parsed packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+	Udp,
+	Tcp,
+}
+
+impl fmt::Display for Protocol {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Protocol::Udp => "UDP",
+			Protocol::Tcp => "TCP",
+		})
+	}
+}
+
+/// Header fields of a packet relevant to classification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+	pub protocol: Protocol,
+	pub src: SocketAddr,
+	pub dst: SocketAddr,
+	pub payload_len: usize,
+}
+
+impl PacketInfo {
+	/// Parse the IP and transport headers of a raw IP packet
+	pub fn parse(data: &[u8]) -> Result<Self, Reason> {
+		let sliced_packet = SlicedPacket::from_ip(data).map_err(|_| Reason::Malformed)?;
+		Self::from_sliced(&sliced_packet)
+	}
+
+	/// Parse the headers of a raw IP packet that may have been cut short after them
+	///
+	/// The payload length is the one announced by the IP header, not the one left in `data`.
+	pub fn parse_lax(data: &[u8]) -> Result<Self, Reason> {
+		let sliced_packet = LaxSlicedPacket::from_ip(data).map_err(|_| Reason::Malformed)?;
+		let (src_addr, dst_addr, ip_len): (IpAddr, IpAddr, usize) = match &sliced_packet.net {
+			Some(LaxNetSlice::Ipv4(ip4)) => (
+				ip4.header().source_addr().into(),
+				ip4.header().destination_addr().into(),
+				ip4.header().total_len().into(),
+			),
+			Some(LaxNetSlice::Ipv6(ip6)) => (
+				ip6.header().source_addr().into(),
+				ip6.header().destination_addr().into(),
+				Ipv6Header::LEN + usize::from(ip6.header().payload_length()),
+			),
+			_ => return Err(Reason::Unsupported),
+		};
+		// Headers cut short cannot be told apart from unsupported protocols otherwise
+		if sliced_packet.transport.is_none() && sliced_packet.stop_err.is_some() {
+			return Err(Reason::Malformed);
+		}
+
+		let mut info = Self::from_transport(src_addr, dst_addr, sliced_packet.transport.as_ref())?;
+		info.payload_len += ip_len.saturating_sub(data.len());
+		Ok(info)
+	}
+
+	/// Extract the header fields from an already sliced packet
+	pub fn from_sliced(sliced_packet: &SlicedPacket) -> Result<Self, Reason> {
+		let (src_addr, dst_addr): (IpAddr, IpAddr) = match &sliced_packet.net {
+			Some(NetSlice::Ipv4(ip4)) => (
+				ip4.header().source_addr().into(),
+				ip4.header().destination_addr().into(),
+			),
+			Some(NetSlice::Ipv6(ip6)) => (
+				ip6.header().source_addr().into(),
+				ip6.header().destination_addr().into(),
+			),
+			_ => return Err(Reason::Unsupported),
+		};
+		Self::from_transport(src_addr, dst_addr, sliced_packet.transport.as_ref())
+	}
+
+	fn from_transport(
+		src_addr: IpAddr, dst_addr: IpAddr, transport: Option<&TransportSlice>,
+	) -> Result<Self, Reason> {
+		let (protocol, src_port, dst_port, payload_len) = match transport {
+			Some(TransportSlice::Udp(udp)) => (
+				Protocol::Udp,
+				udp.source_port(),
+				udp.destination_port(),
+				udp.payload().len(),
+			),
+			Some(TransportSlice::Tcp(tcp)) => (
+				Protocol::Tcp,
+				tcp.source_port(),
+				tcp.destination_port(),
+				tcp.payload().len(),
+			),
+			_ => return Err(Reason::Unsupported),
+		};
+
+		Ok(Self {
+			protocol,
+			src: SocketAddr::new(src_addr, src_port),
+			dst: SocketAddr::new(dst_addr, dst_port),
+			payload_len,
+		})
+	}
+
+	/// End of the packet on this machine's side, given the direction it travels in
+	pub fn local(&self, outbound: bool) -> SocketAddr { if outbound { self.src } else { self.dst } }
+
+	/// End of the packet on the peer's side, given the direction it travels in
+	pub fn remote(&self, outbound: bool) -> SocketAddr { if outbound { self.dst } else { self.src } }
+}
+
+/// Verdict of a packet together with the tracked process it belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Classification {
+	pub verdict: Verdict,
+	/// PID of the tracked process owning the packet, if any
+	pub pid: Option<u32>,
+	/// Profile of the tracked process owning the packet, if any
+	pub profile: Option<Arc<str>>,
+}
+
+impl Classification {
+	fn untracked() -> Self {
+		Self {
+			verdict: Verdict::Pass(Reason::Untracked),
+			pid: None,
+			profile: None,
+		}
+	}
+}
+
+/// Classify a raw IP packet
+pub fn classify(
+	data: &[u8], meta: &PacketMeta, tracker: &ConnectionTracker, policy: &Policy,
+) -> Verdict {
+	match PacketInfo::parse(data) {
+		Ok(info) => classify_info(&info, meta, tracker, policy).verdict,
+		Err(reason) => Verdict::Drop(reason),
+	}
+}
+
+/// Classify a packet from its parsed header fields, tagging it with the process it belongs to
+pub fn classify_info(
+	info: &PacketInfo, meta: &PacketMeta, tracker: &ConnectionTracker, policy: &Policy,
+) -> Classification {
+	match info.protocol {
+		Protocol::Udp => {
+			let local = info.local(meta.outbound);
+			let Some((pid, profile)) = tracker.udp_owner(local) else {
+				return Classification::untracked();
+			};
+			let mode = policy.mode();
+			let remote = info.remote(meta.outbound).ip();
+			let blocklisted = || policy.blocked(remote).is_some();
+			let allowlisted = || policy.is_allowlisted(remote);
+			let verdict = match policy.profile(&profile) {
+				_ if mode == Mode::Open => Verdict::PassAndCapture(Reason::Open),
+				_ if mode == Mode::Lockdown => Verdict::DropAndCapture(Reason::Lockdown),
+				_ if blocklisted() => Verdict::DropAndCapture(Reason::Blocklisted),
+				_ if mode == Mode::FriendsOnly && allowlisted() => {
+					Verdict::PassAndCapture(Reason::Allowlisted)
+				}
+				Some(rules) => {
+					let matching_port = local.port() == rules.game_port;
+					let size = info.payload_len;
+					if matching_port && rules.heartbeat_sizes.contains(&size) {
+						Verdict::PassAndCapture(Reason::Heartbeat)
+					} else if matching_port && rules.matchmaking_sizes.contains(&size) {
+						Verdict::DropAndCapture(Reason::Matchmaking)
+					} else {
+						Verdict::DropAndCapture(Reason::TrackedUdp)
+					}
+				}
+				None => Verdict::DropAndCapture(Reason::TrackedUdp),
+			};
+			Classification {
+				verdict,
+				pid: Some(pid),
+				profile: Some(profile),
+			}
+		}
+		Protocol::Tcp => {
+			let (local, remote) = (info.local(meta.outbound), info.remote(meta.outbound));
+			let Some((pid, profile)) = tracker.tcp_owner(local, remote) else {
+				return Classification::untracked();
+			};
+			// Blocklisted peers are cut off from the game's TCP connections too
+			let verdict = if policy.mode() != Mode::Open && policy.blocked(remote.ip()).is_some() {
+				Verdict::DropAndCapture(Reason::Blocklisted)
+			} else {
+				Verdict::PassAndCapture(Reason::TcpPassthrough)
+			};
+			Classification {
+				verdict,
+				pid: Some(pid),
+				profile: Some(profile),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use etherparse::PacketBuilder;
+
+	use super::*;
+
+	const PID: u32 = 7;
+	const PROFILE: &str = "game";
+	const LOCAL: &str = "192.168.1.2:6672";
+	const FRIEND: &str = "198.51.100.1";
+	const FOE: &str = "203.0.113.9";
+
+	fn policy(mode: Mode) -> Policy {
+		let expired = BlockedPeer {
+			expires: Some(SystemTime::now() - Duration::from_secs(60)),
+			note: None,
+		};
+		Policy {
+			mode: AtomicMode::new(mode),
+			profiles: vec![ProfilePolicy {
+				name: PROFILE.into(),
+				game_port: GAME_PORT,
+				heartbeat_sizes: vec![12],
+				matchmaking_sizes: vec![191],
+			}],
+			allowlist: RwLock::new(
+				[
+					FRIEND.parse::<Cidr>().unwrap(),
+					"192.0.2.0/24".parse().unwrap(),
+				]
+				.into_iter()
+				.collect(),
+			),
+			blocklist: [
+				(FOE.parse().unwrap(), BlockedPeer::default()),
+				// Listed on both lists, the blocklist wins
+				("192.0.2.66".parse().unwrap(), BlockedPeer::default()),
+				("192.0.2.77".parse().unwrap(), expired),
+			]
+			.into_iter()
+			.collect(),
+		}
+	}
+
+	fn tracker() -> ConnectionTracker {
+		let tracker = ConnectionTracker::new();
+		tracker.add_process(PID, PROFILE.into());
+		tracker.add_udp_endpoint(PID, LOCAL.parse().unwrap());
+		tracker.add_udp_endpoint(PID, "192.168.1.2:50000".parse().unwrap());
+		tracker
+	}
+
+	/// Inbound packet from `peer` port 6672 to `local` carrying `payload_len` bytes
+	fn inbound(protocol: Protocol, peer: &str, local: &str, payload_len: usize) -> PacketInfo {
+		PacketInfo {
+			protocol,
+			src: SocketAddr::new(peer.parse().unwrap(), 6672),
+			dst: local.parse().unwrap(),
+			payload_len,
+		}
+	}
+
+	fn verdict(mode: Mode, peer: &str, payload_len: usize) -> Verdict {
+		let info = inbound(Protocol::Udp, peer, LOCAL, payload_len);
+		classify_info(&info, &PacketMeta::default(), &tracker(), &policy(mode)).verdict
+	}
+
+	#[test]
+	fn open_and_lockdown_override_the_lists() {
+		for peer in [FRIEND, FOE, "10.0.0.1"] {
+			for size in [12, 191, 500] {
+				assert_eq!(
+					verdict(Mode::Open, peer, size),
+					Verdict::PassAndCapture(Reason::Open)
+				);
+				assert_eq!(
+					verdict(Mode::Lockdown, peer, size),
+					Verdict::DropAndCapture(Reason::Lockdown)
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn blocklist_overrides_allowlist_and_heartbeats() {
+		for mode in [Mode::Solo, Mode::FriendsOnly] {
+			assert_eq!(
+				verdict(mode, FOE, 12),
+				Verdict::DropAndCapture(Reason::Blocklisted)
+			);
+			assert_eq!(
+				verdict(mode, "192.0.2.66", 191),
+				Verdict::DropAndCapture(Reason::Blocklisted)
+			);
+		}
+		// An expired entry no longer applies
+		assert_eq!(
+			verdict(Mode::FriendsOnly, "192.0.2.77", 191),
+			Verdict::PassAndCapture(Reason::Allowlisted)
+		);
+	}
+
+	#[test]
+	fn allowlist_applies_in_friends_only_mode() {
+		assert_eq!(
+			verdict(Mode::FriendsOnly, FRIEND, 191),
+			Verdict::PassAndCapture(Reason::Allowlisted)
+		);
+		assert_eq!(
+			verdict(Mode::FriendsOnly, "::ffff:198.51.100.1", 500),
+			Verdict::PassAndCapture(Reason::Allowlisted)
+		);
+		assert_eq!(
+			verdict(Mode::Solo, FRIEND, 191),
+			Verdict::DropAndCapture(Reason::Matchmaking)
+		);
+	}
+
+	#[test]
+	fn profile_sizes_apply_on_the_game_port() {
+		for mode in [Mode::Solo, Mode::FriendsOnly] {
+			assert_eq!(
+				verdict(mode, "10.0.0.1", 12),
+				Verdict::PassAndCapture(Reason::Heartbeat)
+			);
+			assert_eq!(
+				verdict(mode, "10.0.0.1", 191),
+				Verdict::DropAndCapture(Reason::Matchmaking)
+			);
+			assert_eq!(
+				verdict(mode, "10.0.0.1", 500),
+				Verdict::DropAndCapture(Reason::TrackedUdp)
+			);
+		}
+		let info = inbound(Protocol::Udp, "10.0.0.1", "192.168.1.2:50000", 12);
+		let classification = classify_info(
+			&info,
+			&PacketMeta::default(),
+			&tracker(),
+			&policy(Mode::Solo),
+		);
+		assert_eq!(
+			classification.verdict,
+			Verdict::DropAndCapture(Reason::TrackedUdp)
+		);
+	}
+
+	#[test]
+	fn unknown_profile_is_tracked_udp() {
+		let tracker = ConnectionTracker::new();
+		tracker.add_process(PID, "other".into());
+		tracker.add_udp_endpoint(PID, LOCAL.parse().unwrap());
+		let info = inbound(Protocol::Udp, "10.0.0.1", LOCAL, 12);
+		let classification =
+			classify_info(&info, &PacketMeta::default(), &tracker, &policy(Mode::Solo));
+		assert_eq!(
+			classification.verdict,
+			Verdict::DropAndCapture(Reason::TrackedUdp)
+		);
+	}
+
+	#[test]
+	fn owner_is_reported() {
+		let tracker = tracker();
+		let policy = policy(Mode::Solo);
+		let meta = PacketMeta::default();
+
+		let classification = classify_info(
+			&inbound(Protocol::Udp, FOE, LOCAL, 12),
+			&meta,
+			&tracker,
+			&policy,
+		);
+		assert_eq!(classification.pid, Some(PID));
+		assert_eq!(classification.profile.as_deref(), Some(PROFILE));
+
+		let untracked = inbound(Protocol::Udp, FOE, "192.168.1.2:5000", 12);
+		assert_eq!(
+			classify_info(&untracked, &meta, &tracker, &policy),
+			Classification::untracked()
+		);
+
+		let tcp = inbound(Protocol::Tcp, "10.0.0.1", "192.168.1.2:50001", 100);
+		assert_eq!(
+			classify_info(&tcp, &meta, &tracker, &policy),
+			Classification::untracked()
+		);
+		tracker.add_tcp_connection(PID, tcp.dst, tcp.src);
+		let classification = classify_info(&tcp, &meta, &tracker, &policy);
+		assert_eq!(
+			classification.verdict,
+			Verdict::PassAndCapture(Reason::TcpPassthrough)
+		);
+		assert_eq!(classification.pid, Some(PID));
+	}
+
+	#[test]
+	fn blocklist_applies_to_tracked_tcp_connections() {
+		let tracker = tracker();
+		let meta = PacketMeta::default();
+		let foe = inbound(Protocol::Tcp, FOE, "192.168.1.2:50001", 100);
+		let friend = inbound(Protocol::Tcp, FRIEND, "192.168.1.2:50002", 100);
+		tracker.add_tcp_connection(PID, foe.dst, foe.src);
+		tracker.add_tcp_connection(PID, friend.dst, friend.src);
+
+		for mode in [Mode::Solo, Mode::FriendsOnly, Mode::Lockdown] {
+			let policy = policy(mode);
+			assert_eq!(
+				classify_info(&foe, &meta, &tracker, &policy).verdict,
+				Verdict::DropAndCapture(Reason::Blocklisted)
+			);
+			assert_eq!(
+				classify_info(&friend, &meta, &tracker, &policy).verdict,
+				Verdict::PassAndCapture(Reason::TcpPassthrough)
+			);
+		}
+		assert_eq!(
+			classify_info(&foe, &meta, &tracker, &policy(Mode::Open)).verdict,
+			Verdict::PassAndCapture(Reason::TcpPassthrough)
+		);
+	}
+
+	#[test]
+	fn classify_raw_packets() {
+		let tracker = tracker();
+		let policy = policy(Mode::Solo);
+		let meta = PacketMeta::default();
+		let udp = |local_port: u16, payload_len: usize| {
+			let builder = PacketBuilder::ipv4([10, 0, 0, 1], [192, 168, 1, 2], 64).udp(6672, local_port);
+			let mut data = Vec::with_capacity(builder.size(payload_len));
+			builder.write(&mut data, &vec![0; payload_len]).unwrap();
+			data
+		};
+		let icmp = {
+			let builder =
+				PacketBuilder::ipv4([10, 0, 0, 1], [192, 168, 1, 2], 64).icmpv4_echo_request(1, 1);
+			let mut data = Vec::with_capacity(builder.size(0));
+			builder.write(&mut data, &[]).unwrap();
+			data
+		};
+		let cases = [
+			(udp(6672, 12), Verdict::PassAndCapture(Reason::Heartbeat)),
+			(udp(6672, 191), Verdict::DropAndCapture(Reason::Matchmaking)),
+			(udp(5000, 191), Verdict::Pass(Reason::Untracked)),
+			(icmp, Verdict::Drop(Reason::Unsupported)),
+			(vec![0x45, 0, 0], Verdict::Drop(Reason::Malformed)),
+		];
+		for (data, expected) in cases {
+			assert_eq!(classify(&data, &meta, &tracker, &policy), expected);
+		}
+	}
+}
@@ -0,0 +1,588 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+use toml::value::{Datetime, Offset, Time};
+
+use crate::cidr::Cidr;
+use crate::classifier::{
+	AtomicMode, BlockedPeer, GAME_PORT, GAME_TCP_PORTS, GAME_UDP_PORTS, HEARTBEAT_SIZES,
+	MATCHMAKING_SIZES, Mode, Policy, ProfilePolicy,
+};
+
+/// Built-in profile names and the executables they track
+pub const GTA5_ENHANCED_PROFILE: &str = "gta5-enhanced";
+pub const GTA5_ENHANCED_PROCESS_NAME: &str = "GTA5_Enhanced.exe";
+pub const GTA5_LEGACY_PROFILE: &str = "gta5-legacy";
+pub const GTA5_LEGACY_PROCESS_NAME: &str = "GTA5.exe";
+pub const RDR2_PROFILE: &str = "rdr2";
+pub const RDR2_PROCESS_NAME: &str = "RDR2.exe";
+
+/// Largest payload a UDP datagram can carry over IPv4
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// A single port or an inclusive port range, written as `6672` or `[61455, 61458]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum PortSpec {
+	Single(u16),
+	Range([u16; 2]),
+}
+
+impl PortSpec {
+	/// The inclusive range of ports this spec covers
+	pub fn range(&self) -> RangeInclusive<u16> {
+		match *self {
+			PortSpec::Single(port) => port..=port,
+			PortSpec::Range([start, end]) => start..=end,
+		}
+	}
+}
+
+impl From<RangeInclusive<u16>> for PortSpec {
+	fn from(range: RangeInclusive<u16>) -> Self {
+		let (start, end) = range.into_inner();
+		if start == end {
+			PortSpec::Single(start)
+		} else {
+			PortSpec::Range([start, end])
+		}
+	}
+}
+
+impl fmt::Display for PortSpec {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PortSpec::Single(port) => write!(f, "{}", port),
+			PortSpec::Range([start, end]) => write!(f, "{}-{}", start, end),
+		}
+	}
+}
+
+/// Ports, packet sizes and process names of one game
+///
+/// Keys missing from a profile in the configuration file fall back to the
+/// GTA Online values.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+	/// Executable names of the game processes to track
+	pub process_names: Vec<String>,
+	/// Local UDP port carrying session traffic
+	pub game_port: u16,
+	/// UDP ports diverted for inspection
+	pub udp_ports: Vec<PortSpec>,
+	/// TCP ports diverted for inspection when TCP capture is enabled
+	pub tcp_ports: Vec<PortSpec>,
+	/// UDP payload sizes of heartbeat packets
+	pub heartbeat_sizes: Vec<usize>,
+	/// UDP payload sizes of matchmaking packets
+	pub matchmaking_sizes: Vec<usize>,
+}
+
+impl Default for Profile {
+	fn default() -> Self { Self::rockstar(GTA5_ENHANCED_PROCESS_NAME) }
+}
+
+impl Profile {
+	/// Profile of a Rockstar title sharing the GTA Online session protocol
+	fn rockstar(process_name: &str) -> Self {
+		Self {
+			process_names: vec![process_name.to_owned()],
+			game_port: GAME_PORT,
+			udp_ports: GAME_UDP_PORTS.into_iter().map(PortSpec::from).collect(),
+			tcp_ports: GAME_TCP_PORTS.into_iter().map(PortSpec::from).collect(),
+			heartbeat_sizes: HEARTBEAT_SIZES.to_vec(),
+			matchmaking_sizes: MATCHMAKING_SIZES.to_vec(),
+		}
+	}
+
+	/// Check that every value of the profile is usable and consistent
+	fn validate(&self, name: &str) -> Result<(), ConfigError> {
+		let invalid = |message: String| {
+			Err(ConfigError::Invalid(format!(
+				"profile `{}`: {}",
+				name, message
+			)))
+		};
+
+		if self.process_names.is_empty() {
+			return invalid("`process-names` must list at least one executable".to_owned());
+		}
+		if let Some(name) = self
+			.process_names
+			.iter()
+			.find(|name| name.trim().is_empty())
+		{
+			return invalid(format!("`process-names` contains an empty name {:?}", name));
+		}
+		if self.game_port == 0 {
+			return invalid("`game-port` must not be 0".to_owned());
+		}
+		if self.udp_ports.is_empty() {
+			return invalid("`udp-ports` must list at least one port".to_owned());
+		}
+		for (key, ports) in [
+			("udp-ports", &self.udp_ports),
+			("tcp-ports", &self.tcp_ports),
+		] {
+			for spec in ports {
+				let range = spec.range();
+				if *range.start() == 0 {
+					return invalid(format!("`{}` entry {} must not include port 0", key, spec));
+				}
+				if range.is_empty() {
+					return invalid(format!(
+						"`{}` entry {} has its start after its end",
+						key, spec
+					));
+				}
+			}
+		}
+		if !self
+			.udp_ports
+			.iter()
+			.any(|spec| spec.range().contains(&self.game_port))
+		{
+			return invalid(format!(
+				"`game-port` {} is not covered by `udp-ports`",
+				self.game_port
+			));
+		}
+		if self.heartbeat_sizes.is_empty() {
+			return invalid("`heartbeat-sizes` must list at least one size".to_owned());
+		}
+		for (key, sizes) in [
+			("heartbeat-sizes", &self.heartbeat_sizes),
+			("matchmaking-sizes", &self.matchmaking_sizes),
+		] {
+			if let Some(size) = sizes.iter().find(|size| **size > MAX_UDP_PAYLOAD) {
+				return invalid(format!(
+					"`{}` entry {} exceeds the maximum UDP payload of {}",
+					key, size, MAX_UDP_PAYLOAD
+				));
+			}
+		}
+		if let Some(size) = self
+			.heartbeat_sizes
+			.iter()
+			.find(|size| self.matchmaking_sizes.contains(size))
+		{
+			return invalid(format!(
+				"size {} is listed in both `heartbeat-sizes` and `matchmaking-sizes`",
+				size
+			));
+		}
+		Ok(())
+	}
+}
+
+/// Known-bad peer listed under `[[blocklist]]`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BlocklistEntry {
+	/// Address or network of the peer
+	pub cidr: Cidr,
+	/// Date, or date and time with an offset, after which the entry is ignored
+	pub expires: Option<Datetime>,
+	/// Free-text note on why the peer is blocked
+	pub note: Option<String>,
+}
+
+impl BlocklistEntry {
+	/// Point in time at which the entry expires, if any
+	pub fn expiry_time(&self) -> Result<Option<SystemTime>, ConfigError> {
+		self
+			.expires
+			.as_ref()
+			.map(|expires| {
+				datetime_to_system_time(expires).ok_or_else(|| {
+					ConfigError::Invalid(format!(
+						"blocklist entry {}: `expires` {} must be a date or a date and time with an offset",
+						self.cidr, expires
+					))
+				})
+			})
+			.transpose()
+	}
+}
+
+/// Convert a TOML date, or a date and time with an offset, to a point in time
+///
+/// A bare date stands for midnight UTC.
+fn datetime_to_system_time(datetime: &Datetime) -> Option<SystemTime> {
+	let date = datetime.date?;
+	let offset_minutes = match (datetime.time, datetime.offset) {
+		(None, None) => 0,
+		(Some(_), Some(Offset::Z)) => 0,
+		(Some(_), Some(Offset::Custom { minutes })) => i64::from(minutes),
+		_ => return None,
+	};
+	let time = datetime.time.unwrap_or(Time {
+		hour: 0,
+		minute: 0,
+		second: 0,
+		nanosecond: 0,
+	});
+
+	// Days since the Unix epoch in the proleptic Gregorian calendar
+	let (month, day) = (i64::from(date.month), i64::from(date.day));
+	let year = i64::from(date.year) - i64::from(month <= 2);
+	let era = year.div_euclid(400);
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	let days = era * 146097 + day_of_era - 719468;
+
+	let seconds = days * 86400
+		+ i64::from(time.hour) * 3600
+		+ i64::from(time.minute) * 60
+		+ i64::from(time.second)
+		- offset_minutes * 60;
+	let since_epoch = Duration::new(seconds.unsigned_abs(), time.nanosecond);
+	if seconds >= 0 {
+		SystemTime::UNIX_EPOCH.checked_add(since_epoch)
+	} else {
+		SystemTime::UNIX_EPOCH.checked_sub(since_epoch)
+	}
+}
+
+/// Layout of the TOML configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+	active_profiles: Option<Vec<String>>,
+	mode: Option<Mode>,
+	allowlist: Vec<Cidr>,
+	blocklist: Vec<BlocklistEntry>,
+	profiles: BTreeMap<String, Profile>,
+}
+
+/// Settings loaded from the TOML configuration file
+///
+/// Profiles defined in the file are added to the built-in ones, replacing a
+/// built-in profile of the same name. Unless `active-profiles` is set, only the
+/// profiles defined in the file are active, or the GTA V ones if there are none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+	/// Names of the profiles tracked at the same time
+	pub active_profiles: Vec<String>,
+	/// Filtering mode at startup
+	pub mode: Mode,
+	/// Peer addresses and networks whose game traffic is passed in friends-only mode
+	pub allowlist: Vec<Cidr>,
+	/// Known-bad peers whose game traffic is always dropped
+	pub blocklist: Vec<BlocklistEntry>,
+	/// Every known profile by name
+	pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		let profiles = BTreeMap::from([
+			(
+				GTA5_ENHANCED_PROFILE.to_owned(),
+				Profile::rockstar(GTA5_ENHANCED_PROCESS_NAME),
+			),
+			(
+				GTA5_LEGACY_PROFILE.to_owned(),
+				Profile::rockstar(GTA5_LEGACY_PROCESS_NAME),
+			),
+			// Red Dead Online is assumed to share the GTA Online packet sizes,
+			// which is unverified, so the profile must be activated explicitly
+			(
+				RDR2_PROFILE.to_owned(),
+				Profile::rockstar(RDR2_PROCESS_NAME),
+			),
+		]);
+		Self {
+			active_profiles: vec![
+				GTA5_ENHANCED_PROFILE.to_owned(),
+				GTA5_LEGACY_PROFILE.to_owned(),
+			],
+			mode: Mode::default(),
+			allowlist: Vec::new(),
+			blocklist: Vec::new(),
+			profiles,
+		}
+	}
+}
+
+/// Error raised while loading the configuration file
+#[derive(Debug)]
+pub enum ConfigError {
+	/// The file could not be read
+	Io(PathBuf, std::io::Error),
+	/// The file is not valid TOML or has unexpected keys
+	Parse(toml::de::Error),
+	/// A value is out of range or inconsistent with another one
+	Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ConfigError::Io(path, e) => write!(f, "failed to read config {:?}: {}", path, e),
+			ConfigError::Parse(e) => write!(f, "failed to parse config: {}", e),
+			ConfigError::Invalid(message) => write!(f, "invalid config: {}", message),
+		}
+	}
+}
+
+impl std::error::Error for ConfigError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			ConfigError::Io(_, e) => Some(e),
+			ConfigError::Parse(e) => Some(e),
+			ConfigError::Invalid(_) => None,
+		}
+	}
+}
+
+impl Config {
+	/// Read, parse and validate a configuration file
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+		let path = path.as_ref();
+		let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+		Self::from_toml(&text)
+	}
+
+	/// Parse and validate a configuration from TOML text
+	pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+		let file: ConfigFile = toml::from_str(text).map_err(ConfigError::Parse)?;
+		let mut config = Config::default();
+		if file.active_profiles.is_none() && !file.profiles.is_empty() {
+			config.active_profiles = file.profiles.keys().cloned().collect();
+		}
+		config.profiles.extend(file.profiles);
+		config.mode = file.mode.unwrap_or_default();
+		config.allowlist = file.allowlist;
+		config.blocklist = file.blocklist;
+		if let Some(active_profiles) = file.active_profiles {
+			config.active_profiles = active_profiles;
+		}
+		config.validate()?;
+		Ok(config)
+	}
+
+	/// Check that every active profile is usable and that they do not overlap
+	pub fn validate(&self) -> Result<(), ConfigError> {
+		if self.active_profiles.is_empty() {
+			return Err(ConfigError::Invalid(
+				"`active-profiles` must name at least one profile".to_owned(),
+			));
+		}
+		for name in &self.active_profiles {
+			if !self.profiles.contains_key(name) {
+				return Err(ConfigError::Invalid(format!(
+					"`active-profiles` names unknown profile `{}`",
+					name
+				)));
+			}
+		}
+		for (name, profile) in self.active() {
+			profile.validate(name)?;
+			for process_name in &profile.process_names {
+				if let Some(other) = self.profile_for_process(process_name)
+					&& other != name
+				{
+					return Err(ConfigError::Invalid(format!(
+						"process `{}` is listed in both profile `{}` and profile `{}`",
+						process_name, other, name
+					)));
+				}
+			}
+		}
+		for entry in &self.blocklist {
+			entry.expiry_time()?;
+		}
+		Ok(())
+	}
+
+	/// Whether peers are allowlisted while the starting mode ignores the allowlist
+	pub fn ignores_allowlist(&self) -> bool {
+		!self.allowlist.is_empty() && self.mode != Mode::FriendsOnly
+	}
+
+	/// Active profiles with their names, in activation order
+	pub fn active(&self) -> impl Iterator<Item = (&str, &Profile)> {
+		self.active_profiles.iter().filter_map(|name| {
+			self
+				.profiles
+				.get(name)
+				.map(|profile| (name.as_str(), profile))
+		})
+	}
+
+	/// Executable names of every active profile
+	pub fn process_names(&self) -> impl Iterator<Item = &str> {
+		self
+			.active()
+			.flat_map(|(_, profile)| profile.process_names.iter().map(String::as_str))
+	}
+
+	/// Name of the active profile tracking the executable `name`
+	pub fn profile_for_process(&self, name: &str) -> Option<&str> {
+		self.active().find_map(|(profile_name, profile)| {
+			profile
+				.process_names
+				.iter()
+				.any(|process_name| process_name.eq_ignore_ascii_case(name))
+				.then_some(profile_name)
+		})
+	}
+
+	/// UDP ports of every active profile as deduplicated ranges
+	pub fn udp_port_ranges(&self) -> Vec<RangeInclusive<u16>> {
+		self.port_ranges(|profile| &profile.udp_ports)
+	}
+
+	/// TCP ports of every active profile as deduplicated ranges
+	pub fn tcp_port_ranges(&self) -> Vec<RangeInclusive<u16>> {
+		self.port_ranges(|profile| &profile.tcp_ports)
+	}
+
+	fn port_ranges(&self, ports: impl Fn(&Profile) -> &Vec<PortSpec>) -> Vec<RangeInclusive<u16>> {
+		let mut ranges: Vec<RangeInclusive<u16>> = Vec::new();
+		for (_, profile) in self.active() {
+			for range in ports(profile).iter().map(PortSpec::range) {
+				if !ranges.contains(&range) {
+					ranges.push(range);
+				}
+			}
+		}
+		ranges
+	}
+
+	/// Classification policy covering every active profile
+	pub fn policy(&self) -> Policy {
+		Policy {
+			mode: AtomicMode::new(self.mode),
+			profiles: self
+				.active()
+				.map(|(name, profile)| ProfilePolicy {
+					name: Arc::from(name),
+					game_port: profile.game_port,
+					heartbeat_sizes: profile.heartbeat_sizes.clone(),
+					matchmaking_sizes: profile.matchmaking_sizes.clone(),
+				})
+				.collect(),
+			allowlist: RwLock::new(self.allowlist.iter().copied().collect()),
+			blocklist: self
+				.blocklist
+				.iter()
+				.map(|entry| {
+					let peer = BlockedPeer {
+						expires: entry.expiry_time().ok().flatten(),
+						note: entry.note.as_deref().map(Arc::from),
+					};
+					(entry.cidr, peer)
+				})
+				.collect(),
+		}
+	}
+}
+
+/// Rules of the built-in profiles, as if no configuration file was given
+impl Default for Policy {
+	fn default() -> Self { Config::default().policy() }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn system_time(datetime: &str) -> Option<SystemTime> {
+		datetime_to_system_time(&datetime.parse::<Datetime>().unwrap())
+	}
+
+	fn from_epoch(seconds: i64, nanos: u32) -> SystemTime {
+		let offset = Duration::new(seconds.unsigned_abs(), 0);
+		let time = if seconds >= 0 {
+			SystemTime::UNIX_EPOCH + offset
+		} else {
+			SystemTime::UNIX_EPOCH - offset
+		};
+		time + Duration::from_nanos(nanos.into())
+	}
+
+	#[test]
+	fn rfc3339_to_system_time() {
+		assert_eq!(
+			system_time("1970-01-01T00:00:00Z"),
+			Some(SystemTime::UNIX_EPOCH)
+		);
+		assert_eq!(system_time("2000-03-01"), Some(from_epoch(951_868_800, 0)));
+		assert_eq!(
+			system_time("2024-02-29T12:34:56.5+02:00"),
+			Some(from_epoch(1_709_202_896, 500_000_000))
+		);
+		assert_eq!(
+			system_time("2038-01-19T03:14:08Z"),
+			Some(from_epoch(2_147_483_648, 0))
+		);
+		assert_eq!(system_time("1969-12-31T23:59:59Z"), Some(from_epoch(-1, 0)));
+		assert_eq!(
+			system_time("1900-03-01T00:00:00-05:30"),
+			Some(from_epoch(-2_203_871_400, 0))
+		);
+	}
+
+	#[test]
+	fn default_profiles() {
+		let config = Config::from_toml("").unwrap();
+		assert_eq!(config, Config::default());
+		assert_eq!(
+			config.active_profiles,
+			[GTA5_ENHANCED_PROFILE, GTA5_LEGACY_PROFILE]
+		);
+		assert!(config.profiles.contains_key(RDR2_PROFILE));
+		assert!(config.process_names().all(|name| name != RDR2_PROCESS_NAME));
+
+		let config = Config::from_toml("active-profiles = [\"rdr2\"]").unwrap();
+		assert_eq!(
+			config.process_names().collect::<Vec<_>>(),
+			[RDR2_PROCESS_NAME]
+		);
+	}
+
+	#[test]
+	fn file_profiles_replace_the_active_ones() {
+		// Claims the executable of a built-in profile, which is then inactive
+		let toml = "[profiles.legacy]\nprocess-names = [\"GTA5.exe\"]";
+		let config = Config::from_toml(toml).unwrap();
+		assert_eq!(config.active_profiles, ["legacy"]);
+		assert_eq!(config.profile_for_process("GTA5.exe"), Some("legacy"));
+		assert!(config.profiles.contains_key(GTA5_LEGACY_PROFILE));
+
+		let toml = format!("active-profiles = [\"legacy\", \"gta5-legacy\"]\n{}", toml);
+		assert!(matches!(
+			Config::from_toml(&toml),
+			Err(ConfigError::Invalid(_))
+		));
+	}
+
+	#[test]
+	fn allowlist_keeps_the_default_mode() {
+		let config = Config::from_toml("allowlist = [\"198.51.100.0/24\"]").unwrap();
+		assert_eq!(config.mode, Mode::Solo);
+		assert_eq!(config.allowlist, ["198.51.100.0/24".parse().unwrap()]);
+		assert!(config.ignores_allowlist());
+
+		let config =
+			Config::from_toml("mode = \"friends-only\"\nallowlist = [\"198.51.100.0/24\"]").unwrap();
+		assert_eq!(config.mode, Mode::FriendsOnly);
+		assert!(!config.ignores_allowlist());
+		assert!(!Config::default().ignores_allowlist());
+	}
+
+	#[test]
+	fn rfc3339_without_offset_is_rejected() {
+		assert_eq!(system_time("2024-02-29T12:34:56"), None);
+		assert_eq!(system_time("12:34:56"), None);
+	}
+}
@@ -1,116 +1,718 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
 use dashmap::{DashMap, DashSet};
 use log::debug;
+use serde::Serialize;
+
+/// Tracked process and its connections at one point in time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TrackedProcess {
+	pub pid: u32,
+	/// Name of the game profile of the process
+	pub profile: String,
+	/// Local addresses of the UDP endpoints
+	pub udp_endpoints: Vec<SocketAddr>,
+	pub tcp_connections: Vec<TcpConnection>,
+}
+
+/// Both ends of a TCP connection of this machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct TcpConnection {
+	pub local: SocketAddr,
+	pub remote: SocketAddr,
+}
+
+impl TcpConnection {
+	/// Connection between `local` and `remote`, with IPv4-mapped addresses made plain IPv4
+	pub fn new(local: SocketAddr, remote: SocketAddr) -> Self {
+		Self {
+			local: canonical(local),
+			remote: canonical(remote),
+		}
+	}
+}
+
+/// Change bringing the tracker back in line with the processes and connections of the system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Correction {
+	/// Stop tracking a process whose exit was missed
+	Untrack { pid: u32 },
+	/// Track a process whose start was missed
+	Track { pid: u32, profile: Arc<str> },
+	/// Forget a TCP connection that was closed or moved to another process
+	RemoveTcp { pid: u32, connection: TcpConnection },
+	/// Forget a UDP endpoint that was closed or moved to another process
+	RemoveUdp { pid: u32, local: SocketAddr },
+	/// Add a TCP connection whose creation was missed
+	AddTcp { pid: u32, connection: TcpConnection },
+	/// Add a UDP endpoint whose creation was missed
+	AddUdp { pid: u32, local: SocketAddr },
+}
+
+/// Tracked process bound to a local UDP address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UdpBinding {
+	pid: u32,
+	/// Whether an IPv6 socket also receives IPv4 traffic
+	dual_stack: bool,
+}
+
+/// Socket address with an IPv4-mapped IPv6 address made plain IPv4
+fn canonical(addr: SocketAddr) -> SocketAddr {
+	SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
 
 /// Manages tracking of game processes and their network connections
 pub struct ConnectionTracker {
-	/// Set of tracked process IDs (e.g., GTA5_Enhanced.exe)
-	pub pid_set: DashSet<u32>,
-	/// Map of PID -> Set<(local_port, remote_port)> for TCP connections
-	pub tcp_map: DashMap<u32, DashSet<(u16, u16)>>,
-	/// Map of PID -> Set<local_port> for UDP endpoints
-	pub udp_map: DashMap<u32, DashSet<u16>>,
+	/// Map of tracked process ID (e.g., GTA5_Enhanced.exe) -> name of its game profile
+	pub pid_map: DashMap<u32, Arc<str>>,
+	/// Map of PID -> Set<TcpConnection> for TCP connections
+	pub tcp_map: DashMap<u32, DashSet<TcpConnection>>,
+	/// Map of PID -> Set<local_address> for UDP endpoints
+	pub udp_map: DashMap<u32, DashSet<SocketAddr>>,
+	/// Map of TcpConnection -> PID, the reverse of `tcp_map`
+	tcp_index: DashMap<TcpConnection, u32>,
+	/// Map of local_address -> PID, the reverse of `udp_map`
+	udp_index: DashMap<SocketAddr, UdpBinding>,
 }
 
 impl ConnectionTracker {
 	/// Create a new ConnectionTracker
 	pub fn new() -> Self {
 		Self {
-			pid_set: DashSet::new(),
+			pid_map: DashMap::new(),
 			tcp_map: DashMap::new(),
 			udp_map: DashMap::new(),
+			tcp_index: DashMap::new(),
+			udp_index: DashMap::new(),
 		}
 	}
 
-	/// Add a process ID to track
-	pub fn add_process(&self, pid: u32) { self.pid_set.insert(pid); }
+	/// Add a process ID to track under the given game profile
+	pub fn add_process(&self, pid: u32, profile: Arc<str>) { self.pid_map.insert(pid, profile); }
 
 	/// Remove a process ID and its connections
 	pub fn remove_process(&self, pid: u32) {
-		self.pid_set.remove(&pid);
-		self.tcp_map.remove(&pid);
-		self.udp_map.remove(&pid);
+		self.pid_map.remove(&pid);
+		if let Some((_, connections)) = self.tcp_map.remove(&pid) {
+			for connection in connections {
+				self
+					.tcp_index
+					.remove_if(&connection, |_, owner| *owner == pid);
+			}
+		}
+		if let Some((_, endpoints)) = self.udp_map.remove(&pid) {
+			for endpoint in endpoints {
+				self
+					.udp_index
+					.remove_if(&endpoint, |_, owner| owner.pid == pid);
+			}
+		}
 	}
 
 	/// Check if a process is being tracked
-	pub fn contains_process(&self, pid: u32) -> bool { self.pid_set.contains(&pid) }
+	pub fn contains_process(&self, pid: u32) -> bool { self.pid_map.contains_key(&pid) }
+
+	/// Name of the game profile a tracked process belongs to
+	pub fn process_profile(&self, pid: u32) -> Option<Arc<str>> {
+		self.pid_map.get(&pid).map(|entry| Arc::clone(entry.value()))
+	}
+
+	/// Every tracked process with its connections, ordered by PID
+	pub fn processes(&self) -> Vec<TrackedProcess> {
+		let mut processes: Vec<_> = self
+			.pid_map
+			.iter()
+			.map(|entry| {
+				let pid = *entry.key();
+				let mut udp_endpoints: Vec<_> = self
+					.udp_map
+					.view(&pid, |_, endpoints| {
+						endpoints.iter().map(|endpoint| *endpoint).collect()
+					})
+					.unwrap_or_default();
+				udp_endpoints.sort_unstable();
+				let mut tcp_connections: Vec<_> = self
+					.tcp_map
+					.view(&pid, |_, connections| {
+						connections.iter().map(|connection| *connection).collect()
+					})
+					.unwrap_or_default();
+				tcp_connections.sort_unstable();
+				TrackedProcess {
+					pid,
+					profile: entry.value().to_string(),
+					udp_endpoints,
+					tcp_connections,
+				}
+			})
+			.collect();
+		processes.sort_unstable_by_key(|process| process.pid);
+		processes
+	}
 
-	/// Add a TCP connection for a process
-	pub fn add_tcp_connection(&self, pid: u32, local_port: u16, remote_port: u16) {
-		if local_port == 0 || remote_port == 0 || pid == 0 {
+	/// Add a TCP connection between `local` and `remote` for a process
+	pub fn add_tcp_connection(&self, pid: u32, local: SocketAddr, remote: SocketAddr) {
+		if local.port() == 0 || remote.port() == 0 || pid == 0 {
 			return;
 		}
 		debug!(
 			"TCP connection added for PID {}: local:{} <=> remote:{}",
-			pid, local_port, remote_port
+			pid, local, remote
 		);
+		// A reused connection now belongs to the new process only
+		let connection = TcpConnection::new(local, remote);
+		if let Some(previous) = self.tcp_index.insert(connection, pid)
+			&& previous != pid
+			&& let Some(entry) = self.tcp_map.get(&previous)
+		{
+			entry.value().remove(&connection);
+		}
 		let entry = self.tcp_map.entry(pid).or_default();
-		entry.value().insert((local_port, remote_port));
+		entry.value().insert(connection);
 	}
 
-	/// Remove a TCP connection for a process
-	pub fn remove_tcp_connection(&self, pid: u32, local_port: u16, remote_port: u16) {
-		if local_port == 0 || remote_port == 0 || pid == 0 {
+	/// Remove the TCP connection between `local` and `remote` for a process
+	pub fn remove_tcp_connection(&self, pid: u32, local: SocketAddr, remote: SocketAddr) {
+		if local.port() == 0 || remote.port() == 0 || pid == 0 {
 			return;
 		}
+		let connection = TcpConnection::new(local, remote);
 		if let Some(entry) = self.tcp_map.get(&pid) {
 			debug!(
 				"TCP connection removed for PID {}: local:{} <=> remote:{}",
-				pid, local_port, remote_port
+				pid, local, remote
 			);
-			entry.value().remove(&(local_port, remote_port));
+			entry.value().remove(&connection);
 		}
+		self
+			.tcp_index
+			.remove_if(&connection, |_, owner| *owner == pid);
+	}
+
+	/// Add a UDP endpoint bound to `local` for a process
+	///
+	/// WMI does not report `IPV6_V6ONLY`, so an endpoint bound to `::` is taken to be
+	/// dual-stack and receive IPv4 traffic as well, rather than let that traffic through.
+	pub fn add_udp_endpoint(&self, pid: u32, local: SocketAddr) {
+		self.insert_udp_endpoint(pid, local, local.ip() == Ipv6Addr::UNSPECIFIED)
 	}
 
-	/// Add a UDP endpoint for a process
-	pub fn add_udp_endpoint(&self, pid: u32, local_port: u16) {
-		if local_port == 0 || pid == 0 {
+	fn insert_udp_endpoint(&self, pid: u32, local: SocketAddr, dual_stack: bool) {
+		if local.port() == 0 || pid == 0 {
 			return;
 		}
-		debug!("UDP endpoint added for PID {}: local:{}", pid, local_port);
+		debug!(
+			"UDP endpoint added for PID {}: local:{}{}",
+			pid,
+			local,
+			if dual_stack { " (dual-stack)" } else { "" }
+		);
+		// A reused endpoint now belongs to the new process only
+		let local = canonical(local);
+		let binding = UdpBinding { pid, dual_stack };
+		if let Some(previous) = self.udp_index.insert(local, binding)
+			&& previous.pid != pid
+			&& let Some(entry) = self.udp_map.get(&previous.pid)
+		{
+			entry.value().remove(&local);
+		}
 		let entry = self.udp_map.entry(pid).or_default();
-		entry.value().insert(local_port);
+		entry.value().insert(local);
 	}
 
-	/// Remove a UDP endpoint for a process
-	pub fn remove_udp_endpoint(&self, pid: u32, local_port: u16) {
-		if local_port == 0 || pid == 0 {
+	/// Remove the UDP endpoint bound to `local` for a process
+	pub fn remove_udp_endpoint(&self, pid: u32, local: SocketAddr) {
+		if local.port() == 0 || pid == 0 {
 			return;
 		}
+		let local = canonical(local);
 		if let Some(entry) = self.udp_map.get(&pid) {
-			debug!("UDP endpoint removed for PID {}: local:{}", pid, local_port);
-			entry.value().remove(&local_port);
+			debug!("UDP endpoint removed for PID {}: local:{}", pid, local);
+			entry.value().remove(&local);
 		}
+		self
+			.udp_index
+			.remove_if(&local, |_, owner| owner.pid == pid);
 	}
 
-	/// Check if a UDP packet with the given local port belongs to a tracked process
-	pub fn is_tracked_udp(&self, local_port: u16) -> bool {
-		if local_port == 0 {
-			return false;
+	/// Check if a UDP packet with the given local address belongs to a tracked process
+	pub fn is_tracked_udp(&self, local: SocketAddr) -> bool { self.udp_profile(local).is_some() }
+
+	/// Profile of the tracked process owning the given local UDP address
+	pub fn udp_profile(&self, local: SocketAddr) -> Option<Arc<str>> {
+		self.udp_owner(local).map(|(_, profile)| profile)
+	}
+
+	/// PID and profile of the tracked process owning the given local UDP address
+	///
+	/// An endpoint bound to the exact address wins over one bound to every address of its
+	/// family, which wins over a dual-stack one bound to `::` for IPv4 addresses.
+	pub fn udp_owner(&self, local: SocketAddr) -> Option<(u32, Arc<str>)> {
+		if local.port() == 0 {
+			return None;
 		}
-		self.pid_set.iter().any(|pid| {
+		let local = canonical(local);
+		let binding = |ip: IpAddr| {
 			self
-				.udp_map
-				.view(pid.key(), |_, ports| ports.contains(&local_port))
-				.unwrap_or(false)
-		})
+				.udp_index
+				.get(&SocketAddr::new(ip, local.port()))
+				.map(|entry| *entry.value())
+		};
+		let unspecified: IpAddr = match local.ip() {
+			IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+			IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+		};
+		let pid = binding(local.ip())
+			.or_else(|| binding(unspecified))
+			.or_else(|| {
+				binding(Ipv6Addr::UNSPECIFIED.into())
+					.filter(|binding| local.is_ipv4() && binding.dual_stack)
+			})?
+			.pid;
+		self.process_profile(pid).map(|profile| (pid, profile))
 	}
 
-	/// Check if a TCP packet belongs to a tracked process
-	pub fn is_tracked_tcp(&self, src_port: u16, dst_port: u16) -> bool {
-		if src_port == 0 || dst_port == 0 {
-			return false;
+	/// Corrections turning the tracker into what a full query of the system found
+	///
+	/// `processes` maps the PID of every running game process to its profile, `tcps` and
+	/// `udps` hold the TCP connections and UDP endpoints of every process by owning PID.
+	/// Removals come before additions, so that an endpoint moved to another process is
+	/// first taken from its previous owner.
+	pub fn corrections(
+		&self, processes: &HashMap<u32, Arc<str>>, tcps: &HashSet<(u32, TcpConnection)>,
+		udps: &HashSet<(u32, SocketAddr)>,
+	) -> Vec<Correction> {
+		let mut corrections = Vec::new();
+		let tracked = self.processes();
+		for process in &tracked {
+			if !processes.contains_key(&process.pid) {
+				corrections.push(Correction::Untrack { pid: process.pid });
+			}
 		}
-		self.pid_set.iter().any(|pid| {
-			self
-				.tcp_map
-				.view(pid.key(), |_, ports| {
-					ports.contains(&(src_port, dst_port)) || ports.contains(&(dst_port, src_port))
-				})
-				.unwrap_or(false)
-		})
+		let mut missed: Vec<_> = processes
+			.iter()
+			.filter(|(pid, _)| !self.contains_process(**pid))
+			.collect();
+		missed.sort_unstable_by_key(|(pid, _)| **pid);
+		corrections.extend(missed.into_iter().map(|(pid, profile)| Correction::Track {
+			pid: *pid,
+			profile: Arc::clone(profile),
+		}));
+
+		// Closed connections linger with zero ports, which the tracker never holds
+		let tcps: HashSet<(u32, TcpConnection)> = tcps
+			.iter()
+			.filter(|(pid, connection)| {
+				processes.contains_key(pid) && connection.local.port() != 0 && connection.remote.port() != 0
+			})
+			.map(|(pid, connection)| {
+				(
+					*pid,
+					TcpConnection::new(connection.local, connection.remote),
+				)
+			})
+			.collect();
+		let udps: HashSet<(u32, SocketAddr)> = udps
+			.iter()
+			.filter(|(pid, local)| processes.contains_key(pid) && local.port() != 0)
+			.map(|(pid, local)| (*pid, canonical(*local)))
+			.collect();
+
+		let running = tracked
+			.iter()
+			.filter(|process| processes.contains_key(&process.pid));
+		for process in running.clone() {
+			for connection in &process.tcp_connections {
+				if !tcps.contains(&(process.pid, *connection)) {
+					corrections.push(Correction::RemoveTcp {
+						pid: process.pid,
+						connection: *connection,
+					});
+				}
+			}
+			for local in &process.udp_endpoints {
+				if !udps.contains(&(process.pid, *local)) {
+					corrections.push(Correction::RemoveUdp {
+						pid: process.pid,
+						local: *local,
+					});
+				}
+			}
+		}
+		let current: HashMap<_, _> = running.map(|process| (process.pid, process)).collect();
+		let mut tcps: Vec<_> = tcps
+			.into_iter()
+			.filter(|(pid, connection)| {
+				current
+					.get(pid)
+					.is_none_or(|process| !process.tcp_connections.contains(connection))
+			})
+			.collect();
+		tcps.sort_unstable();
+		corrections.extend(
+			tcps
+				.into_iter()
+				.map(|(pid, connection)| Correction::AddTcp { pid, connection }),
+		);
+		let mut udps: Vec<_> = udps
+			.into_iter()
+			.filter(|(pid, local)| {
+				current
+					.get(pid)
+					.is_none_or(|process| !process.udp_endpoints.contains(local))
+			})
+			.collect();
+		udps.sort_unstable();
+		corrections.extend(
+			udps
+				.into_iter()
+				.map(|(pid, local)| Correction::AddUdp { pid, local }),
+		);
+		corrections
+	}
+
+	/// Apply a correction computed by [`ConnectionTracker::corrections`]
+	pub fn apply(&self, correction: &Correction) {
+		match correction {
+			Correction::Untrack { pid } => self.remove_process(*pid),
+			Correction::Track { pid, profile } => self.add_process(*pid, Arc::clone(profile)),
+			Correction::RemoveTcp { pid, connection } => {
+				self.remove_tcp_connection(*pid, connection.local, connection.remote)
+			}
+			Correction::RemoveUdp { pid, local } => self.remove_udp_endpoint(*pid, *local),
+			Correction::AddTcp { pid, connection } => {
+				self.add_tcp_connection(*pid, connection.local, connection.remote)
+			}
+			Correction::AddUdp { pid, local } => self.add_udp_endpoint(*pid, *local),
+		}
+	}
+
+	/// Check if a TCP packet between `local` and `remote` belongs to a tracked process
+	pub fn is_tracked_tcp(&self, local: SocketAddr, remote: SocketAddr) -> bool {
+		self.tcp_profile(local, remote).is_some()
+	}
+
+	/// Profile of the tracked process owning the TCP connection between `local` and `remote`
+	pub fn tcp_profile(&self, local: SocketAddr, remote: SocketAddr) -> Option<Arc<str>> {
+		self.tcp_owner(local, remote).map(|(_, profile)| profile)
+	}
+
+	/// PID and profile of the tracked process owning the TCP connection between `local` and `remote`
+	pub fn tcp_owner(&self, local: SocketAddr, remote: SocketAddr) -> Option<(u32, Arc<str>)> {
+		if local.port() == 0 || remote.port() == 0 {
+			return None;
+		}
+		let pid = *self.tcp_index.get(&TcpConnection::new(local, remote))?;
+		self.process_profile(pid).map(|profile| (pid, profile))
 	}
 }
 
 impl Default for ConnectionTracker {
 	fn default() -> Self { Self::new() }
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const GAME: &str = "GTA V Enhanced";
+
+	fn addr(s: &str) -> SocketAddr { s.parse().unwrap() }
+
+	fn tracker(pids: &[u32]) -> ConnectionTracker {
+		let tracker = ConnectionTracker::new();
+		for pid in pids {
+			tracker.add_process(*pid, Arc::from(GAME));
+		}
+		tracker
+	}
+
+	fn owner(owner: Option<(u32, Arc<str>)>) -> Option<u32> { owner.map(|(pid, _)| pid) }
+
+	/// PID owning the local UDP address `local`
+	fn udp_pid(tracker: &ConnectionTracker, local: &str) -> Option<u32> {
+		owner(tracker.udp_owner(addr(local)))
+	}
+
+	#[test]
+	fn removals_leave_no_stale_index_entries() {
+		let tracker = tracker(&[1, 2]);
+		let (local, remote) = (addr("192.168.1.2:50000"), addr("203.0.113.7:443"));
+		tracker.add_tcp_connection(1, local, remote);
+		tracker.add_udp_endpoint(1, addr("0.0.0.0:6672"));
+		tracker.remove_tcp_connection(1, local, remote);
+		tracker.remove_udp_endpoint(1, addr("0.0.0.0:6672"));
+		assert!(tracker.tcp_index.is_empty());
+		assert!(tracker.udp_index.is_empty());
+		assert_eq!(owner(tracker.tcp_owner(local, remote)), None);
+		assert_eq!(owner(tracker.udp_owner(addr("192.168.1.2:6672"))), None);
+
+		tracker.add_tcp_connection(2, local, remote);
+		tracker.add_udp_endpoint(2, addr("0.0.0.0:6672"));
+		tracker.add_udp_endpoint(2, addr("192.168.1.2:6673"));
+		tracker.remove_process(2);
+		assert!(tracker.tcp_index.is_empty());
+		assert!(tracker.udp_index.is_empty());
+		assert!(!tracker.tcp_map.contains_key(&2));
+		assert!(!tracker.udp_map.contains_key(&2));
+		assert!(!tracker.contains_process(2));
+	}
+
+	#[test]
+	fn removing_another_owner_keeps_the_index() {
+		let tracker = tracker(&[1, 2]);
+		tracker.add_udp_endpoint(1, addr("0.0.0.0:6672"));
+		tracker.remove_udp_endpoint(2, addr("0.0.0.0:6672"));
+		tracker.remove_process(2);
+		assert_eq!(owner(tracker.udp_owner(addr("192.168.1.2:6672"))), Some(1));
+	}
+
+	#[test]
+	fn endpoints_move_to_their_new_owner() {
+		let tracker = tracker(&[1, 2]);
+		let (local, remote) = (addr("192.168.1.2:50000"), addr("203.0.113.7:443"));
+		tracker.add_tcp_connection(1, local, remote);
+		tracker.add_udp_endpoint(1, addr("0.0.0.0:6672"));
+		tracker.add_tcp_connection(2, local, remote);
+		tracker.add_udp_endpoint(2, addr("0.0.0.0:6672"));
+
+		assert_eq!(owner(tracker.tcp_owner(local, remote)), Some(2));
+		assert_eq!(owner(tracker.udp_owner(addr("192.168.1.2:6672"))), Some(2));
+		assert!(tracker.tcp_map.get(&1).unwrap().is_empty());
+		assert!(tracker.udp_map.get(&1).unwrap().is_empty());
+
+		// The previous owner going away must not take the endpoint with it
+		tracker.remove_process(1);
+		assert_eq!(owner(tracker.tcp_owner(local, remote)), Some(2));
+		assert_eq!(owner(tracker.udp_owner(addr("192.168.1.2:6672"))), Some(2));
+	}
+
+	#[test]
+	fn connections_sharing_a_local_port_are_kept_apart() {
+		let tracker = tracker(&[1, 2]);
+		let local = addr("192.168.1.2:50000");
+		let (first, second) = (addr("203.0.113.7:443"), addr("198.51.100.9:443"));
+		tracker.add_tcp_connection(1, local, first);
+		tracker.add_tcp_connection(2, local, second);
+
+		assert_eq!(owner(tracker.tcp_owner(local, first)), Some(1));
+		assert_eq!(owner(tracker.tcp_owner(local, second)), Some(2));
+		assert_eq!(owner(tracker.tcp_owner(local, addr("192.0.2.1:443"))), None);
+
+		tracker.remove_tcp_connection(1, local, first);
+		assert_eq!(owner(tracker.tcp_owner(local, first)), None);
+		assert_eq!(owner(tracker.tcp_owner(local, second)), Some(2));
+		assert_eq!(tracker.tcp_index.len(), 1);
+	}
+
+	#[test]
+	fn udp_owner_prefers_the_exact_address() {
+		let tracker = tracker(&[1, 2, 3]);
+		tracker.add_udp_endpoint(1, addr("192.168.1.2:6672"));
+		tracker.add_udp_endpoint(2, addr("0.0.0.0:6672"));
+		tracker.add_udp_endpoint(3, addr("[::]:6672"));
+
+		assert_eq!(udp_pid(&tracker, "192.168.1.2:6672"), Some(1));
+		assert_eq!(udp_pid(&tracker, "10.0.0.5:6672"), Some(2));
+		assert_eq!(udp_pid(&tracker, "[2001:db8::2]:6672"), Some(3));
+		assert_eq!(udp_pid(&tracker, "192.168.1.2:6673"), None);
+	}
+
+	#[test]
+	fn udp_owner_falls_back_to_the_unspecified_address_of_the_family() {
+		let tracker = tracker(&[1, 2]);
+		tracker.add_udp_endpoint(1, addr("0.0.0.0:6672"));
+		tracker.add_udp_endpoint(2, addr("[::]:6672"));
+
+		assert_eq!(udp_pid(&tracker, "192.168.1.2:6672"), Some(1));
+		assert_eq!(udp_pid(&tracker, "[2001:db8::2]:6672"), Some(2));
+		assert_eq!(udp_pid(&tracker, "[fe80::1]:6672"), Some(2));
+	}
+
+	#[test]
+	fn endpoints_bound_to_the_ipv6_unspecified_address_claim_ipv4_traffic() {
+		let tracker = tracker(&[1, 2]);
+		tracker.add_udp_endpoint(1, addr("[::]:6672"));
+		tracker.apply(&Correction::AddUdp {
+			pid: 2,
+			local: addr("[::]:6673"),
+		});
+
+		assert_eq!(udp_pid(&tracker, "192.168.1.2:6672"), Some(1));
+		assert_eq!(udp_pid(&tracker, "[::ffff:192.168.1.2]:6672"), Some(1));
+		assert_eq!(udp_pid(&tracker, "192.168.1.2:6673"), Some(2));
+		assert_eq!(udp_pid(&tracker, "[::ffff:192.168.1.2]:6673"), Some(2));
+		assert_eq!(udp_pid(&tracker, "[2001:db8::2]:6673"), Some(2));
+	}
+
+	#[test]
+	fn only_dual_stack_endpoints_claim_ipv4_traffic() {
+		let tracker = tracker(&[1]);
+		tracker.insert_udp_endpoint(1, addr("[::]:6672"), false);
+
+		assert_eq!(udp_pid(&tracker, "192.168.1.2:6672"), None);
+		assert_eq!(udp_pid(&tracker, "[::ffff:192.168.1.2]:6672"), None);
+		assert_eq!(udp_pid(&tracker, "[2001:db8::2]:6672"), Some(1));
+
+		tracker.add_udp_endpoint(1, addr("[::]:6672"));
+		assert_eq!(udp_pid(&tracker, "192.168.1.2:6672"), Some(1));
+	}
+
+	#[test]
+	fn ipv4_mapped_addresses_match_plain_ipv4() {
+		let tracker = tracker(&[1]);
+		let (local, remote) = (addr("192.168.1.2:50000"), addr("203.0.113.7:443"));
+		let (mapped_local, mapped_remote) = (
+			addr("[::ffff:192.168.1.2]:50000"),
+			addr("[::ffff:203.0.113.7]:443"),
+		);
+		tracker.add_tcp_connection(1, mapped_local, mapped_remote);
+		tracker.add_udp_endpoint(1, addr("[::ffff:192.168.1.2]:6672"));
+
+		assert_eq!(owner(tracker.tcp_owner(local, remote)), Some(1));
+		assert_eq!(owner(tracker.tcp_owner(mapped_local, remote)), Some(1));
+		assert_eq!(owner(tracker.udp_owner(addr("192.168.1.2:6672"))), Some(1));
+		assert_eq!(
+			owner(tracker.udp_owner(addr("[::ffff:192.168.1.2]:6672"))),
+			Some(1)
+		);
+
+		tracker.remove_tcp_connection(1, local, remote);
+		tracker.remove_udp_endpoint(1, addr("192.168.1.2:6672"));
+		assert!(tracker.tcp_index.is_empty());
+		assert!(tracker.udp_index.is_empty());
+	}
+
+	#[test]
+	fn correct_processes() {
+		let tracker = tracker(&[1, 2]);
+		let processes = HashMap::from([(2, Arc::from(GAME)), (3, Arc::from("rdr2"))]);
+		let corrections = tracker.corrections(&processes, &HashSet::new(), &HashSet::new());
+		assert_eq!(
+			corrections,
+			[
+				Correction::Untrack { pid: 1 },
+				Correction::Track {
+					pid: 3,
+					profile: Arc::from("rdr2")
+				},
+			]
+		);
+
+		for correction in &corrections {
+			tracker.apply(correction);
+		}
+		assert!(!tracker.contains_process(1));
+		assert_eq!(tracker.process_profile(3).as_deref(), Some("rdr2"));
+		assert!(
+			tracker
+				.corrections(&processes, &HashSet::new(), &HashSet::new())
+				.is_empty()
+		);
+	}
+
+	#[test]
+	fn correct_connections() {
+		let tracker = tracker(&[1]);
+		let (local, remote) = (addr("192.168.1.2:50000"), addr("203.0.113.7:443"));
+		let closed = TcpConnection::new(addr("192.168.1.2:50001"), remote);
+		tracker.add_tcp_connection(1, local, remote);
+		tracker.add_tcp_connection(1, closed.local, closed.remote);
+		tracker.add_udp_endpoint(1, addr("0.0.0.0:6672"));
+		tracker.add_udp_endpoint(1, addr("0.0.0.0:6673"));
+
+		let missed = TcpConnection::new(addr("[::ffff:192.168.1.2]:50002"), remote);
+		let processes = HashMap::from([(1, Arc::from(GAME))]);
+		let tcps = HashSet::from([
+			(1, TcpConnection::new(local, remote)),
+			(1, missed),
+			// Closed connections linger with zero ports
+			(1, TcpConnection::new(addr("0.0.0.0:0"), addr("0.0.0.0:0"))),
+			// Connections of processes that are not games are ignored
+			(9, TcpConnection::new(addr("192.168.1.2:50003"), remote)),
+		]);
+		let udps = HashSet::from([
+			(1, addr("0.0.0.0:6672")),
+			(1, addr("[::ffff:192.168.1.2]:6674")),
+			(1, addr("0.0.0.0:0")),
+			(9, addr("0.0.0.0:6675")),
+		]);
+		let corrections = tracker.corrections(&processes, &tcps, &udps);
+		assert_eq!(
+			corrections,
+			[
+				Correction::RemoveTcp {
+					pid: 1,
+					connection: closed
+				},
+				Correction::RemoveUdp {
+					pid: 1,
+					local: addr("0.0.0.0:6673")
+				},
+				Correction::AddTcp {
+					pid: 1,
+					connection: TcpConnection::new(addr("192.168.1.2:50002"), remote)
+				},
+				Correction::AddUdp {
+					pid: 1,
+					local: addr("192.168.1.2:6674")
+				},
+			]
+		);
+
+		for correction in &corrections {
+			tracker.apply(correction);
+		}
+		assert!(tracker.corrections(&processes, &tcps, &udps).is_empty());
+		assert_eq!(owner(tracker.tcp_owner(closed.local, closed.remote)), None);
+		assert_eq!(
+			owner(tracker.tcp_owner(missed.local, missed.remote)),
+			Some(1)
+		);
+		assert_eq!(udp_pid(&tracker, "192.168.1.2:6673"), None);
+		assert_eq!(udp_pid(&tracker, "192.168.1.2:6674"), Some(1));
+	}
+
+	#[test]
+	fn correct_reowned_endpoints() {
+		let tracker = tracker(&[1, 2]);
+		let connection = TcpConnection::new(addr("192.168.1.2:50000"), addr("203.0.113.7:443"));
+		tracker.add_tcp_connection(1, connection.local, connection.remote);
+		tracker.add_udp_endpoint(1, addr("0.0.0.0:6672"));
+
+		let processes = HashMap::from([(1, Arc::from(GAME)), (2, Arc::from(GAME))]);
+		let tcps = HashSet::from([(2, connection)]);
+		let udps = HashSet::from([(2, addr("0.0.0.0:6672"))]);
+		let corrections = tracker.corrections(&processes, &tcps, &udps);
+		assert_eq!(
+			corrections,
+			[
+				Correction::RemoveTcp { pid: 1, connection },
+				Correction::RemoveUdp {
+					pid: 1,
+					local: addr("0.0.0.0:6672")
+				},
+				Correction::AddTcp { pid: 2, connection },
+				Correction::AddUdp {
+					pid: 2,
+					local: addr("0.0.0.0:6672")
+				},
+			]
+		);
+
+		for correction in &corrections {
+			tracker.apply(correction);
+		}
+		assert_eq!(
+			owner(tracker.tcp_owner(connection.local, connection.remote)),
+			Some(2)
+		);
+		assert_eq!(udp_pid(&tracker, "192.168.1.2:6672"), Some(2));
+		assert_eq!(tracker.tcp_index.len(), 1);
+		assert_eq!(tracker.udp_index.len(), 1);
+		assert!(tracker.corrections(&processes, &tcps, &udps).is_empty());
+	}
+}
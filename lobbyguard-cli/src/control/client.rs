@@ -0,0 +1,69 @@
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use super::{Request, Response};
+
+/// Send one request to the control endpoint and wait for its result
+pub async fn call(
+	endpoint: &str, method: &str, params: Value,
+) -> Result<Value, Box<dyn std::error::Error>> {
+	let request = Request::new(1, method, params);
+	let mut line = serde_json::to_string(&request)?;
+	line.push('\n');
+
+	#[cfg(unix)]
+	let stream = tokio::net::UnixStream::connect(endpoint).await?;
+	#[cfg(windows)]
+	let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(endpoint)?;
+
+	let (reader, mut writer) = tokio::io::split(stream);
+	writer.write_all(line.as_bytes()).await?;
+	let mut reply = String::new();
+	BufReader::new(reader).read_line(&mut reply).await?;
+	if reply.is_empty() {
+		return Err("control endpoint closed the connection without replying".into());
+	}
+
+	let response: Response = serde_json::from_str(&reply)?;
+	match (response.result, response.error) {
+		(_, Some(error)) => Err(error.into()),
+		(Some(result), None) => Ok(result),
+		(None, None) => Ok(Value::Null),
+	}
+}
+
+/// Translate the words of a `ctl` command line into a method and its parameters
+///
+/// Recognized commands:
+/// `mode [MODE]`, `allow [add|remove CIDR]`, `tracked`, `stats`, `flows`,
+/// `peers [json|csv]`, `log [LEVEL]`, `recorder`, `dump [PATH]` relative to the daemon's working directory and `call METHOD [JSON]` for any other method.
+pub fn command_request(words: &[String]) -> Result<(String, Value), String> {
+	let words: Vec<&str> = words.iter().map(String::as_str).collect();
+	let (method, params) = match words.as_slice() {
+		["mode"] => ("mode.get", Value::Null),
+		["mode", mode] => ("mode.set", json!({ "mode": mode })),
+		["allow"] | ["allow", "list"] => ("allowlist.list", Value::Null),
+		["allow", "add", cidr] => ("allowlist.add", json!({ "cidr": cidr })),
+		["allow", "remove", cidr] => ("allowlist.remove", json!({ "cidr": cidr })),
+		["tracked"] => ("tracker.list", Value::Null),
+		["stats"] => ("stats.get", Value::Null),
+		["flows"] => ("flows.list", Value::Null),
+		["peers"] => ("peers.list", Value::Null),
+		["peers", format] => ("peers.list", json!({ "format": format })),
+		["log"] => ("log.get", Value::Null),
+		["log", level] => ("log.set", json!({ "level": level })),
+		["recorder"] => ("recorder.get", Value::Null),
+		["dump"] => ("recorder.dump", Value::Null),
+		// Sent as typed: the daemon only writes below its own working directory
+		["dump", path] => ("recorder.dump", json!({ "path": path })),
+		["call", method] => (*method, Value::Null),
+		["call", method, params] => {
+			let params =
+				serde_json::from_str(params).map_err(|e| format!("invalid JSON params: {}", e))?;
+			(*method, params)
+		}
+		[] => return Err("missing command".to_owned()),
+		_ => return Err(format!("unknown command `{}`", words.join(" "))),
+	};
+	Ok((method.to_owned(), params))
+}
@@ -0,0 +1,372 @@
+pub mod client;
+pub mod server;
+
+use std::fmt;
+use std::path::{Component, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use client::{call, command_request};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+pub use server::serve;
+
+use crate::capture::recorder::dump_path;
+use crate::capture::{CaptureFormat, FlightRecorder};
+use crate::cidr::Cidr;
+use crate::classifier::{Mode, Policy};
+use crate::connection_tracker::ConnectionTracker;
+use crate::log_level::LogLevel;
+use crate::peer_table;
+use crate::stats::Stats;
+
+/// Control endpoint used when none is given: a named pipe on Windows, a Unix socket elsewhere
+pub fn default_endpoint() -> String {
+	if cfg!(windows) {
+		r"\\.\pipe\lobbyguard".to_owned()
+	} else {
+		let dir = std::env::var_os("XDG_RUNTIME_DIR")
+			.map(Into::into)
+			.unwrap_or_else(std::env::temp_dir);
+		dir.join("lobbyguard.sock").to_string_lossy().into_owned()
+	}
+}
+
+/// JSON-RPC request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+	pub jsonrpc: String,
+	#[serde(default)]
+	pub id: Value,
+	pub method: String,
+	#[serde(default)]
+	pub params: Value,
+}
+
+impl Request {
+	/// JSON-RPC 2.0 request calling `method`
+	pub fn new(id: u64, method: impl Into<String>, params: Value) -> Self {
+		Self {
+			jsonrpc: "2.0".to_owned(),
+			id: id.into(),
+			method: method.into(),
+			params,
+		}
+	}
+}
+
+/// JSON-RPC response carrying either a result or an error
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+	pub jsonrpc: String,
+	pub id: Value,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub result: Option<Value>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub error: Option<RpcError>,
+}
+
+impl Response {
+	fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+		let (result, error) = match outcome {
+			Ok(result) => (Some(result), None),
+			Err(error) => (None, Some(error)),
+		};
+		Self {
+			jsonrpc: "2.0".to_owned(),
+			id,
+			result,
+			error,
+		}
+	}
+}
+
+/// JSON-RPC error object
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+	pub code: i64,
+	pub message: String,
+}
+
+impl RpcError {
+	pub const INVALID_PARAMS: i64 = -32602;
+	pub const INVALID_REQUEST: i64 = -32600;
+	pub const METHOD_NOT_FOUND: i64 = -32601;
+	pub const PARSE_ERROR: i64 = -32700;
+	/// The method exists but could not be carried out
+	pub const SERVER_ERROR: i64 = -32000;
+
+	fn new(code: i64, message: impl Into<String>) -> Self {
+		Self {
+			code,
+			message: message.into(),
+		}
+	}
+
+	fn invalid_params(message: impl fmt::Display) -> Self {
+		Self::new(Self::INVALID_PARAMS, message.to_string())
+	}
+}
+
+impl fmt::Display for RpcError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} (code {})", self.message, self.code)
+	}
+}
+
+impl std::error::Error for RpcError {}
+
+#[derive(Deserialize)]
+struct ModeParams {
+	mode: String,
+}
+
+#[derive(Deserialize)]
+struct CidrParams {
+	cidr: String,
+}
+
+#[derive(Deserialize)]
+struct LogParams {
+	level: String,
+}
+
+#[derive(Deserialize, Default)]
+struct PeersParams {
+	format: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct DumpParams {
+	/// File to write, relative to the working directory of lobbyguard
+	path: Option<PathBuf>,
+	format: Option<String>,
+}
+
+/// Shared state the control endpoint reads and changes
+///
+/// Requests and responses are JSON-RPC 2.0 objects, one per line.
+pub struct Controller {
+	tracker: Arc<ConnectionTracker>,
+	policy: Arc<Policy>,
+	stats: Arc<Stats>,
+	log_level: LogLevel,
+	recorder: Option<Arc<FlightRecorder>>,
+}
+
+impl Controller {
+	/// Controller acting on the state shared with the packet loop
+	pub fn new(
+		tracker: Arc<ConnectionTracker>, policy: Arc<Policy>, stats: Arc<Stats>, log_level: LogLevel,
+		recorder: Option<Arc<FlightRecorder>>,
+	) -> Self {
+		Self {
+			tracker,
+			policy,
+			stats,
+			log_level,
+			recorder,
+		}
+	}
+
+	/// Answer one line of request JSON with one line of response JSON
+	pub fn handle_line(&self, line: &str) -> String {
+		let response = match serde_json::from_str::<Request>(line) {
+			Ok(request) if request.jsonrpc != "2.0" => Response::new(
+				request.id,
+				Err(RpcError::new(
+					RpcError::INVALID_REQUEST,
+					"`jsonrpc` must be \"2.0\"",
+				)),
+			),
+			Ok(request) => Response::new(request.id, self.handle(&request.method, request.params)),
+			Err(e) => Response::new(
+				Value::Null,
+				Err(RpcError::new(RpcError::PARSE_ERROR, e.to_string())),
+			),
+		};
+		serde_json::to_string(&response).unwrap_or_default()
+	}
+
+	/// Run a method and return its result
+	pub fn handle(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+		match method {
+			"mode.get" => Ok(json!({ "mode": self.policy.mode().as_str() })),
+			"mode.set" => {
+				let params: ModeParams = parse_params(params)?;
+				let mode: Mode = params.mode.parse().map_err(RpcError::invalid_params)?;
+				let previous = self.policy.set_mode(mode);
+				if previous != mode {
+					log::info!("Switched from {} to {} mode", previous, mode);
+				}
+				Ok(json!({ "mode": mode.as_str(), "previous": previous.as_str() }))
+			}
+			"allowlist.list" => Ok(json!({ "allowlist": self.policy.allowlist() })),
+			"allowlist.add" => {
+				let cidr = parse_cidr(params)?;
+				let added = self.policy.allow(cidr);
+				if added {
+					log::info!("Added {} to the allowlist", cidr);
+				}
+				Ok(json!({ "cidr": cidr, "added": added }))
+			}
+			"allowlist.remove" => {
+				let cidr = parse_cidr(params)?;
+				let removed = self.policy.disallow(&cidr);
+				if removed {
+					log::info!("Removed {} from the allowlist", cidr);
+				}
+				Ok(json!({ "cidr": cidr, "removed": removed }))
+			}
+			"tracker.list" => Ok(json!({ "processes": self.tracker.processes() })),
+			"stats.get" => Ok(json!(self.stats.snapshot())),
+			"flows.list" => {
+				let now = SystemTime::now()
+					.duration_since(UNIX_EPOCH)
+					.unwrap_or_default();
+				self.stats.flows.expire(now);
+				Ok(json!({ "flows": self.stats.flows.snapshot() }))
+			}
+			"peers.list" => self.list_peers(params),
+			"log.get" => Ok(json!({ "level": self.log_level.spec() })),
+			"log.set" => {
+				let params: LogParams = parse_params(params)?;
+				let previous = self
+					.log_level
+					.set(&params.level)
+					.map_err(RpcError::invalid_params)?;
+				Ok(json!({ "level": params.level, "previous": previous }))
+			}
+			"recorder.get" => Ok(match &self.recorder {
+				Some(recorder) => json!({
+					"enabled": true,
+					"packets": recorder.len(),
+					"bytes": recorder.bytes(),
+				}),
+				None => json!({ "enabled": false }),
+			}),
+			"recorder.dump" => self.dump_recorder(params),
+			_ => Err(RpcError::new(
+				RpcError::METHOD_NOT_FOUND,
+				format!("unknown method `{}`", method),
+			)),
+		}
+	}
+}
+
+impl Controller {
+	fn list_peers(&self, params: Value) -> Result<Value, RpcError> {
+		let params: PeersParams = if params.is_null() {
+			PeersParams::default()
+		} else {
+			parse_params(params)?
+		};
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default();
+		self.stats.peers.expire(now);
+		let peers = self.stats.peers.snapshot(&self.policy);
+		match params.format.as_deref().unwrap_or("json") {
+			"json" => Ok(json!({ "peers": peers })),
+			"csv" => Ok(json!(peer_table::to_csv(&peers))),
+			other => Err(RpcError::invalid_params(format!(
+				"unknown format `{}`, expected json or csv",
+				other
+			))),
+		}
+	}
+
+	fn dump_recorder(&self, params: Value) -> Result<Value, RpcError> {
+		let Some(recorder) = &self.recorder else {
+			return Err(RpcError::new(
+				RpcError::SERVER_ERROR,
+				"the flight recorder is disabled",
+			));
+		};
+		let params: DumpParams = if params.is_null() {
+			DumpParams::default()
+		} else {
+			parse_params(params)?
+		};
+		// Clients must not make the elevated process write anywhere else
+		if let Some(path) = &params.path
+			&& !path
+				.components()
+				.all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+		{
+			return Err(RpcError::invalid_params(format!(
+				"dump path {:?} must be relative and stay in the working directory",
+				path
+			)));
+		}
+		let format = match (&params.format, &params.path) {
+			(Some(format), _) => format.parse().map_err(RpcError::invalid_params)?,
+			(None, Some(path)) => CaptureFormat::from_path(path),
+			(None, None) => CaptureFormat::Pcapng,
+		};
+		let path = params.path.unwrap_or_else(|| dump_path(format));
+		let packets = recorder
+			.dump(&path, format)
+			.map_err(|e| RpcError::new(RpcError::SERVER_ERROR, format!("dump failed: {}", e)))?;
+		log::info!("Dumped {} recorded packets to {:?}", packets, path);
+		Ok(json!({ "path": path, "packets": packets }))
+	}
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+	serde_json::from_value(params).map_err(RpcError::invalid_params)
+}
+
+fn parse_cidr(params: Value) -> Result<Cidr, RpcError> {
+	let params: CidrParams = parse_params(params)?;
+	params.cidr.parse().map_err(RpcError::invalid_params)
+}
+
+#[cfg(test)]
+mod tests {
+	use pcap_file::TsResolution;
+
+	use super::*;
+
+	fn controller() -> Controller {
+		let recorder = FlightRecorder::new(None, 1024, TsResolution::MicroSecond);
+		Controller::new(
+			Arc::new(ConnectionTracker::new()),
+			Arc::new(Policy::default()),
+			Arc::new(Stats::new()),
+			LogLevel::from_default_env_or("info"),
+			Some(Arc::new(recorder)),
+		)
+	}
+
+	#[test]
+	fn dump_command_writes_to_the_working_directory() {
+		let path = "lobbyguard-ctl-dump-test.pcapng";
+		let words = ["dump", path].map(String::from);
+		let (method, params) = command_request(&words).unwrap();
+		let result = controller().handle(&method, params);
+		let written = std::fs::metadata(path).is_ok();
+		let _ = std::fs::remove_file(path);
+
+		assert_eq!(result.unwrap(), json!({ "path": path, "packets": 0 }));
+		assert!(written);
+	}
+
+	#[test]
+	fn dump_stays_in_the_working_directory() {
+		let controller = controller();
+		let outside = std::env::temp_dir().join("lobbyguard-dump.pcap");
+		for path in [
+			outside.to_str().unwrap(),
+			"../lobbyguard-dump.pcap",
+			"captures/../../lobbyguard-dump.pcap",
+		] {
+			let error = controller
+				.handle("recorder.dump", json!({ "path": path }))
+				.unwrap_err();
+			assert_eq!(error.code, RpcError::INVALID_PARAMS, "{}", path);
+		}
+		assert!(!outside.exists());
+	}
+}
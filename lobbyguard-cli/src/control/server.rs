@@ -0,0 +1,307 @@
+use std::io;
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use super::Controller;
+
+/// Longest request line accepted from a client
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+/// Serve control requests on `endpoint` until an I/O error occurs
+#[cfg(unix)]
+pub async fn serve(endpoint: &str, controller: Arc<Controller>) -> io::Result<()> {
+	use std::os::unix::fs::FileTypeExt;
+
+	use tokio::net::UnixStream;
+
+	match std::fs::symlink_metadata(endpoint) {
+		Ok(metadata) if !metadata.file_type().is_socket() => {
+			return Err(io::Error::new(
+				io::ErrorKind::AlreadyExists,
+				format!("{} exists and is not a socket", endpoint),
+			));
+		}
+		// A socket file left behind by a previous run that nobody listens on
+		Ok(_) if UnixStream::connect(endpoint).await.is_err() => {
+			std::fs::remove_file(endpoint)?;
+			debug!("Removed stale control socket {}", endpoint);
+		}
+		_ => {}
+	}
+	let listener = bind_private(endpoint)?;
+	info!("Control API listening on {}", endpoint);
+
+	loop {
+		let (stream, _) = listener.accept().await?;
+		tokio::spawn(handle_connection(stream, Arc::clone(&controller)));
+	}
+}
+
+/// Bind a socket at `endpoint` that only the owner may connect to, from the moment it exists
+///
+/// The socket is bound in a directory private to the owner, restricted, then linked into place.
+#[cfg(unix)]
+fn bind_private(endpoint: &str) -> io::Result<tokio::net::UnixListener> {
+	use std::fs::{self, DirBuilder, Permissions};
+	use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+	use std::path::Path;
+
+	let endpoint = Path::new(endpoint);
+	let name = endpoint.file_name().unwrap_or_default().to_string_lossy();
+	let dir = endpoint.with_file_name(format!(".{}.{}", name, std::process::id()));
+	DirBuilder::new().mode(0o700).create(&dir)?;
+	let path = dir.join("control.sock");
+	let listener = tokio::net::UnixListener::bind(&path).and_then(|listener| {
+		fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+		// Unlike a rename, a link never replaces the socket of another running instance
+		fs::hard_link(&path, endpoint)?;
+		Ok(listener)
+	});
+	let _ = fs::remove_file(&path);
+	let _ = fs::remove_dir(&dir);
+	listener
+}
+
+/// Serve control requests on `endpoint` until an I/O error occurs
+#[cfg(windows)]
+pub async fn serve(endpoint: &str, controller: Arc<Controller>) -> io::Result<()> {
+	use tokio::net::windows::named_pipe::ServerOptions;
+
+	let mut security = PipeSecurity::new()?;
+	// SAFETY: `security` holds valid attributes until the server stops
+	let mut server = unsafe {
+		ServerOptions::new()
+			.first_pipe_instance(true)
+			.create_with_security_attributes_raw(endpoint, security.attributes())?
+	};
+	info!("Control API listening on {}", endpoint);
+
+	loop {
+		server.connect().await?;
+		let client = server;
+		// SAFETY: as above
+		server = unsafe {
+			ServerOptions::new().create_with_security_attributes_raw(endpoint, security.attributes())?
+		};
+		tokio::spawn(handle_connection(client, Arc::clone(&controller)));
+	}
+}
+
+/// Security attributes letting only SYSTEM, the Administrators and the current user open the pipe
+#[cfg(windows)]
+struct PipeSecurity {
+	descriptor: windows::Win32::Security::PSECURITY_DESCRIPTOR,
+	attributes: windows::Win32::Security::SECURITY_ATTRIBUTES,
+}
+
+#[cfg(windows)]
+impl PipeSecurity {
+	fn new() -> io::Result<Self> {
+		use windows::Win32::Security::Authorization::{
+			ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+		};
+		use windows::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+		use windows::core::HSTRING;
+
+		// Protected DACL granting full access to the three of them and nobody else
+		let sddl = format!(
+			"D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;GA;;;{})",
+			current_user_sid()?
+		);
+		let mut descriptor = PSECURITY_DESCRIPTOR::default();
+		// SAFETY: the descriptor is allocated by the call and freed on drop
+		unsafe {
+			ConvertStringSecurityDescriptorToSecurityDescriptorW(
+				&HSTRING::from(sddl),
+				SDDL_REVISION_1,
+				&mut descriptor,
+				None,
+			)?;
+		}
+		Ok(Self {
+			descriptor,
+			attributes: SECURITY_ATTRIBUTES {
+				nLength: size_of::<SECURITY_ATTRIBUTES>() as u32,
+				lpSecurityDescriptor: descriptor.0,
+				bInheritHandle: false.into(),
+			},
+		})
+	}
+
+	/// Pointer to the attributes, as expected by `CreateNamedPipe`
+	fn attributes(&mut self) -> *mut std::ffi::c_void { (&raw mut self.attributes).cast() }
+}
+
+// SAFETY: the descriptor is owned by the value and never mutated once built
+#[cfg(windows)]
+unsafe impl Send for PipeSecurity {}
+
+#[cfg(windows)]
+impl Drop for PipeSecurity {
+	fn drop(&mut self) {
+		use windows::Win32::Foundation::{HLOCAL, LocalFree};
+
+		// SAFETY: the descriptor was allocated with `LocalAlloc` and is no longer used
+		unsafe { LocalFree(Some(HLOCAL(self.descriptor.0))) };
+	}
+}
+
+/// SID of the user running this process, in its string form
+#[cfg(windows)]
+fn current_user_sid() -> io::Result<String> {
+	use windows::Win32::Foundation::{CloseHandle, HANDLE, HLOCAL, LocalFree};
+	use windows::Win32::Security::Authorization::ConvertSidToStringSidW;
+	use windows::Win32::Security::{GetTokenInformation, TOKEN_QUERY, TOKEN_USER, TokenUser};
+	use windows::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+	use windows::core::PWSTR;
+
+	// SAFETY: the token is closed once queried, the buffer is aligned for and
+	// outlives the `TOKEN_USER` written to it, and the string SID is freed once copied
+	unsafe {
+		let mut token = HANDLE::default();
+		OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token)?;
+		let mut len = 0;
+		// Fails with the size the buffer needs
+		let _ = GetTokenInformation(token, TokenUser, None, 0, &mut len);
+		let mut buffer = vec![0u64; (len as usize).div_ceil(size_of::<u64>())];
+		let result = GetTokenInformation(
+			token,
+			TokenUser,
+			Some(buffer.as_mut_ptr().cast()),
+			len,
+			&mut len,
+		);
+		let _ = CloseHandle(token);
+		result?;
+
+		let user = &*buffer.as_ptr().cast::<TOKEN_USER>();
+		let mut sid = PWSTR::null();
+		ConvertSidToStringSidW(user.User.Sid, &mut sid)?;
+		let string = sid.to_string();
+		LocalFree(Some(HLOCAL(sid.0.cast())));
+		string.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+	}
+}
+
+/// Answer every request line of one client until it disconnects
+async fn handle_connection<S>(stream: S, controller: Arc<Controller>)
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let (reader, mut writer) = tokio::io::split(stream);
+	let mut reader = BufReader::new(reader);
+	let mut line = String::new();
+	loop {
+		line.clear();
+		match (&mut reader)
+			.take(MAX_REQUEST_LEN as u64)
+			.read_line(&mut line)
+			.await
+		{
+			Ok(0) => break,
+			Ok(_) if !line.ends_with('\n') && line.len() >= MAX_REQUEST_LEN => {
+				warn!(
+					"Dropping control client sending a request over {} bytes",
+					MAX_REQUEST_LEN
+				);
+				break;
+			}
+			Ok(_) => {}
+			Err(e) => {
+				debug!("Control client read error: {}", e);
+				break;
+			}
+		}
+		if line.trim().is_empty() {
+			continue;
+		}
+
+		let mut response = controller.handle_line(line.trim());
+		response.push('\n');
+		if let Err(e) = writer.write_all(response.as_bytes()).await {
+			debug!("Control client write error: {}", e);
+			break;
+		}
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use std::os::unix::fs::PermissionsExt;
+	use std::time::Duration;
+
+	use pcap_file::TsResolution;
+	use tokio::net::UnixStream;
+
+	use super::*;
+	use crate::capture::FlightRecorder;
+	use crate::classifier::Policy;
+	use crate::connection_tracker::ConnectionTracker;
+	use crate::log_level::LogLevel;
+	use crate::stats::Stats;
+
+	fn controller() -> Arc<Controller> {
+		let recorder = FlightRecorder::new(None, 1024, TsResolution::MicroSecond);
+		Arc::new(Controller::new(
+			Arc::new(ConnectionTracker::new()),
+			Arc::new(Policy::default()),
+			Arc::new(Stats::new()),
+			LogLevel::from_default_env_or("info"),
+			Some(Arc::new(recorder)),
+		))
+	}
+
+	fn endpoint(name: &str) -> String {
+		let path =
+			std::env::temp_dir().join(format!("lobbyguard-{}-{}.sock", name, std::process::id()));
+		path.to_str().unwrap().to_owned()
+	}
+
+	#[tokio::test]
+	async fn socket_replaces_a_stale_one_and_is_owner_only() {
+		let endpoint = endpoint("server");
+		let _ = std::fs::remove_file(&endpoint);
+		// Bound then dropped, the socket file stays behind with nobody listening
+		drop(std::os::unix::net::UnixListener::bind(&endpoint).unwrap());
+
+		let server = {
+			let endpoint = endpoint.clone();
+			tokio::spawn(async move { serve(&endpoint, controller()).await })
+		};
+		let mut connected = false;
+		for _ in 0..100 {
+			if UnixStream::connect(&endpoint).await.is_ok() {
+				connected = true;
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		let mode = std::fs::metadata(&endpoint).unwrap().permissions().mode();
+		// The private directory the socket was bound in is gone
+		let staging = format!(".lobbyguard-server-{0}.sock.{0}", std::process::id());
+		let staged = std::env::temp_dir().join(staging).exists();
+		let second = serve(&endpoint, controller()).await;
+		server.abort();
+		let _ = std::fs::remove_file(&endpoint);
+
+		assert!(connected);
+		assert_eq!(mode & 0o777, 0o600);
+		assert!(!staged);
+		// A second instance leaves the socket of the first alone
+		assert_eq!(second.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+	}
+
+	#[tokio::test]
+	async fn refuse_to_replace_other_files() {
+		let endpoint = endpoint("not-a-socket");
+		std::fs::write(&endpoint, b"").unwrap();
+		let result = serve(&endpoint, controller()).await;
+		let kept = std::fs::metadata(&endpoint).is_ok();
+		let _ = std::fs::remove_file(&endpoint);
+
+		assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+		assert!(kept);
+	}
+}
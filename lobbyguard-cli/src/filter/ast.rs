@@ -0,0 +1,569 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+/// Protocol predicate of the WinDivert filter language
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Proto {
+	Ip,
+	Ipv6,
+	Icmp,
+	Icmpv6,
+	Tcp,
+	Udp,
+}
+
+impl fmt::Display for Proto {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Proto::Ip => "ip",
+			Proto::Ipv6 => "ipv6",
+			Proto::Icmp => "icmp",
+			Proto::Icmpv6 => "icmpv6",
+			Proto::Tcp => "tcp",
+			Proto::Udp => "udp",
+		})
+	}
+}
+
+/// Transport protocol owning a port or payload field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+	Tcp,
+	Udp,
+}
+
+impl From<Transport> for Proto {
+	fn from(transport: Transport) -> Self {
+		match transport {
+			Transport::Tcp => Proto::Tcp,
+			Transport::Udp => Proto::Udp,
+		}
+	}
+}
+
+/// Field of the IPv4 header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpField {
+	HdrLength,
+	Tos,
+	Length,
+	Id,
+	Df,
+	Mf,
+	FragOff,
+	Ttl,
+	Protocol,
+	Checksum,
+	SrcAddr,
+	DstAddr,
+}
+
+impl IpField {
+	pub const ALL: [IpField; 12] = [
+		IpField::HdrLength,
+		IpField::Tos,
+		IpField::Length,
+		IpField::Id,
+		IpField::Df,
+		IpField::Mf,
+		IpField::FragOff,
+		IpField::Ttl,
+		IpField::Protocol,
+		IpField::Checksum,
+		IpField::SrcAddr,
+		IpField::DstAddr,
+	];
+
+	/// Name of the field after `ip.`
+	pub fn name(&self) -> &'static str {
+		match self {
+			IpField::HdrLength => "HdrLength",
+			IpField::Tos => "TOS",
+			IpField::Length => "Length",
+			IpField::Id => "Id",
+			IpField::Df => "DF",
+			IpField::Mf => "MF",
+			IpField::FragOff => "FragOff",
+			IpField::Ttl => "TTL",
+			IpField::Protocol => "Protocol",
+			IpField::Checksum => "Checksum",
+			IpField::SrcAddr => "SrcAddr",
+			IpField::DstAddr => "DstAddr",
+		}
+	}
+}
+
+/// Field of the IPv6 header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ipv6Field {
+	TrafficClass,
+	FlowLabel,
+	Length,
+	NextHdr,
+	HopLimit,
+	SrcAddr,
+	DstAddr,
+}
+
+impl Ipv6Field {
+	pub const ALL: [Ipv6Field; 7] = [
+		Ipv6Field::TrafficClass,
+		Ipv6Field::FlowLabel,
+		Ipv6Field::Length,
+		Ipv6Field::NextHdr,
+		Ipv6Field::HopLimit,
+		Ipv6Field::SrcAddr,
+		Ipv6Field::DstAddr,
+	];
+
+	/// Name of the field after `ipv6.`
+	pub fn name(&self) -> &'static str {
+		match self {
+			Ipv6Field::TrafficClass => "TrafficClass",
+			Ipv6Field::FlowLabel => "FlowLabel",
+			Ipv6Field::Length => "Length",
+			Ipv6Field::NextHdr => "NextHdr",
+			Ipv6Field::HopLimit => "HopLimit",
+			Ipv6Field::SrcAddr => "SrcAddr",
+			Ipv6Field::DstAddr => "DstAddr",
+		}
+	}
+}
+
+/// Field of the ICMP or ICMPv6 header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IcmpField {
+	Type,
+	Code,
+	Checksum,
+	/// Rest of the header after the checksum
+	Body,
+}
+
+impl IcmpField {
+	pub const ALL: [IcmpField; 4] = [
+		IcmpField::Type,
+		IcmpField::Code,
+		IcmpField::Checksum,
+		IcmpField::Body,
+	];
+
+	/// Name of the field after `icmp.` or `icmpv6.`
+	pub fn name(&self) -> &'static str {
+		match self {
+			IcmpField::Type => "Type",
+			IcmpField::Code => "Code",
+			IcmpField::Checksum => "Checksum",
+			IcmpField::Body => "Body",
+		}
+	}
+}
+
+/// Field of the TCP header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TcpField {
+	SrcPort,
+	DstPort,
+	SeqNum,
+	AckNum,
+	HdrLength,
+	Urg,
+	Ack,
+	Psh,
+	Rst,
+	Syn,
+	Fin,
+	Window,
+	Checksum,
+	UrgPtr,
+	PayloadLength,
+}
+
+impl TcpField {
+	pub const ALL: [TcpField; 15] = [
+		TcpField::SrcPort,
+		TcpField::DstPort,
+		TcpField::SeqNum,
+		TcpField::AckNum,
+		TcpField::HdrLength,
+		TcpField::Urg,
+		TcpField::Ack,
+		TcpField::Psh,
+		TcpField::Rst,
+		TcpField::Syn,
+		TcpField::Fin,
+		TcpField::Window,
+		TcpField::Checksum,
+		TcpField::UrgPtr,
+		TcpField::PayloadLength,
+	];
+
+	/// Name of the field after `tcp.`
+	pub fn name(&self) -> &'static str {
+		match self {
+			TcpField::SrcPort => "SrcPort",
+			TcpField::DstPort => "DstPort",
+			TcpField::SeqNum => "SeqNum",
+			TcpField::AckNum => "AckNum",
+			TcpField::HdrLength => "HdrLength",
+			TcpField::Urg => "Urg",
+			TcpField::Ack => "Ack",
+			TcpField::Psh => "Psh",
+			TcpField::Rst => "Rst",
+			TcpField::Syn => "Syn",
+			TcpField::Fin => "Fin",
+			TcpField::Window => "Window",
+			TcpField::Checksum => "Checksum",
+			TcpField::UrgPtr => "UrgPtr",
+			TcpField::PayloadLength => "PayloadLength",
+		}
+	}
+}
+
+/// Field of the UDP header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UdpField {
+	SrcPort,
+	DstPort,
+	Length,
+	Checksum,
+	PayloadLength,
+}
+
+impl UdpField {
+	pub const ALL: [UdpField; 5] = [
+		UdpField::SrcPort,
+		UdpField::DstPort,
+		UdpField::Length,
+		UdpField::Checksum,
+		UdpField::PayloadLength,
+	];
+
+	/// Name of the field after `udp.`
+	pub fn name(&self) -> &'static str {
+		match self {
+			UdpField::SrcPort => "SrcPort",
+			UdpField::DstPort => "DstPort",
+			UdpField::Length => "Length",
+			UdpField::Checksum => "Checksum",
+			UdpField::PayloadLength => "PayloadLength",
+		}
+	}
+}
+
+/// Packet field of the WinDivert network layer filter language
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Field {
+	Inbound,
+	Outbound,
+	Loopback,
+	Impostor,
+	/// Whether the packet is an IP fragment
+	Fragment,
+	IfIdx,
+	SubIfIdx,
+	/// Length of the whole IP packet
+	Length,
+	Ip(IpField),
+	Ipv6(Ipv6Field),
+	Icmp(IcmpField),
+	Icmpv6(IcmpField),
+	Tcp(TcpField),
+	Udp(UdpField),
+}
+
+impl Field {
+	/// Fields describing the packet as a whole rather than one of its headers
+	pub const PACKET: [Field; 8] = [
+		Field::Inbound,
+		Field::Outbound,
+		Field::Loopback,
+		Field::Impostor,
+		Field::Fragment,
+		Field::IfIdx,
+		Field::SubIfIdx,
+		Field::Length,
+	];
+
+	/// Source port of `transport`
+	pub fn src_port(transport: Transport) -> Field {
+		match transport {
+			Transport::Tcp => Field::Tcp(TcpField::SrcPort),
+			Transport::Udp => Field::Udp(UdpField::SrcPort),
+		}
+	}
+
+	/// Destination port of `transport`
+	pub fn dst_port(transport: Transport) -> Field {
+		match transport {
+			Transport::Tcp => Field::Tcp(TcpField::DstPort),
+			Transport::Udp => Field::Udp(UdpField::DstPort),
+		}
+	}
+
+	/// Payload length of `transport`
+	pub fn payload_length(transport: Transport) -> Field {
+		match transport {
+			Transport::Tcp => Field::Tcp(TcpField::PayloadLength),
+			Transport::Udp => Field::Udp(UdpField::PayloadLength),
+		}
+	}
+
+	/// Whether the field holds an IP address
+	pub fn is_address(&self) -> bool {
+		matches!(
+			self,
+			Field::Ip(IpField::SrcAddr | IpField::DstAddr)
+				| Field::Ipv6(Ipv6Field::SrcAddr | Ipv6Field::DstAddr)
+		)
+	}
+}
+
+impl fmt::Display for Field {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Field::Inbound => f.write_str("inbound"),
+			Field::Outbound => f.write_str("outbound"),
+			Field::Loopback => f.write_str("loopback"),
+			Field::Impostor => f.write_str("impostor"),
+			Field::Fragment => f.write_str("fragment"),
+			Field::IfIdx => f.write_str("ifIdx"),
+			Field::SubIfIdx => f.write_str("subIfIdx"),
+			Field::Length => f.write_str("length"),
+			Field::Ip(field) => write!(f, "{}.{}", Proto::Ip, field.name()),
+			Field::Ipv6(field) => write!(f, "{}.{}", Proto::Ipv6, field.name()),
+			Field::Icmp(field) => write!(f, "{}.{}", Proto::Icmp, field.name()),
+			Field::Icmpv6(field) => write!(f, "{}.{}", Proto::Icmpv6, field.name()),
+			Field::Tcp(field) => write!(f, "{}.{}", Proto::Tcp, field.name()),
+			Field::Udp(field) => write!(f, "{}.{}", Proto::Udp, field.name()),
+		}
+	}
+}
+
+/// Constant a field is compared against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Value {
+	Number(u32),
+	Addr(IpAddr),
+}
+
+impl Value {
+	/// Value as WinDivert compares it, with IPv4 addresses mapped into IPv6
+	pub fn to_u128(&self) -> u128 {
+		match self {
+			Value::Number(number) => (*number).into(),
+			Value::Addr(IpAddr::V4(addr)) => addr.to_ipv6_mapped().to_bits(),
+			Value::Addr(IpAddr::V6(addr)) => addr.to_bits(),
+		}
+	}
+}
+
+impl From<u32> for Value {
+	fn from(number: u32) -> Self { Value::Number(number) }
+}
+
+impl From<IpAddr> for Value {
+	fn from(addr: IpAddr) -> Self { Value::Addr(addr) }
+}
+
+impl fmt::Display for Value {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Value::Number(number) => write!(f, "{}", number),
+			Value::Addr(addr) => write!(f, "{}", addr),
+		}
+	}
+}
+
+/// Comparison operator between a field and a constant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CmpOp {
+	Eq,
+	Ne,
+	Lt,
+	Le,
+	Gt,
+	Ge,
+}
+
+impl CmpOp {
+	/// Apply the operator to a field value and a constant
+	pub fn apply<T: Ord>(&self, lhs: T, rhs: T) -> bool {
+		match self {
+			CmpOp::Eq => lhs == rhs,
+			CmpOp::Ne => lhs != rhs,
+			CmpOp::Lt => lhs < rhs,
+			CmpOp::Le => lhs <= rhs,
+			CmpOp::Gt => lhs > rhs,
+			CmpOp::Ge => lhs >= rhs,
+		}
+	}
+}
+
+impl fmt::Display for CmpOp {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			CmpOp::Eq => "==",
+			CmpOp::Ne => "!=",
+			CmpOp::Lt => "<",
+			CmpOp::Le => "<=",
+			CmpOp::Gt => ">",
+			CmpOp::Ge => ">=",
+		})
+	}
+}
+
+/// Typed syntax tree of a WinDivert filter expression
+///
+/// Rendering with [`fmt::Display`] always produces a well-formed filter string.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Filter {
+	True,
+	False,
+	Protocol(Proto),
+	/// Bare field, true when the packet carries it with a non-zero value
+	Test(Field),
+	Compare(Field, CmpOp, Value),
+	And(Vec<Filter>),
+	Or(Vec<Filter>),
+	Not(Box<Filter>),
+	Ternary(Box<Filter>, Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+	/// Conjunction of all `filters`, flattening nested conjunctions
+	pub fn and(filters: impl IntoIterator<Item = Filter>) -> Filter {
+		let mut terms = Vec::new();
+		for filter in filters {
+			match filter {
+				Filter::And(inner) => terms.extend(inner),
+				Filter::True => {}
+				other => terms.push(other),
+			}
+		}
+		match terms.len() {
+			0 => Filter::True,
+			1 => terms.remove(0),
+			_ => Filter::And(terms),
+		}
+	}
+
+	/// Disjunction of all `filters`, flattening nested disjunctions
+	pub fn or(filters: impl IntoIterator<Item = Filter>) -> Filter {
+		let mut terms = Vec::new();
+		for filter in filters {
+			match filter {
+				Filter::Or(inner) => terms.extend(inner),
+				Filter::False => {}
+				other => terms.push(other),
+			}
+		}
+		match terms.len() {
+			0 => Filter::False,
+			1 => terms.remove(0),
+			_ => Filter::Or(terms),
+		}
+	}
+
+	/// Negation of `filter`
+	pub fn negate(filter: Filter) -> Filter {
+		match filter {
+			Filter::Not(inner) => *inner,
+			Filter::True => Filter::False,
+			Filter::False => Filter::True,
+			other => Filter::Not(Box::new(other)),
+		}
+	}
+
+	/// `condition ? then : otherwise`
+	pub fn ternary(condition: Filter, then: Filter, otherwise: Filter) -> Filter {
+		Filter::Ternary(Box::new(condition), Box::new(then), Box::new(otherwise))
+	}
+
+	/// Comparison of `field` against `value`
+	pub fn cmp(field: Field, op: CmpOp, value: impl Into<Value>) -> Filter {
+		Filter::Compare(field, op, value.into())
+	}
+
+	/// Packets of `transport` whose source or destination port is `port`
+	pub fn port(transport: Transport, port: u16) -> Filter {
+		Filter::or([
+			Filter::cmp(Field::src_port(transport), CmpOp::Eq, u32::from(port)),
+			Filter::cmp(Field::dst_port(transport), CmpOp::Eq, u32::from(port)),
+		])
+	}
+
+	/// Packets of `transport` whose source or destination port lies in `ports`
+	pub fn port_range(transport: Transport, ports: RangeInclusive<u16>) -> Filter {
+		let (start, end) = ports.into_inner();
+		if start == end {
+			return Filter::port(transport, start);
+		}
+		let within = |field| {
+			Filter::and([
+				Filter::cmp(field, CmpOp::Ge, u32::from(start)),
+				Filter::cmp(field, CmpOp::Le, u32::from(end)),
+			])
+		};
+		Filter::or([
+			within(Field::src_port(transport)),
+			within(Field::dst_port(transport)),
+		])
+	}
+
+	/// Packets of `transport` carrying a non-empty payload
+	pub fn has_payload(transport: Transport) -> Filter {
+		Filter::cmp(Field::payload_length(transport), CmpOp::Gt, 0u32)
+	}
+
+	/// Whether the filter needs parentheses when nested in another expression
+	fn is_compound(&self) -> bool {
+		matches!(self, Filter::And(_) | Filter::Or(_) | Filter::Ternary(..))
+	}
+
+	fn fmt_operand(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.is_compound() {
+			write!(f, "({})", self)
+		} else {
+			write!(f, "{}", self)
+		}
+	}
+
+	fn fmt_joined(terms: &[Filter], separator: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (i, term) in terms.iter().enumerate() {
+			if i > 0 {
+				f.write_str(separator)?;
+			}
+			term.fmt_operand(f)?;
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for Filter {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Filter::True => f.write_str("true"),
+			Filter::False => f.write_str("false"),
+			Filter::Protocol(proto) => write!(f, "{}", proto),
+			Filter::Test(field) => write!(f, "{}", field),
+			Filter::Compare(field, op, value) => write!(f, "{} {} {}", field, op, value),
+			Filter::And(terms) if terms.is_empty() => f.write_str("true"),
+			Filter::And(terms) => Filter::fmt_joined(terms, " and ", f),
+			Filter::Or(terms) if terms.is_empty() => f.write_str("false"),
+			Filter::Or(terms) => Filter::fmt_joined(terms, " or ", f),
+			Filter::Not(inner) => {
+				f.write_str("not ")?;
+				inner.fmt_operand(f)
+			}
+			Filter::Ternary(condition, then, otherwise) => {
+				condition.fmt_operand(f)?;
+				f.write_str(" ? ")?;
+				then.fmt_operand(f)?;
+				f.write_str(" : ")?;
+				otherwise.fmt_operand(f)
+			}
+		}
+	}
+}
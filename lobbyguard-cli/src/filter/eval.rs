@@ -0,0 +1,239 @@
+use etherparse::{NetSlice, SlicedPacket, TransportSlice};
+
+use super::ast::{Field, Filter, IcmpField, IpField, Ipv6Field, Proto, TcpField, UdpField};
+use crate::packet_io::PacketMeta;
+
+impl Filter {
+	/// Evaluate the filter against a sliced packet received with `meta` the way WinDivert would.
+	///
+	/// A comparison on a field of a protocol the packet does not carry is false.
+	pub fn matches(&self, packet: &SlicedPacket, meta: &PacketMeta) -> bool {
+		match self {
+			Filter::True => true,
+			Filter::False => false,
+			Filter::Protocol(proto) => has_protocol(*proto, packet),
+			Filter::Test(field) => field_value(*field, packet, meta).is_some_and(|value| value != 0),
+			Filter::Compare(field, op, value) => {
+				field_value(*field, packet, meta).is_some_and(|actual| op.apply(actual, value.to_u128()))
+			}
+			Filter::And(terms) => terms.iter().all(|term| term.matches(packet, meta)),
+			Filter::Or(terms) => terms.iter().any(|term| term.matches(packet, meta)),
+			Filter::Not(inner) => !inner.matches(packet, meta),
+			Filter::Ternary(condition, then, otherwise) => {
+				if condition.matches(packet, meta) {
+					then.matches(packet, meta)
+				} else {
+					otherwise.matches(packet, meta)
+				}
+			}
+		}
+	}
+
+	/// Evaluate the filter against a raw IP packet, treating unparseable data as no match
+	pub fn matches_ip(&self, data: &[u8], meta: &PacketMeta) -> bool {
+		SlicedPacket::from_ip(data).is_ok_and(|packet| self.matches(&packet, meta))
+	}
+}
+
+fn has_protocol(proto: Proto, packet: &SlicedPacket) -> bool {
+	match proto {
+		Proto::Ip => matches!(packet.net, Some(NetSlice::Ipv4(_))),
+		Proto::Ipv6 => matches!(packet.net, Some(NetSlice::Ipv6(_))),
+		Proto::Icmp => matches!(packet.transport, Some(TransportSlice::Icmpv4(_))),
+		Proto::Icmpv6 => matches!(packet.transport, Some(TransportSlice::Icmpv6(_))),
+		Proto::Tcp => matches!(packet.transport, Some(TransportSlice::Tcp(_))),
+		Proto::Udp => matches!(packet.transport, Some(TransportSlice::Udp(_))),
+	}
+}
+
+/// Value of `field` in the packet, with addresses mapped into IPv6 like [`super::Value::to_u128`]
+fn field_value(field: Field, packet: &SlicedPacket, meta: &PacketMeta) -> Option<u128> {
+	let value: u128 = match (field, &packet.net, &packet.transport) {
+		(Field::Inbound, ..) => (!meta.outbound).into(),
+		(Field::Outbound, ..) => meta.outbound.into(),
+		(Field::Loopback, ..) => meta.loopback.into(),
+		(Field::Impostor, ..) => meta.impostor.into(),
+		(Field::IfIdx, ..) => meta.interface_index.into(),
+		(Field::SubIfIdx, ..) => meta.subinterface_index.into(),
+		(Field::Fragment, Some(NetSlice::Ipv4(ip)), _) => ip.is_payload_fragmented().into(),
+		(Field::Fragment, Some(NetSlice::Ipv6(ip)), _) => ip.is_payload_fragmented().into(),
+		(Field::Length, Some(NetSlice::Ipv4(ip)), _) => ip.header().total_len().into(),
+		(Field::Length, Some(NetSlice::Ipv6(ip)), _) => {
+			(40 + u32::from(ip.header().payload_length())).into()
+		}
+		(Field::Ip(field), Some(NetSlice::Ipv4(ip)), _) => {
+			let header = ip.header();
+			match field {
+				IpField::HdrLength => header.ihl().into(),
+				IpField::Tos => header.slice()[1].into(),
+				IpField::Length => header.total_len().into(),
+				IpField::Id => header.identification().into(),
+				IpField::Df => header.dont_fragment().into(),
+				IpField::Mf => header.more_fragments().into(),
+				IpField::FragOff => header.fragments_offset().value().into(),
+				IpField::Ttl => header.ttl().into(),
+				IpField::Protocol => header.protocol().0.into(),
+				IpField::Checksum => header.header_checksum().into(),
+				IpField::SrcAddr => header.source_addr().to_ipv6_mapped().to_bits(),
+				IpField::DstAddr => header.destination_addr().to_ipv6_mapped().to_bits(),
+			}
+		}
+		(Field::Ipv6(field), Some(NetSlice::Ipv6(ip)), _) => {
+			let header = ip.header();
+			match field {
+				Ipv6Field::TrafficClass => header.traffic_class().into(),
+				Ipv6Field::FlowLabel => header.flow_label().value().into(),
+				Ipv6Field::Length => header.payload_length().into(),
+				Ipv6Field::NextHdr => header.next_header().0.into(),
+				Ipv6Field::HopLimit => header.hop_limit().into(),
+				Ipv6Field::SrcAddr => header.source_addr().to_bits(),
+				Ipv6Field::DstAddr => header.destination_addr().to_bits(),
+			}
+		}
+		(Field::Icmp(field), _, Some(TransportSlice::Icmpv4(icmp))) => match field {
+			IcmpField::Type => icmp.type_u8().into(),
+			IcmpField::Code => icmp.code_u8().into(),
+			IcmpField::Checksum => icmp.checksum().into(),
+			IcmpField::Body => u32::from_be_bytes(icmp.bytes5to8()).into(),
+		},
+		(Field::Icmpv6(field), _, Some(TransportSlice::Icmpv6(icmp))) => match field {
+			IcmpField::Type => icmp.type_u8().into(),
+			IcmpField::Code => icmp.code_u8().into(),
+			IcmpField::Checksum => icmp.checksum().into(),
+			IcmpField::Body => u32::from_be_bytes(icmp.bytes5to8()).into(),
+		},
+		(Field::Tcp(field), _, Some(TransportSlice::Tcp(tcp))) => match field {
+			TcpField::SrcPort => tcp.source_port().into(),
+			TcpField::DstPort => tcp.destination_port().into(),
+			TcpField::SeqNum => tcp.sequence_number().into(),
+			TcpField::AckNum => tcp.acknowledgment_number().into(),
+			TcpField::HdrLength => tcp.data_offset().into(),
+			TcpField::Urg => tcp.urg().into(),
+			TcpField::Ack => tcp.ack().into(),
+			TcpField::Psh => tcp.psh().into(),
+			TcpField::Rst => tcp.rst().into(),
+			TcpField::Syn => tcp.syn().into(),
+			TcpField::Fin => tcp.fin().into(),
+			TcpField::Window => tcp.window_size().into(),
+			TcpField::Checksum => tcp.checksum().into(),
+			TcpField::UrgPtr => tcp.urgent_pointer().into(),
+			TcpField::PayloadLength => tcp.payload().len() as u128,
+		},
+		(Field::Udp(field), _, Some(TransportSlice::Udp(udp))) => match field {
+			UdpField::SrcPort => udp.source_port().into(),
+			UdpField::DstPort => udp.destination_port().into(),
+			UdpField::Length => udp.length().into(),
+			UdpField::Checksum => udp.checksum().into(),
+			UdpField::PayloadLength => udp.payload().len() as u128,
+		},
+		_ => return None,
+	};
+	Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+	use etherparse::PacketBuilder;
+
+	use super::*;
+	use crate::filter::parse;
+
+	fn tcp_syn() -> Vec<u8> {
+		let builder = PacketBuilder::ipv4([192, 168, 1, 2], [1, 2, 3, 4], 64)
+			.tcp(50000, 443, 7, 1024)
+			.syn();
+		let mut packet = Vec::with_capacity(builder.size(0));
+		builder.write(&mut packet, &[]).unwrap();
+		packet
+	}
+
+	fn udp_v6(payload: &[u8]) -> Vec<u8> {
+		let builder = PacketBuilder::ipv6(
+			"fe80::1".parse::<std::net::Ipv6Addr>().unwrap().octets(),
+			"fe80::2".parse::<std::net::Ipv6Addr>().unwrap().octets(),
+			32,
+		)
+		.udp(6672, 6672);
+		let mut packet = Vec::with_capacity(builder.size(payload.len()));
+		builder.write(&mut packet, payload).unwrap();
+		packet
+	}
+
+	fn matches(filter: &str, packet: &[u8], meta: &PacketMeta) -> bool {
+		parse(filter).unwrap().matches_ip(packet, meta)
+	}
+
+	#[test]
+	fn metadata_fields() {
+		let outbound = PacketMeta {
+			outbound: true,
+			interface_index: 3,
+			..Default::default()
+		};
+		let packet = tcp_syn();
+		assert!(matches("outbound and ifIdx == 3", &packet, &outbound));
+		assert!(!matches(
+			"inbound or loopback or impostor",
+			&packet,
+			&outbound
+		));
+		assert!(matches("inbound", &packet, &PacketMeta::default()));
+	}
+
+	#[test]
+	fn header_fields() {
+		let meta = PacketMeta::default();
+		let packet = tcp_syn();
+		assert!(matches(
+			"ip.DstAddr == 1.2.3.4 and ip.TTL == 64",
+			&packet,
+			&meta
+		));
+		assert!(matches(
+			"ip.SrcAddr >= 192.168.0.0 and ip.SrcAddr < 192.169.0.0",
+			&packet,
+			&meta
+		));
+		assert!(matches(
+			"tcp.Syn and not tcp.Ack and tcp.SeqNum == 7",
+			&packet,
+			&meta
+		));
+		assert!(matches(
+			"length == 40 and ip.Protocol == 6 and not fragment",
+			&packet,
+			&meta
+		));
+		assert!(!matches(
+			"ipv6 or udp or icmp or ipv6.HopLimit == 64",
+			&packet,
+			&meta
+		));
+
+		let packet = udp_v6(b"session");
+		assert!(matches(
+			"ipv6.SrcAddr == fe80::1 and ipv6.HopLimit == 32",
+			&packet,
+			&meta
+		));
+		assert!(matches(
+			"udp.PayloadLength == 7 and udp.Length == 15",
+			&packet,
+			&meta
+		));
+		assert!(matches(
+			"length == 55 and ipv6.NextHdr == 17",
+			&packet,
+			&meta
+		));
+		assert!(!matches("ip.TTL == 32 or tcp.Syn", &packet, &meta));
+	}
+
+	#[test]
+	fn missing_fields_compare_false() {
+		let meta = PacketMeta::default();
+		let packet = udp_v6(b"");
+		assert!(!matches("tcp.DstPort != 80", &packet, &meta));
+		assert!(matches("not tcp.DstPort == 80", &packet, &meta));
+	}
+}
@@ -0,0 +1,154 @@
+pub mod ast;
+pub mod eval;
+pub mod parser;
+
+use std::ops::RangeInclusive;
+
+pub use ast::{
+	CmpOp, Field, Filter, IcmpField, IpField, Ipv6Field, Proto, TcpField, Transport, UdpField, Value,
+};
+pub use parser::{ParseError, parse};
+
+use crate::config::Config;
+
+/// Build the filter selecting non-empty UDP and TCP packets on the given ports.
+///
+/// A protocol without any port is left out of the filter entirely.
+pub fn traffic_filter(
+	udp_ports: &[RangeInclusive<u16>], tcp_ports: &[RangeInclusive<u16>],
+) -> Filter {
+	let clause = |transport: Transport, ports: &[RangeInclusive<u16>]| {
+		if ports.is_empty() {
+			return Filter::False;
+		}
+		Filter::ternary(
+			Filter::Protocol(transport.into()),
+			Filter::and([
+				Filter::or(
+					ports
+						.iter()
+						.map(|range| Filter::port_range(transport, range.clone())),
+				),
+				Filter::has_payload(transport),
+			]),
+			Filter::False,
+		)
+	};
+
+	Filter::and([
+		Filter::or([
+			clause(Transport::Udp, udp_ports),
+			clause(Transport::Tcp, tcp_ports),
+		]),
+		Filter::or([Filter::Protocol(Proto::Ip), Filter::Protocol(Proto::Ipv6)]),
+	])
+}
+
+/// Build the typed WinDivert filter for network packet capture.
+///
+/// # Arguments
+/// * `config` - Configuration providing the UDP and TCP ports
+/// * `capture_tcp` - Whether to include TCP traffic on the configured TCP ports
+pub fn network_filter(config: &Config, capture_tcp: bool) -> Filter {
+	let tcp_ports = if capture_tcp {
+		config.tcp_port_ranges()
+	} else {
+		Vec::new()
+	};
+	traffic_filter(&config.udp_port_ranges(), &tcp_ports)
+}
+
+/// Build the WinDivert filter string for network packet capture.
+///
+/// # Arguments
+/// * `config` - Configuration providing the UDP and TCP ports
+/// * `capture_tcp` - Whether to include TCP traffic on the configured TCP ports
+///
+/// # Returns
+/// A WinDivert filter string
+pub fn build_network_filter(config: &Config, capture_tcp: bool) -> String {
+	network_filter(config, capture_tcp).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+	use etherparse::PacketBuilder;
+
+	use super::*;
+	use crate::classifier::{GAME_TCP_PORTS, GAME_UDP_PORTS};
+	use crate::packet_io::PacketMeta;
+
+	fn udp_packet(dst_port: u16, payload: &[u8]) -> Vec<u8> {
+		let builder = PacketBuilder::ipv4([192, 168, 1, 2], [203, 0, 113, 7], 64).udp(50000, dst_port);
+		let mut packet = Vec::with_capacity(builder.size(payload.len()));
+		builder.write(&mut packet, payload).unwrap();
+		packet
+	}
+
+	#[test]
+	fn network_filter_structure() {
+		let filter = network_filter(&Config::default(), true);
+		let Filter::And(terms) = &filter else {
+			panic!("expected a conjunction, got {}", filter);
+		};
+		assert_eq!(terms.len(), 2);
+		assert_eq!(
+			terms[1],
+			Filter::Or(vec![
+				Filter::Protocol(Proto::Ip),
+				Filter::Protocol(Proto::Ipv6)
+			])
+		);
+		let Filter::Or(clauses) = &terms[0] else {
+			panic!("expected one clause per protocol, got {}", terms[0]);
+		};
+		let expected = [
+			(Transport::Udp, &GAME_UDP_PORTS),
+			(Transport::Tcp, &GAME_TCP_PORTS),
+		];
+		assert_eq!(clauses.len(), expected.len());
+		for (clause, (transport, ports)) in clauses.iter().zip(expected) {
+			let Filter::Ternary(condition, then, otherwise) = clause else {
+				panic!("expected a ternary, got {}", clause);
+			};
+			assert_eq!(**condition, Filter::Protocol(transport.into()));
+			assert_eq!(**otherwise, Filter::False);
+			let ports = ports
+				.iter()
+				.map(|range| Filter::port_range(transport, range.clone()));
+			assert_eq!(
+				**then,
+				Filter::And(vec![Filter::or(ports), Filter::has_payload(transport)])
+			);
+		}
+	}
+
+	#[test]
+	fn network_filter_without_tcp() {
+		let filter = network_filter(&Config::default(), false);
+		assert_eq!(filter, traffic_filter(&GAME_UDP_PORTS, &[]));
+		assert_eq!(
+			filter.to_string(),
+			"(udp ? ((udp.SrcPort == 6672 or udp.DstPort == 6672 \
+			 or (udp.SrcPort >= 61455 and udp.SrcPort <= 61458) \
+			 or (udp.DstPort >= 61455 and udp.DstPort <= 61458)) and udp.PayloadLength > 0) : false) \
+			 and (ip or ipv6)"
+		);
+	}
+
+	#[test]
+	fn network_filter_selects_game_traffic() {
+		let filter = network_filter(&Config::default(), true);
+		let meta = PacketMeta::default();
+		assert!(filter.matches_ip(&udp_packet(6672, b"session"), &meta));
+		assert!(filter.matches_ip(&udp_packet(61457, b"session"), &meta));
+		assert!(!filter.matches_ip(&udp_packet(6672, b""), &meta));
+		assert!(!filter.matches_ip(&udp_packet(53, b"query"), &meta));
+	}
+
+	#[test]
+	fn traffic_filter_without_ports_matches_nothing() {
+		let filter = traffic_filter(&[], &[]);
+		assert!(!filter.matches_ip(&udp_packet(6672, b"session"), &PacketMeta::default()));
+	}
+}
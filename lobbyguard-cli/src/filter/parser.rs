@@ -0,0 +1,535 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use super::ast::{
+	CmpOp, Field, Filter, IcmpField, IpField, Ipv6Field, Proto, TcpField, UdpField, Value,
+};
+
+/// Error raised for a malformed or unsupported filter string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+	/// Byte offset in the input where the problem was found
+	pub offset: usize,
+	pub message: String,
+}
+
+impl ParseError {
+	fn new(offset: usize, message: impl Into<String>) -> Self {
+		Self {
+			offset,
+			message: message.into(),
+		}
+	}
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"invalid filter at offset {}: {}",
+			self.offset, self.message
+		)
+	}
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+	Ident(String),
+	Number(u32),
+	Addr(IpAddr),
+	Cmp(CmpOp),
+	And,
+	Or,
+	Not,
+	Question,
+	Colon,
+	LParen,
+	RParen,
+}
+
+impl fmt::Display for Token {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Token::Ident(ident) => write!(f, "`{}`", ident),
+			Token::Number(number) => write!(f, "`{}`", number),
+			Token::Addr(addr) => write!(f, "`{}`", addr),
+			Token::Cmp(op) => write!(f, "`{}`", op),
+			Token::And => f.write_str("`and`"),
+			Token::Or => f.write_str("`or`"),
+			Token::Not => f.write_str("`not`"),
+			Token::Question => f.write_str("`?`"),
+			Token::Colon => f.write_str("`:`"),
+			Token::LParen => f.write_str("`(`"),
+			Token::RParen => f.write_str("`)`"),
+		}
+	}
+}
+
+/// Lex the IP address starting at `start` if one follows a comparison operator.
+///
+/// Returns the address and its length, or `None` when the input holds no address there, e.g. a
+/// number followed by the `:` of a ternary.
+fn address(
+	input: &str, start: usize, tokens: &[(usize, Token)],
+) -> Result<Option<(IpAddr, usize)>, ParseError> {
+	if !matches!(tokens.last(), Some((_, Token::Cmp(_)))) {
+		return Ok(None);
+	}
+	let len = input[start..]
+		.find(|c: char| !(c.is_ascii_hexdigit() || c == '.' || c == ':'))
+		.unwrap_or(input.len() - start);
+	let literal = &input[start..start + len];
+	if literal.contains(':') {
+		return Ok(
+			literal
+				.parse::<Ipv6Addr>()
+				.ok()
+				.map(|addr| (IpAddr::V6(addr), len)),
+		);
+	}
+	if !literal.contains('.') {
+		return Ok(None);
+	}
+	match literal.parse::<Ipv4Addr>() {
+		Ok(addr) => Ok(Some((IpAddr::V4(addr), len))),
+		Err(_) => Err(ParseError::new(
+			start,
+			format!("invalid address `{}`", literal),
+		)),
+	}
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+	let bytes = input.as_bytes();
+	let mut tokens = Vec::new();
+	let mut i = 0;
+	while i < bytes.len() {
+		let start = i;
+		let c = bytes[i];
+		let two = bytes.get(i..i + 2);
+		if let Some((addr, len)) = address(input, start, &tokens)? {
+			tokens.push((start, Token::Addr(addr)));
+			i += len;
+			continue;
+		}
+		let (token, len) = match c {
+			_ if c.is_ascii_whitespace() => {
+				i += 1;
+				continue;
+			}
+			b'(' => (Token::LParen, 1),
+			b')' => (Token::RParen, 1),
+			b'?' => (Token::Question, 1),
+			b':' => (Token::Colon, 1),
+			_ if two == Some(b"&&") => (Token::And, 2),
+			_ if two == Some(b"||") => (Token::Or, 2),
+			_ if two == Some(b"==") => (Token::Cmp(CmpOp::Eq), 2),
+			_ if two == Some(b"!=") => (Token::Cmp(CmpOp::Ne), 2),
+			_ if two == Some(b"<=") => (Token::Cmp(CmpOp::Le), 2),
+			_ if two == Some(b">=") => (Token::Cmp(CmpOp::Ge), 2),
+			b'=' => (Token::Cmp(CmpOp::Eq), 1),
+			b'<' => (Token::Cmp(CmpOp::Lt), 1),
+			b'>' => (Token::Cmp(CmpOp::Gt), 1),
+			b'!' => (Token::Not, 1),
+			b'0'..=b'9' => {
+				while i < bytes.len() && bytes[i].is_ascii_alphanumeric() {
+					i += 1;
+				}
+				let literal = &input[start..i];
+				let value = match literal
+					.strip_prefix("0x")
+					.or_else(|| literal.strip_prefix("0X"))
+				{
+					Some(hex) => u32::from_str_radix(hex, 16),
+					None => literal.parse(),
+				}
+				.map_err(|_| ParseError::new(start, format!("invalid number `{}`", literal)))?;
+				tokens.push((start, Token::Number(value)));
+				continue;
+			}
+			_ if c.is_ascii_alphabetic() || c == b'_' => {
+				while i < bytes.len()
+					&& (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' || bytes[i] == b'.')
+				{
+					i += 1;
+				}
+				let word = &input[start..i];
+				let token = match word.to_ascii_lowercase().as_str() {
+					"and" => Token::And,
+					"or" => Token::Or,
+					"not" => Token::Not,
+					_ => Token::Ident(word.to_owned()),
+				};
+				tokens.push((start, token));
+				continue;
+			}
+			_ => {
+				let character = input[start..].chars().next().unwrap_or_default();
+				return Err(ParseError::new(
+					start,
+					format!("unexpected character `{}`", character),
+				));
+			}
+		};
+		i += len;
+		tokens.push((start, token));
+	}
+	Ok(tokens)
+}
+
+/// Look up a field name, ignoring case like WinDivert does
+fn field(name: &str) -> Option<Field> {
+	let Some((protocol, field)) = name.split_once('.') else {
+		return Field::PACKET
+			.into_iter()
+			.find(|field| field.to_string().eq_ignore_ascii_case(name));
+	};
+	let named = |candidate: &str| candidate.eq_ignore_ascii_case(field);
+	match protocol.to_ascii_lowercase().as_str() {
+		"ip" => IpField::ALL
+			.into_iter()
+			.find(|field| named(field.name()))
+			.map(Field::Ip),
+		"ipv6" => Ipv6Field::ALL
+			.into_iter()
+			.find(|field| named(field.name()))
+			.map(Field::Ipv6),
+		"icmp" => IcmpField::ALL
+			.into_iter()
+			.find(|field| named(field.name()))
+			.map(Field::Icmp),
+		"icmpv6" => IcmpField::ALL
+			.into_iter()
+			.find(|field| named(field.name()))
+			.map(Field::Icmpv6),
+		"tcp" => TcpField::ALL
+			.into_iter()
+			.find(|field| named(field.name()))
+			.map(Field::Tcp),
+		"udp" => UdpField::ALL
+			.into_iter()
+			.find(|field| named(field.name()))
+			.map(Field::Udp),
+		_ => None,
+	}
+}
+
+fn protocol(name: &str) -> Option<Proto> {
+	match name.to_ascii_lowercase().as_str() {
+		"ip" => Some(Proto::Ip),
+		"ipv6" => Some(Proto::Ipv6),
+		"icmp" => Some(Proto::Icmp),
+		"icmpv6" => Some(Proto::Icmpv6),
+		"tcp" => Some(Proto::Tcp),
+		"udp" => Some(Proto::Udp),
+		_ => None,
+	}
+}
+
+struct Parser {
+	tokens: Vec<(usize, Token)>,
+	pos: usize,
+	end: usize,
+}
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> { self.tokens.get(self.pos).map(|(_, token)| token) }
+
+	fn offset(&self) -> usize {
+		self
+			.tokens
+			.get(self.pos)
+			.map(|(offset, _)| *offset)
+			.unwrap_or(self.end)
+	}
+
+	fn next(&mut self) -> Option<Token> {
+		let token = self.tokens.get(self.pos).map(|(_, token)| token.clone());
+		self.pos += 1;
+		token
+	}
+
+	fn eat(&mut self, expected: &Token) -> bool {
+		if self.peek() == Some(expected) {
+			self.pos += 1;
+			true
+		} else {
+			false
+		}
+	}
+
+	fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+		if self.eat(&expected) {
+			return Ok(());
+		}
+		Err(self.unexpected(&format!("expected {}", expected)))
+	}
+
+	fn unexpected(&self, context: &str) -> ParseError {
+		match self.peek() {
+			Some(token) => ParseError::new(self.offset(), format!("{}, found {}", context, token)),
+			None => ParseError::new(self.offset(), format!("{}, found end of filter", context)),
+		}
+	}
+
+	fn ternary(&mut self) -> Result<Filter, ParseError> {
+		let condition = self.or()?;
+		if !self.eat(&Token::Question) {
+			return Ok(condition);
+		}
+		let then = self.ternary()?;
+		self.expect(Token::Colon)?;
+		let otherwise = self.ternary()?;
+		Ok(Filter::ternary(condition, then, otherwise))
+	}
+
+	fn or(&mut self) -> Result<Filter, ParseError> {
+		let mut terms = vec![self.and()?];
+		while self.eat(&Token::Or) {
+			terms.push(self.and()?);
+		}
+		Ok(if terms.len() == 1 {
+			terms.remove(0)
+		} else {
+			Filter::Or(terms)
+		})
+	}
+
+	fn and(&mut self) -> Result<Filter, ParseError> {
+		let mut terms = vec![self.unary()?];
+		while self.eat(&Token::And) {
+			terms.push(self.unary()?);
+		}
+		Ok(if terms.len() == 1 {
+			terms.remove(0)
+		} else {
+			Filter::And(terms)
+		})
+	}
+
+	fn unary(&mut self) -> Result<Filter, ParseError> {
+		if self.eat(&Token::Not) {
+			return Ok(Filter::Not(Box::new(self.unary()?)));
+		}
+		self.primary()
+	}
+
+	fn primary(&mut self) -> Result<Filter, ParseError> {
+		let offset = self.offset();
+		match self.next() {
+			Some(Token::LParen) => {
+				let inner = self.ternary()?;
+				self.expect(Token::RParen)?;
+				Ok(inner)
+			}
+			Some(Token::Ident(name)) if name.eq_ignore_ascii_case("true") => Ok(Filter::True),
+			Some(Token::Ident(name)) if name.eq_ignore_ascii_case("false") => Ok(Filter::False),
+			Some(Token::Ident(name)) => {
+				if let Some(proto) = protocol(&name) {
+					return Ok(Filter::Protocol(proto));
+				}
+				let Some(field) = field(&name) else {
+					return Err(ParseError::new(
+						offset,
+						format!("unknown or unsupported field `{}`", name),
+					));
+				};
+				// A bare field tests for a non-zero value
+				let Some(Token::Cmp(op)) = self.peek().cloned() else {
+					return Ok(Filter::Test(field));
+				};
+				self.pos += 1;
+				match self.next() {
+					Some(Token::Number(value)) => Ok(Filter::Compare(field, op, Value::Number(value))),
+					Some(Token::Addr(addr)) if field.is_address() => {
+						Ok(Filter::Compare(field, op, Value::Addr(addr)))
+					}
+					_ => {
+						self.pos -= 1;
+						let expected = if field.is_address() {
+							"expected address or number"
+						} else {
+							"expected number"
+						};
+						Err(self.unexpected(expected))
+					}
+				}
+			}
+			_ => {
+				self.pos -= 1;
+				Err(self.unexpected("expected expression"))
+			}
+		}
+	}
+}
+
+/// Parse a WinDivert filter string into its typed syntax tree.
+///
+/// The network layer grammar is accepted except for payload and packet byte access such as
+/// `tcp.Payload[0]`, and the `timestamp`, `random8`/`random16`/`random32` and `zero` fields.
+/// Addresses are only accepted in comparisons with the `SrcAddr` and `DstAddr` fields.
+pub fn parse(input: &str) -> Result<Filter, ParseError> {
+	let mut parser = Parser {
+		tokens: tokenize(input)?,
+		pos: 0,
+		end: input.len(),
+	};
+	let filter = parser.ternary()?;
+	if parser.peek().is_some() {
+		return Err(parser.unexpected("expected end of filter"));
+	}
+	Ok(filter)
+}
+
+impl FromStr for Filter {
+	type Err = ParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> { parse(s) }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::filter::ast::Transport;
+
+	#[test]
+	fn parse_render_round_trip() {
+		let filters = [
+			crate::filter::traffic_filter(&[6672..=6672, 61455..=61458], &[80..=80]),
+			Filter::and([
+				Filter::Test(Field::Outbound),
+				Filter::Protocol(Proto::Udp),
+				Filter::negate(Filter::Test(Field::Loopback)),
+			]),
+			Filter::or([
+				Filter::cmp(
+					Field::Ip(IpField::DstAddr),
+					CmpOp::Eq,
+					IpAddr::from([1, 2, 3, 4]),
+				),
+				Filter::cmp(
+					Field::Ipv6(Ipv6Field::SrcAddr),
+					CmpOp::Ne,
+					"fe80::1".parse::<IpAddr>().unwrap(),
+				),
+			]),
+			Filter::ternary(
+				Filter::Test(Field::Tcp(TcpField::Syn)),
+				Filter::cmp(Field::Icmpv6(IcmpField::Type), CmpOp::Lt, 128u32),
+				Filter::negate(Filter::Or(vec![
+					Filter::True,
+					Filter::Test(Field::Impostor),
+				])),
+			),
+		];
+		for filter in filters {
+			assert_eq!(parse(&filter.to_string()), Ok(filter));
+		}
+	}
+
+	#[test]
+	fn and_binds_tighter_than_or() {
+		assert_eq!(parse("udp and tcp or ip"), parse("(udp and tcp) or ip"));
+		assert_eq!(
+			parse("udp or tcp and ip"),
+			Ok(Filter::Or(vec![
+				Filter::Protocol(Proto::Udp),
+				Filter::And(vec![
+					Filter::Protocol(Proto::Tcp),
+					Filter::Protocol(Proto::Ip),
+				]),
+			]))
+		);
+		assert_eq!(
+			parse("not udp and tcp"),
+			Ok(Filter::And(vec![
+				Filter::Not(Box::new(Filter::Protocol(Proto::Udp))),
+				Filter::Protocol(Proto::Tcp),
+			]))
+		);
+		assert_eq!(
+			parse("udp or tcp ? ip : ipv6"),
+			Ok(Filter::ternary(
+				Filter::Or(vec![
+					Filter::Protocol(Proto::Udp),
+					Filter::Protocol(Proto::Tcp),
+				]),
+				Filter::Protocol(Proto::Ip),
+				Filter::Protocol(Proto::Ipv6),
+			))
+		);
+	}
+
+	#[test]
+	fn parse_windivert_syntax() {
+		assert_eq!(
+			parse("outbound and udp"),
+			Ok(Filter::And(vec![
+				Filter::Test(Field::Outbound),
+				Filter::Protocol(Proto::Udp),
+			]))
+		);
+		assert_eq!(
+			parse("ip.DstAddr == 1.2.3.4"),
+			Ok(Filter::cmp(
+				Field::Ip(IpField::DstAddr),
+				CmpOp::Eq,
+				IpAddr::from([1, 2, 3, 4]),
+			))
+		);
+		assert_eq!(
+			parse("TCP.SYN && !INBOUND"),
+			Ok(Filter::And(vec![
+				Filter::Test(Field::Tcp(TcpField::Syn)),
+				Filter::Not(Box::new(Filter::Test(Field::Inbound))),
+			]))
+		);
+		assert_eq!(
+			parse("udp.DstPort = 0x1A10"),
+			Ok(Filter::cmp(
+				Field::dst_port(Transport::Udp),
+				CmpOp::Eq,
+				6672u32,
+			))
+		);
+		assert_eq!(
+			parse("ipv6.DstAddr >= ::ffff:10.0.0.1 ? true:false"),
+			Ok(Filter::ternary(
+				Filter::cmp(
+					Field::Ipv6(Ipv6Field::DstAddr),
+					CmpOp::Ge,
+					"::ffff:10.0.0.1".parse::<IpAddr>().unwrap(),
+				),
+				Filter::True,
+				Filter::False,
+			))
+		);
+		assert_eq!(
+			parse("udp ? udp.DstPort == 1:false"),
+			Ok(Filter::ternary(
+				Filter::Protocol(Proto::Udp),
+				Filter::cmp(Field::dst_port(Transport::Udp), CmpOp::Eq, 1u32),
+				Filter::False,
+			))
+		);
+	}
+
+	#[test]
+	fn error_offsets() {
+		let offset = |input: &str| parse(input).unwrap_err().offset;
+		assert_eq!(offset(""), 0);
+		assert_eq!(offset("udp and"), 7);
+		assert_eq!(offset("udp and (tcp"), 12);
+		assert_eq!(offset("udp tcp"), 4);
+		assert_eq!(offset("udp.Bogus == 1"), 0);
+		assert_eq!(offset("tcp and udp.DstPort =="), 22);
+		assert_eq!(offset("tcp.DstPort == 1.2.3.4"), 15);
+		assert_eq!(offset("ip.SrcAddr == 1.2.3"), 14);
+		assert_eq!(offset("udp.DstPort == 99999999999"), 15);
+		assert_eq!(offset("tcp.Payload[0] == 1"), 11);
+		assert_eq!(offset("udp ? tcp"), 9);
+	}
+}
@@ -0,0 +1,284 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use serde::{Serialize, Serializer};
+
+use crate::classifier::{Classification, PacketInfo, Protocol};
+use crate::packet_io::PacketMeta;
+
+/// How long a flow is kept after its last packet when no other timeout is given
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Transport 5-tuple of a flow, seen from this machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct FlowKey {
+	pub protocol: Protocol,
+	pub local: SocketAddr,
+	pub remote: SocketAddr,
+}
+
+impl FlowKey {
+	/// Key of the flow a packet with header fields `info` belongs to
+	pub fn new(info: &PacketInfo, outbound: bool) -> Self {
+		let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+		Self {
+			protocol: info.protocol,
+			local: canonical(info.local(outbound)),
+			remote: canonical(info.remote(outbound)),
+		}
+	}
+}
+
+/// Packets and bytes sent in one direction of a flow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Traffic {
+	pub packets: u64,
+	/// Sum of the IP packet lengths
+	pub bytes: u64,
+}
+
+/// Traffic exchanged between two endpoints
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Flow {
+	#[serde(flatten)]
+	pub key: FlowKey,
+	/// Timestamp of the first packet, as a duration since the UNIX epoch
+	#[serde(serialize_with = "serialize_secs")]
+	pub first_seen: Duration,
+	/// Timestamp of the latest packet, as a duration since the UNIX epoch
+	#[serde(serialize_with = "serialize_secs")]
+	pub last_seen: Duration,
+	/// Traffic leaving this machine
+	pub outbound: Traffic,
+	/// Traffic reaching this machine
+	pub inbound: Traffic,
+	/// Packets with a pass verdict
+	pub passed: u64,
+	/// Packets with a drop verdict, whether or not it was enforced
+	pub blocked: u64,
+	/// PID of the tracked process owning the flow, if any
+	pub pid: Option<u32>,
+	/// Profile of the tracked process owning the flow, if any
+	pub profile: Option<Arc<str>>,
+}
+
+impl Flow {
+	fn new(key: FlowKey, timestamp: Duration) -> Self {
+		Self {
+			key,
+			first_seen: timestamp,
+			last_seen: timestamp,
+			outbound: Traffic::default(),
+			inbound: Traffic::default(),
+			passed: 0,
+			blocked: 0,
+			pid: None,
+			profile: None,
+		}
+	}
+
+	/// Traffic in both directions
+	pub fn total(&self) -> Traffic {
+		Traffic {
+			packets: self.outbound.packets + self.inbound.packets,
+			bytes: self.outbound.bytes + self.inbound.bytes,
+		}
+	}
+}
+
+/// Serialize a timestamp as fractional seconds
+pub(crate) fn serialize_secs<S: Serializer>(timestamp: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+	serializer.serialize_f64(timestamp.as_secs_f64())
+}
+
+/// Flows seen by the packet loop, forgotten once idle
+#[derive(Debug)]
+pub struct FlowTable {
+	/// Forget a flow once this long passed since its latest packet
+	idle_timeout: Duration,
+	flows: DashMap<FlowKey, Flow>,
+}
+
+impl FlowTable {
+	/// Empty table forgetting flows idle for `idle_timeout`
+	pub fn new(idle_timeout: Duration) -> Self {
+		Self {
+			idle_timeout,
+			flows: DashMap::new(),
+		}
+	}
+
+	/// How long a flow is kept after its latest packet
+	pub fn idle_timeout(&self) -> Duration { self.idle_timeout }
+
+	/// Account a packet of `len` bytes with header fields `info` and its classification
+	pub fn record(
+		&self, info: &PacketInfo, meta: &PacketMeta, len: usize, classification: &Classification,
+	) {
+		let key = FlowKey::new(info, meta.outbound);
+		let mut flow = self
+			.flows
+			.entry(key)
+			.or_insert_with(|| Flow::new(key, meta.timestamp));
+		flow.last_seen = flow.last_seen.max(meta.timestamp);
+		let traffic = if meta.outbound {
+			&mut flow.outbound
+		} else {
+			&mut flow.inbound
+		};
+		traffic.packets += 1;
+		traffic.bytes += len as u64;
+		if classification.verdict.is_pass() {
+			flow.passed += 1;
+		} else {
+			flow.blocked += 1;
+		}
+		if let Some(pid) = classification.pid
+			&& flow.pid != Some(pid)
+		{
+			flow.pid = Some(pid);
+			flow.profile = classification.profile.clone();
+		}
+	}
+
+	/// Forget the flows idle at `now`, returning how many were removed
+	pub fn expire(&self, now: Duration) -> usize {
+		let before = self.flows.len();
+		self
+			.flows
+			.retain(|_, flow| flow.last_seen.saturating_add(self.idle_timeout) >= now);
+		before.saturating_sub(self.flows.len())
+	}
+
+	/// Flow with the given key, if it was seen and has not expired
+	pub fn get(&self, key: &FlowKey) -> Option<Flow> {
+		self.flows.get(key).map(|flow| flow.value().clone())
+	}
+
+	/// Number of flows held
+	pub fn len(&self) -> usize { self.flows.len() }
+
+	/// Whether no flow is held
+	pub fn is_empty(&self) -> bool { self.flows.is_empty() }
+
+	/// Copy of every flow held, ordered by key
+	pub fn snapshot(&self) -> Vec<Flow> {
+		let mut flows: Vec<_> = self.flows.iter().map(|flow| flow.value().clone()).collect();
+		flows.sort_unstable_by_key(|flow| flow.key);
+		flows
+	}
+}
+
+impl Default for FlowTable {
+	fn default() -> Self { Self::new(DEFAULT_IDLE_TIMEOUT) }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::classifier::{Reason, Verdict};
+
+	const LOCAL: &str = "192.168.1.2:6672";
+	const REMOTE: &str = "203.0.113.7:6672";
+
+	fn udp(src: &str, dst: &str) -> PacketInfo {
+		PacketInfo {
+			protocol: Protocol::Udp,
+			src: src.parse().unwrap(),
+			dst: dst.parse().unwrap(),
+			payload_len: 191,
+		}
+	}
+
+	fn meta(outbound: bool, secs: u64) -> PacketMeta {
+		PacketMeta {
+			outbound,
+			timestamp: Duration::from_secs(secs),
+			..Default::default()
+		}
+	}
+
+	fn classification(verdict: Verdict, owner: Option<(u32, &str)>) -> Classification {
+		Classification {
+			verdict,
+			pid: owner.map(|(pid, _)| pid),
+			profile: owner.map(|(_, profile)| Arc::from(profile)),
+		}
+	}
+
+	fn key() -> FlowKey {
+		FlowKey {
+			protocol: Protocol::Udp,
+			local: LOCAL.parse().unwrap(),
+			remote: REMOTE.parse().unwrap(),
+		}
+	}
+
+	#[test]
+	fn count_each_direction() {
+		let table = FlowTable::default();
+		let pass = classification(Verdict::Pass(Reason::Heartbeat), None);
+		let drop = classification(Verdict::Drop(Reason::Matchmaking), None);
+		table.record(&udp(LOCAL, REMOTE), &meta(true, 10), 100, &pass);
+		table.record(&udp(LOCAL, REMOTE), &meta(true, 11), 50, &pass);
+		table.record(&udp(REMOTE, LOCAL), &meta(false, 12), 219, &drop);
+
+		assert_eq!(table.len(), 1);
+		let flow = table.get(&key()).unwrap();
+		assert_eq!((flow.outbound.packets, flow.outbound.bytes), (2, 150));
+		assert_eq!((flow.inbound.packets, flow.inbound.bytes), (1, 219));
+		assert_eq!((flow.total().packets, flow.total().bytes), (3, 369));
+		assert_eq!((flow.passed, flow.blocked), (2, 1));
+		assert_eq!(flow.first_seen, Duration::from_secs(10));
+		assert_eq!(flow.last_seen, Duration::from_secs(12));
+	}
+
+	#[test]
+	fn owner_moves_to_the_latest_tracked_process() {
+		let table = FlowTable::default();
+		let info = udp(LOCAL, REMOTE);
+		let pass = Verdict::Pass(Reason::TrackedUdp);
+		table.record(&info, &meta(true, 1), 40, &classification(pass, None));
+		assert_eq!(table.get(&key()).unwrap().pid, None);
+
+		let gta = classification(pass, Some((1, "gta5")));
+		table.record(&info, &meta(true, 2), 40, &gta);
+		table.record(&info, &meta(true, 3), 40, &classification(pass, None));
+		let flow = table.get(&key()).unwrap();
+		assert_eq!((flow.pid, flow.profile.as_deref()), (Some(1), Some("gta5")));
+
+		let rdr = classification(pass, Some((2, "rdr2")));
+		table.record(&info, &meta(true, 4), 40, &rdr);
+		let flow = table.get(&key()).unwrap();
+		assert_eq!((flow.pid, flow.profile.as_deref()), (Some(2), Some("rdr2")));
+	}
+
+	#[test]
+	fn expire_after_the_idle_timeout() {
+		let table = FlowTable::new(Duration::from_secs(60));
+		let pass = classification(Verdict::Pass(Reason::Heartbeat), None);
+		table.record(&udp(LOCAL, REMOTE), &meta(true, 100), 40, &pass);
+
+		// Kept up to and including `last_seen + idle_timeout`
+		let deadline = Duration::from_secs(160);
+		assert_eq!(table.expire(deadline), 0);
+		assert_eq!(table.len(), 1);
+		assert_eq!(table.expire(deadline + Duration::from_nanos(1)), 1);
+		assert!(table.is_empty());
+	}
+
+	#[test]
+	fn ipv4_mapped_addresses_share_the_flow() {
+		let table = FlowTable::default();
+		let pass = classification(Verdict::Pass(Reason::Heartbeat), None);
+		table.record(&udp(LOCAL, REMOTE), &meta(true, 1), 40, &pass);
+		let mapped = udp("[::ffff:203.0.113.7]:6672", "[::ffff:192.168.1.2]:6672");
+		table.record(&mapped, &meta(false, 2), 40, &pass);
+
+		assert_eq!(FlowKey::new(&mapped, false), key());
+		assert_eq!(table.len(), 1);
+		assert_eq!(table.get(&key()).unwrap().total().packets, 2);
+	}
+}
@@ -1,7 +1,19 @@
-#![feature(ip)]
-
+pub mod analyze;
+pub mod capture;
+pub mod cidr;
+pub mod classifier;
+pub mod config;
 pub mod connection_tracker;
+pub mod control;
 pub mod filter;
+pub mod flow_table;
+pub mod local_addr;
+pub mod log_level;
+pub mod packet_io;
 pub mod packet_processor;
+pub mod peer_table;
+pub mod stats;
+#[cfg(all(windows, feature = "wmi"))]
 pub mod wmi;
+#[cfg(all(windows, feature = "wmi"))]
 pub mod wmi_monitor;
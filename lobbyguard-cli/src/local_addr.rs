@@ -0,0 +1,254 @@
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+
+use log::debug;
+
+use crate::classifier::PacketInfo;
+
+/// Public addresses whose route reveals the preferred source address; nothing is sent to them
+const PROBE_ADDRS: [IpAddr; 2] = [
+	IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+	IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+];
+
+/// Whether `addr` cannot be reached from the internet
+///
+/// True for loopback, unspecified, private, shared (CGNAT), link-local and
+/// unique local addresses, and for IPv4 addresses mapped into IPv6.
+pub fn is_private(addr: IpAddr) -> bool {
+	match addr {
+		IpAddr::V4(v4) => {
+			let [a, b, ..] = v4.octets();
+			v4.is_loopback()
+				|| v4.is_unspecified()
+				|| v4.is_private()
+				|| v4.is_link_local()
+				|| v4.is_broadcast()
+				|| (a == 100 && (b & 0xc0) == 64)
+		}
+		IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+			Some(v4) => is_private(IpAddr::V4(v4)),
+			None => {
+				v6.is_loopback()
+					|| v6.is_unspecified()
+					|| v6.is_unique_local()
+					|| v6.is_unicast_link_local()
+			}
+		},
+	}
+}
+
+/// Addresses assigned to this machine's network interfaces
+///
+/// Tells the direction of packets that come without metadata, such as replayed captures.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LocalAddresses {
+	addrs: BTreeSet<IpAddr>,
+}
+
+impl LocalAddresses {
+	/// Empty set, knowing only loopback addresses
+	pub fn new() -> Self { Self::default() }
+
+	/// Addresses of this machine found by the operating system
+	///
+	/// Reads every interface address through WMI when available, and otherwise
+	/// the preferred IPv4 and IPv6 source addresses.
+	pub fn detect() -> Self {
+		let mut local = Self::new();
+		#[cfg(all(windows, feature = "wmi"))]
+		match crate::wmi_monitor::query_local_addresses() {
+			Ok(addrs) => local.extend(addrs),
+			Err(e) => log::warn!("Failed to query interface addresses from WMI: {}", e),
+		}
+		for probe in PROBE_ADDRS {
+			let bind: IpAddr = match probe {
+				IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+				IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+			};
+			// Connecting a UDP socket only picks a route, no packet is sent
+			let source = UdpSocket::bind((bind, 0))
+				.and_then(|socket| socket.connect((probe, 9)).map(|()| socket))
+				.and_then(|socket| socket.local_addr());
+			match source {
+				Ok(source) => {
+					local.insert(source.ip());
+				}
+				Err(e) => debug!("No route to {}: {}", probe, e),
+			}
+		}
+		local
+	}
+
+	/// Add an address of this machine, returning whether it was new
+	pub fn insert(&mut self, addr: IpAddr) -> bool { self.addrs.insert(addr.to_canonical()) }
+
+	/// Whether `addr` belongs to this machine
+	pub fn contains(&self, addr: IpAddr) -> bool {
+		let addr = addr.to_canonical();
+		addr.is_loopback() || self.addrs.contains(&addr)
+	}
+
+	/// Addresses known besides loopback, in order
+	pub fn iter(&self) -> impl Iterator<Item = IpAddr> + '_ { self.addrs.iter().copied() }
+
+	/// Whether the packet leaves this machine, or `None` if its addresses do not tell
+	///
+	/// Known local addresses decide first; otherwise a private address facing a
+	/// public one is taken to be this machine's side.
+	pub fn is_outbound(&self, info: &PacketInfo) -> Option<bool> {
+		let (src, dst) = (info.src.ip(), info.dst.ip());
+		match (self.contains(src), self.contains(dst)) {
+			(true, false) => return Some(true),
+			(false, true) => return Some(false),
+			_ => {}
+		}
+		match (is_private(src), is_private(dst)) {
+			(true, false) => Some(true),
+			(false, true) => Some(false),
+			_ => None,
+		}
+	}
+}
+
+impl Extend<IpAddr> for LocalAddresses {
+	fn extend<I: IntoIterator<Item = IpAddr>>(&mut self, addrs: I) {
+		for addr in addrs {
+			self.insert(addr);
+		}
+	}
+}
+
+impl FromIterator<IpAddr> for LocalAddresses {
+	fn from_iter<I: IntoIterator<Item = IpAddr>>(addrs: I) -> Self {
+		let mut local = Self::new();
+		local.extend(addrs);
+		local
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use super::*;
+	use crate::classifier::Protocol;
+
+	fn ip(s: &str) -> IpAddr { s.parse().unwrap() }
+
+	fn udp(src: &str, dst: &str) -> PacketInfo {
+		PacketInfo {
+			protocol: Protocol::Udp,
+			src: SocketAddr::new(ip(src), 6672),
+			dst: SocketAddr::new(ip(dst), 6672),
+			payload_len: 0,
+		}
+	}
+
+	#[test]
+	fn private_addresses() {
+		let cases = [
+			("127.0.0.1", true),
+			("0.0.0.0", true),
+			("10.1.2.3", true),
+			("172.16.0.1", true),
+			("172.32.0.1", false),
+			("192.168.1.2", true),
+			("169.254.10.1", true),
+			("100.64.0.1", true),
+			("100.127.255.254", true),
+			("100.128.0.1", false),
+			("255.255.255.255", true),
+			("203.0.113.7", false),
+			("8.8.8.8", false),
+			("::1", true),
+			("::", true),
+			("fd00::1", true),
+			("fe80::1", true),
+			("2001:db8::1", false),
+			("2606:4700::1111", false),
+			("::ffff:192.168.1.2", true),
+			("::ffff:169.254.0.1", true),
+			("::ffff:203.0.113.7", false),
+		];
+		for (addr, private) in cases {
+			assert_eq!(is_private(ip(addr)), private, "{}", addr);
+		}
+	}
+
+	#[test]
+	fn contains_loopback_and_canonical_addresses() {
+		let local: LocalAddresses = [ip("::ffff:198.51.100.4"), ip("2001:db8::2")]
+			.into_iter()
+			.collect();
+		assert!(local.contains(ip("127.0.0.1")));
+		assert!(local.contains(ip("::1")));
+		assert!(local.contains(ip("198.51.100.4")));
+		assert!(local.contains(ip("::ffff:198.51.100.4")));
+		assert!(local.contains(ip("2001:db8::2")));
+		assert!(!local.contains(ip("2001:db8::3")));
+		assert_eq!(
+			local.iter().collect::<Vec<_>>(),
+			[ip("198.51.100.4"), ip("2001:db8::2")]
+		);
+	}
+
+	#[test]
+	fn known_local_addresses_decide_the_direction() {
+		// Public addresses, which only the override tells apart
+		let local: LocalAddresses = [ip("198.51.100.4"), ip("2001:db8::2")]
+			.into_iter()
+			.collect();
+		let cases = [
+			("198.51.100.4", "203.0.113.7", Some(true)),
+			("203.0.113.7", "198.51.100.4", Some(false)),
+			("2001:db8::2", "2001:db8::9", Some(true)),
+			("2001:db8::9", "2001:db8::2", Some(false)),
+			("::ffff:198.51.100.4", "::ffff:203.0.113.7", Some(true)),
+			("::ffff:203.0.113.7", "::ffff:198.51.100.4", Some(false)),
+			// A known address wins over the private address heuristic
+			("203.0.113.7", "::ffff:198.51.100.4", Some(false)),
+			("198.51.100.4", "192.168.1.2", Some(true)),
+		];
+		for (src, dst, outbound) in cases {
+			assert_eq!(
+				local.is_outbound(&udp(src, dst)),
+				outbound,
+				"{} -> {}",
+				src,
+				dst
+			);
+		}
+		assert_eq!(
+			LocalAddresses::new().is_outbound(&udp("198.51.100.4", "203.0.113.7")),
+			None
+		);
+	}
+
+	#[test]
+	fn private_addresses_decide_when_local_addresses_do_not() {
+		let local: LocalAddresses = [ip("192.168.1.2")].into_iter().collect();
+		let cases = [
+			// Neither end is known to be local
+			("10.0.0.5", "203.0.113.7", Some(true)),
+			("203.0.113.7", "10.0.0.5", Some(false)),
+			("fe80::1", "2001:db8::9", Some(true)),
+			("2001:db8::9", "fd00::1", Some(false)),
+			("::ffff:10.0.0.5", "203.0.113.7", Some(true)),
+			("10.0.0.5", "10.0.0.6", None),
+			("203.0.113.7", "198.51.100.9", None),
+			// Both ends are local
+			("192.168.1.2", "127.0.0.1", None),
+			("::1", "::ffff:192.168.1.2", None),
+		];
+		for (src, dst, outbound) in cases {
+			assert_eq!(
+				local.is_outbound(&udp(src, dst)),
+				outbound,
+				"{} -> {}",
+				src,
+				dst
+			);
+		}
+	}
+}
@@ -0,0 +1,55 @@
+use std::sync::{Arc, PoisonError, RwLock};
+
+use logforth::Diagnostic;
+use logforth::filter::env_filter::{EnvFilter, EnvFilterBuilder};
+use logforth::filter::{Filter, FilterResult};
+use logforth::record::FilterCriteria;
+
+/// Console log filter that can be replaced while running
+///
+/// Clones share the same filter, so one can be handed to the logger and
+/// another to the control API.
+#[derive(Debug, Clone)]
+pub struct LogLevel {
+	inner: Arc<RwLock<(String, EnvFilter)>>,
+}
+
+impl LogLevel {
+	/// Filter from the `RUST_LOG` environment variable, or `default` if unset
+	pub fn from_default_env_or(default: &str) -> Self {
+		let spec = std::env::var("RUST_LOG").unwrap_or_else(|_| default.to_owned());
+		let filter = EnvFilterBuilder::from_default_env_or(default).build();
+		Self {
+			inner: Arc::new(RwLock::new((spec, filter))),
+		}
+	}
+
+	/// Directives of the current filter, e.g. `info,lobbyguard_cli=debug`
+	pub fn spec(&self) -> String {
+		self
+			.inner
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.0
+			.clone()
+	}
+
+	/// Replace the filter with the directives of `spec`, returning the previous ones
+	pub fn set(&self, spec: &str) -> Result<String, logforth::Error> {
+		let filter = EnvFilterBuilder::try_from_spec(spec)?.build();
+		let mut inner = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+		let previous = std::mem::replace(&mut *inner, (spec.to_owned(), filter));
+		Ok(previous.0)
+	}
+}
+
+impl Filter for LogLevel {
+	fn enabled(&self, criteria: &FilterCriteria, diags: &[Box<dyn Diagnostic>]) -> FilterResult {
+		self
+			.inner
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.1
+			.enabled(criteria, diags)
+	}
+}
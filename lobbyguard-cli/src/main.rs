@@ -1,76 +1,540 @@
-#![feature(ip)]
-
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::time::Duration;
 
 use argh::FromArgs;
-use fastrace::collector::{Config, ConsoleReporter};
-use log::debug;
+use fastrace::collector::{self, ConsoleReporter};
 use logforth::append;
-use logforth::filter::env_filter::EnvFilterBuilder;
-use windivert::prelude::*;
-
-use lobbyguard_cli::connection_tracker::ConnectionTracker;
-use lobbyguard_cli::filter::build_network_filter;
-use lobbyguard_cli::packet_processor::process_packets;
-use lobbyguard_cli::wmi_monitor::{initialize_wmi, run_wmi_monitor};
+use lobbyguard_cli::capture::recorder::DEFAULT_MAX_BYTES;
+use lobbyguard_cli::capture::rotate::{parse_duration, parse_size};
+use lobbyguard_cli::capture::selector::{VerdictSelection, parse_size_range};
+use lobbyguard_cli::capture::{
+	CaptureFormat, CaptureSelector, Compression, FlightRecorder, Rotation,
+};
+use lobbyguard_cli::cidr::Cidr;
+use lobbyguard_cli::classifier::Mode;
+use lobbyguard_cli::config::Config;
+use lobbyguard_cli::control;
+use lobbyguard_cli::filter::ast::Filter;
+use lobbyguard_cli::log_level::LogLevel;
+use lobbyguard_cli::packet_processor::{
+	MAX_BUFFER_SIZE, MAX_SNAPLEN, parse_buffer_size, parse_snaplen,
+};
+use pcap_file::TsResolution;
 
 #[derive(FromArgs)]
 /// Block the GTA connections you don't want.
+#[cfg_attr(
+	not(all(windows, feature = "windivert", feature = "wmi")),
+	allow(dead_code)
+)]
 struct Lobbyguard {
+	/// path to a TOML configuration file overriding the built-in defaults
+	#[argh(option, short = 'c')]
+	config: Option<PathBuf>,
+
 	/// optional path to output captured traffic
 	#[argh(option, short = 'f')]
 	file: Option<PathBuf>,
 
-	/// whether to capture TCP traffic (ports 80 and 443)
+	/// capture file format: pcap or pcapng (default: from the extension of --file)
+	#[argh(option)]
+	capture_format: Option<CaptureFormat>,
+
+	/// compress the capture as it is written: none, gzip or zstd
+	#[argh(option, default = "Compression::None")]
+	compress: Compression,
+
+	/// start a new numbered capture file after this much packet data, e.g. 100M
+	#[argh(option, from_str_fn(parse_size))]
+	rotate_size: Option<u64>,
+
+	/// start a new numbered capture file after this long, e.g. 30m
+	#[argh(option, from_str_fn(parse_duration))]
+	rotate_interval: Option<Duration>,
+
+	/// number of rotated capture files to keep, deleting the oldest
+	#[argh(option)]
+	max_files: Option<usize>,
+
+	/// write capture timestamps with nanosecond instead of microsecond resolution
+	#[argh(switch)]
+	nanosecond_timestamps: bool,
+
+	/// capture only packets with these verdicts: all, blocked or passed
+	#[argh(option, default = "VerdictSelection::All")]
+	capture_verdict: VerdictSelection,
+
+	/// capture only packets from or to this address or network, repeatable
+	#[argh(option)]
+	capture_peer: Vec<Cidr>,
+
+	/// capture only packets whose IP length lies in this range, e.g. 100-1200
+	#[argh(option, from_str_fn(parse_size_range))]
+	capture_size: Option<RangeInclusive<usize>>,
+
+	/// capture only packets matching this WinDivert filter, e.g. "tcp or udp.DstPort == 6672"
+	#[argh(option)]
+	capture_filter: Option<Filter>,
+
+	/// keep up to this much recent tracked traffic in memory for `ctl dump` or Ctrl-Break, e.g. 32M
+	#[argh(option, from_str_fn(parse_size))]
+	recorder_size: Option<u64>,
+
+	/// keep this long of recent tracked traffic in memory for `ctl dump` or Ctrl-Break, e.g. 2m
+	#[argh(option, from_str_fn(parse_duration))]
+	recorder_window: Option<Duration>,
+
+	/// whether to capture TCP traffic (ports 80 and 443 unless configured)
 	#[argh(option, default = "true")]
 	capture_tcp: bool,
+
+	/// custom WinDivert filter replacing the generated one, without payload byte access or the timestamp, random and zero fields
+	#[argh(option)]
+	filter: Option<String>,
+
+	/// filtering mode at startup: open, solo, friends-only or lockdown
+	#[argh(option, short = 'm')]
+	mode: Option<Mode>,
+
+	/// compute verdicts but re-inject every packet, logging those that would be dropped
+	#[argh(switch)]
+	dry_run: bool,
+
+	/// only observe copies of the traffic, never delaying or dropping a packet
+	#[argh(switch)]
+	sniff: bool,
+
+	/// forget a flow or peer after this long without packets, e.g. 5m (default: 2m)
+	#[argh(option, from_str_fn(parse_duration))]
+	flow_timeout: Option<Duration>,
+
+	/// re-query WMI this often to repair tracked processes and connections whose events were missed
+	#[argh(option, default = "Duration::from_secs(60)", from_str_fn(parse_duration))]
+	reconcile_interval: Duration,
+
+	/// bytes received per packet with --sniff, 1280 to 65535; longer packets are let through unclassified
+	#[argh(option, default = "MAX_BUFFER_SIZE", from_str_fn(parse_buffer_size))]
+	buffer_size: usize,
+
+	/// bytes of each packet kept in captures and the flight recorder, 64 to 65535
+	#[argh(option, default = "MAX_SNAPLEN", from_str_fn(parse_snaplen))]
+	snaplen: usize,
+
+	/// control API endpoint (default: \\.\pipe\lobbyguard on Windows, lobbyguard.sock in the runtime dir elsewhere)
+	#[argh(option)]
+	control: Option<String>,
+
+	#[argh(subcommand)]
+	command: Option<Command>,
+}
+
+#[cfg_attr(
+	not(all(windows, feature = "windivert", feature = "wmi")),
+	allow(dead_code)
+)]
+impl Lobbyguard {
+	/// Capture rotation requested on the command line
+	fn rotation(&self) -> Rotation {
+		Rotation {
+			max_bytes: self.rotate_size,
+			max_age: self.rotate_interval,
+			max_files: self.max_files,
+		}
+	}
+
+	/// Resolution of the timestamps written to captures
+	fn ts_resolution(&self) -> TsResolution {
+		if self.nanosecond_timestamps {
+			TsResolution::NanoSecond
+		} else {
+			TsResolution::MicroSecond
+		}
+	}
+
+	/// Capture selection requested on the command line
+	fn selector(&self) -> CaptureSelector {
+		CaptureSelector::new(
+			self.capture_verdict,
+			self.capture_peer.iter().copied(),
+			self.capture_size.clone(),
+			self.capture_filter.clone(),
+		)
+	}
+
+	/// Flight recorder requested on the command line, if any
+	fn recorder(&self) -> Option<FlightRecorder> {
+		if self.recorder_size.is_none() && self.recorder_window.is_none() {
+			return None;
+		}
+		let max_bytes = self
+			.recorder_size
+			.map_or(DEFAULT_MAX_BYTES, |size| usize::try_from(size).unwrap_or(usize::MAX));
+		Some(FlightRecorder::new(
+			self.recorder_window,
+			max_bytes,
+			self.ts_resolution(),
+		))
+	}
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+	Ctl(Ctl),
+	Analyze(Analyze),
+}
+
+#[derive(FromArgs)]
+/// Control a running lobbyguard: mode [MODE], allow [list|add CIDR|remove CIDR], tracked, stats, flows, peers [json|csv], log [LEVEL], recorder, dump [PATH], call METHOD [JSON].
+#[argh(subcommand, name = "ctl")]
+struct Ctl {
+	/// command and its arguments
+	#[argh(positional)]
+	command: Vec<String>,
+}
+
+#[derive(FromArgs)]
+/// Replay a pcap or pcapng capture through the classifier and report what would have been blocked.
+#[argh(subcommand, name = "analyze")]
+struct Analyze {
+	/// pcap or pcapng file to replay, decompressed if it ends in .gz or .zst
+	#[argh(positional)]
+	capture: PathBuf,
+
+	/// local UDP port owned by the game process, repeatable (default: the profile's game port)
+	#[argh(option)]
+	tracked_port: Vec<u16>,
+
+	/// game profile owning the tracked ports (default: the first active profile)
+	#[argh(option)]
+	profile: Option<String>,
+
+	/// address of the machine the capture was taken on, repeatable (default: this machine's)
+	#[argh(option)]
+	local_addr: Vec<IpAddr>,
 }
 
 #[tokio::main]
 async fn main() {
-	fastrace::set_reporter(ConsoleReporter, Config::default());
+	fastrace::set_reporter(ConsoleReporter, collector::Config::default());
+	let log_level = LogLevel::from_default_env_or("info");
+	let stdout_filter = log_level.clone();
 	logforth::starter_log::builder()
-		.dispatch(|d| {
-			d.filter(EnvFilterBuilder::from_default_env_or("info").build())
-				.append(append::Stdout::default())
-		})
+		.dispatch(|d| d.filter(stdout_filter).append(append::Stdout::default()))
 		.dispatch(|d| d.append(append::FastraceEvent::default()))
 		.apply();
 
 	let args: Lobbyguard = argh::from_env();
+	let endpoint = args.control.clone().unwrap_or_else(control::default_endpoint);
+
+	if let Some(Command::Ctl(ctl)) = &args.command {
+		let ok = run_ctl(&endpoint, ctl).await;
+		fastrace::flush();
+		if !ok {
+			std::process::exit(1);
+		}
+		return;
+	}
+
+	let mut config = match &args.config {
+		Some(path) => match Config::load(path) {
+			Ok(config) => config,
+			Err(e) => {
+				log::error!("{}", e);
+				fastrace::flush();
+				std::process::exit(1);
+			}
+		},
+		None => Config::default(),
+	};
+	if let Some(mode) = args.mode {
+		config.mode = mode;
+	}
+	if config.ignores_allowlist() {
+		log::warn!(
+			"The allowlist is ignored in {} mode, use friends-only mode to let listed peers through",
+			config.mode
+		);
+	}
+
+	// Reject malformed filters before touching WMI or the driver
+	let net_filter = match &args.filter {
+		Some(filter) => match lobbyguard_cli::filter::parse(filter) {
+			Ok(filter) => filter.to_string(),
+			Err(e) => {
+				log::error!("{}", e);
+				fastrace::flush();
+				std::process::exit(1);
+			}
+		},
+		None => lobbyguard_cli::filter::build_network_filter(&config, args.capture_tcp),
+	};
+
+	if let Some(Command::Analyze(analyze)) = &args.command {
+		let ok = run_analyze(analyze, &config);
+		fastrace::flush();
+		if !ok {
+			std::process::exit(1);
+		}
+		return;
+	}
+
+	if args.dry_run && args.sniff {
+		log::error!("`--dry-run` and `--sniff` cannot be combined");
+		fastrace::flush();
+		std::process::exit(1);
+	}
+
+	run_live(args, config, net_filter, endpoint, log_level).await;
+	fastrace::flush();
+}
+
+/// Classify every packet of a capture file and print the report
+fn run_analyze(analyze: &Analyze, config: &Config) -> bool {
+	use lobbyguard_cli::analyze::{self, offline_tracker};
+	use lobbyguard_cli::local_addr::LocalAddresses;
+	use lobbyguard_cli::packet_io::PcapReplaySource;
+
+	let profile_name = match &analyze.profile {
+		Some(name) => name.as_str(),
+		None => config.active_profiles.first().map(String::as_str).unwrap_or_default(),
+	};
+	let Some(profile) = config.profiles.get(profile_name) else {
+		log::error!("Unknown profile `{}`", profile_name);
+		return false;
+	};
+	if !config.active_profiles.iter().any(|name| name == profile_name) {
+		log::error!("Profile `{}` is not active", profile_name);
+		return false;
+	}
+	let tracked_ports = if analyze.tracked_port.is_empty() {
+		vec![profile.game_port]
+	} else {
+		analyze.tracked_port.clone()
+	};
+	log::info!(
+		"Analyzing {:?} in {} mode with UDP ports {:?} tracked as `{}`",
+		analyze.capture,
+		config.mode,
+		tracked_ports,
+		profile_name
+	);
+
+	let source = match PcapReplaySource::open(&analyze.capture) {
+		Ok(source) => source,
+		Err(e) => {
+			log::error!("Failed to open capture {:?}: {}", analyze.capture, e);
+			return false;
+		}
+	};
+	let tracker = offline_tracker(profile_name, &tracked_ports);
+	let local = if analyze.local_addr.is_empty() {
+		LocalAddresses::detect()
+	} else {
+		analyze.local_addr.iter().copied().collect()
+	};
+	log::debug!(
+		"Local addresses: {:?}",
+		local.iter().collect::<Vec<_>>()
+	);
+	match analyze::analyze(source, &tracker, &config.policy(), &local) {
+		Ok(report) => {
+			print!("{}", report);
+			true
+		}
+		Err(e) => {
+			log::error!("Failed to read capture {:?}: {}", analyze.capture, e);
+			false
+		}
+	}
+}
+
+/// Send a `ctl` command to the control endpoint and print its result
+async fn run_ctl(endpoint: &str, ctl: &Ctl) -> bool {
+	let (method, params) = match control::command_request(&ctl.command) {
+		Ok(request) => request,
+		Err(e) => {
+			log::error!("{}", e);
+			return false;
+		}
+	};
+	match control::call(endpoint, &method, params).await {
+		// Exports such as CSV come back as plain text
+		Ok(serde_json::Value::String(text)) => {
+			print!("{}", text);
+			true
+		}
+		Ok(result) => {
+			println!(
+				"{}",
+				serde_json::to_string_pretty(&result).unwrap_or_default()
+			);
+			true
+		}
+		Err(e) => {
+			log::error!("Control request to {} failed: {}", endpoint, e);
+			false
+		}
+	}
+}
+
+/// Divert game traffic until Ctrl-C is pressed
+#[cfg(all(windows, feature = "windivert", feature = "wmi"))]
+async fn run_live(
+	args: Lobbyguard, config: Config, net_filter: String, endpoint: String, log_level: LogLevel,
+) {
+	use std::sync::Arc;
+
+	use lobbyguard_cli::capture::RotatingCapture;
+	use lobbyguard_cli::capture::recorder::dump_path;
+	use lobbyguard_cli::connection_tracker::ConnectionTracker;
+	use lobbyguard_cli::control::Controller;
+	use lobbyguard_cli::flow_table::DEFAULT_IDLE_TIMEOUT;
+	use lobbyguard_cli::packet_io::WinDivertSource;
+	use lobbyguard_cli::packet_processor::{CaptureTargets, Enforcement, LoopOptions, process_packets};
+	use lobbyguard_cli::stats::Stats;
+	use lobbyguard_cli::wmi_monitor::{initialize_wmi, run_wmi_monitor};
+	use log::debug;
+	use windivert::prelude::*;
 
 	// Initialize connection tracker
 	let tracker = Arc::new(ConnectionTracker::new());
 
 	// Initialize WMI and query existing processes/connections
-	let (default_con, standard_con) = initialize_wmi(Arc::clone(&tracker))
+	let (default_con, standard_con) = initialize_wmi(Arc::clone(&tracker), &config)
 		.expect("Failed to initialize WMI connections");
 
-	// Build WinDivert filter
-	let net_filter = build_network_filter(args.capture_tcp);
+	let enforcement = if args.sniff {
+		Enforcement::Sniff
+	} else if args.dry_run {
+		Enforcement::DryRun
+	} else {
+		Enforcement::Enforce
+	};
+	// Sniffed packets are copies, so the originals are never held back
+	let flags = match enforcement {
+		Enforcement::Sniff => WinDivertFlags::new().set_sniff().set_recv_only(),
+		_ => WinDivertFlags::new(),
+	};
+
 	debug!("Creating network divert with filter: {}", net_filter);
-	let network_divert = WinDivert::<NetworkLayer>::network(&net_filter, 0, Default::default())
+	let network_divert = WinDivert::<NetworkLayer>::network(&net_filter, 0, flags)
 		.expect("Failed to create network layer WinDivert handle.");
 
 	let net_shutdown_handle = network_divert.shutdown_handle();
 
 	// Spawn packet processing task
 	let tracker_clone = Arc::clone(&tracker);
-	let pcap_file = args.file.clone();
-	let net_handle = tokio::spawn(async move {
-		process_packets(network_divert, tracker_clone, pcap_file);
+	let policy = Arc::new(config.policy());
+	log::info!("Filtering in {} mode ({:?})", policy.mode(), enforcement);
+	let stats = Arc::new(Stats::with_flow_timeout(
+		args.flow_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
+	));
+	let policy_clone = Arc::clone(&policy);
+	let stats_clone = Arc::clone(&stats);
+	let file = args.file.as_ref().and_then(|path| {
+		let format = args
+			.capture_format
+			.unwrap_or_else(|| CaptureFormat::from_path(path));
+		RotatingCapture::create(
+			path,
+			format,
+			args.compress,
+			args.rotation(),
+			args.ts_resolution(),
+		)
+			.inspect_err(|e| log::error!("Error creating capture file {:?}: {}", path, e))
+			.ok()
+	});
+	let recorder = args.recorder().map(Arc::new);
+	let capture = CaptureTargets {
+		file,
+		recorder: recorder.clone(),
+		selector: args.selector(),
+	};
+	let (source, sink) = WinDivertSource::split(network_divert);
+	// The packet loop blocks on the driver, so it gets a thread of its own
+	let net_handle = tokio::task::spawn_blocking(move || {
+		process_packets(
+			source,
+			sink,
+			tracker_clone,
+			policy_clone,
+			stats_clone,
+			LoopOptions {
+				enforcement,
+				buffer_size: args.buffer_size,
+				snaplen: args.snaplen,
+			},
+			capture,
+		);
+	});
+
+	// Serve the control API alongside the packet loop
+	let controller = Arc::new(Controller::new(
+		Arc::clone(&tracker),
+		policy,
+		stats,
+		log_level,
+		recorder.clone(),
+	));
+	let control_handle = tokio::spawn(async move {
+		if let Err(e) = control::serve(&endpoint, controller).await {
+			log::error!("Control API error on {}: {}", endpoint, e);
+		}
+	});
+
+	// Ctrl-Break dumps the flight recorder while Ctrl-C still stops lobbyguard
+	let dump_handle = recorder.map(|recorder| {
+		tokio::spawn(async move {
+			let mut ctrl_break = match tokio::signal::windows::ctrl_break() {
+				Ok(ctrl_break) => ctrl_break,
+				Err(e) => {
+					log::error!("Failed to listen for Ctrl-Break: {}", e);
+					return;
+				}
+			};
+			while ctrl_break.recv().await.is_some() {
+				let format = CaptureFormat::Pcapng;
+				let path = dump_path(format);
+				match recorder.dump(&path, format) {
+					Ok(packets) => log::info!("Dumped {} recorded packets to {:?}", packets, path),
+					Err(e) => log::error!("Failed to dump recorded packets to {:?}: {}", path, e),
+				}
+			}
+		})
 	});
 
 	// Run WMI event monitoring loop
-	if let Err(e) = run_wmi_monitor(default_con, standard_con, tracker).await {
+	let reconcile_interval = args.reconcile_interval;
+	if let Err(e) =
+		run_wmi_monitor(default_con, standard_con, tracker, &config, reconcile_interval).await
+	{
 		log::error!("WMI monitor error: {}", e);
 	}
 
-	// Cleanup
+	// Cleanup: the shut down handle ends the packet loop once its queue is drained,
+	// which then completes the capture file
 	if let Err(e) = net_shutdown_handle.shutdown() {
 		log::error!("Failed to shutdown network WinDivert: {}", e);
 	}
-	net_handle.abort();
+	if let Err(e) = net_handle.await {
+		log::error!("Packet processing task failed: {}", e);
+	}
+	control_handle.abort();
+	if let Some(dump_handle) = dump_handle {
+		dump_handle.abort();
+	}
+}
+
+/// Live diversion is unavailable without the Windows backends
+#[cfg(not(all(windows, feature = "windivert", feature = "wmi")))]
+async fn run_live(
+	_args: Lobbyguard, _config: Config, _net_filter: String, _endpoint: String, _log_level: LogLevel,
+) {
+	log::error!("Live filtering requires Windows with the `windivert` and `wmi` features enabled");
 	fastrace::flush();
+	std::process::exit(1);
 }
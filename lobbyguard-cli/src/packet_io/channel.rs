@@ -0,0 +1,85 @@
+use std::sync::mpsc::{Receiver, Sender, channel};
+
+use super::{Packet, PacketIoError, PacketMeta, PacketSink, PacketSource};
+
+/// In-memory packet source fed through a channel
+pub struct ChannelSource {
+	receiver: Receiver<Packet<'static>>,
+}
+
+impl ChannelSource {
+	/// Create a source reading from `receiver`
+	pub fn new(receiver: Receiver<Packet<'static>>) -> Self { Self { receiver } }
+
+	/// Create a source together with the sender used to feed it
+	pub fn pair() -> (Sender<Packet<'static>>, Self) {
+		let (sender, receiver) = channel();
+		(sender, Self::new(receiver))
+	}
+}
+
+impl PacketSource for ChannelSource {
+	fn recv<'a>(&mut self, buffer: &'a mut [u8]) -> Result<Option<Packet<'a>>, PacketIoError> {
+		// A disconnected sender means no more packets will arrive
+		let Ok(packet) = self.receiver.recv() else {
+			return Ok(None);
+		};
+		// Packets larger than the buffer are cut short like a real source would
+		let len = packet.data.len().min(buffer.len());
+		buffer[..len].copy_from_slice(&packet.data[..len]);
+		let meta = PacketMeta {
+			truncated: packet.meta.truncated || len < packet.data.len(),
+			..packet.meta
+		};
+		Ok(Some(Packet::new(&buffer[..len], meta)))
+	}
+}
+
+/// In-memory packet sink forwarding re-injected packets through a channel
+pub struct ChannelSink {
+	sender: Sender<Packet<'static>>,
+}
+
+impl ChannelSink {
+	/// Create a sink writing to `sender`
+	pub fn new(sender: Sender<Packet<'static>>) -> Self { Self { sender } }
+
+	/// Create a sink together with the receiver collecting its packets
+	pub fn pair() -> (Self, Receiver<Packet<'static>>) {
+		let (sender, receiver) = channel();
+		(Self::new(sender), receiver)
+	}
+}
+
+impl PacketSink for ChannelSink {
+	fn send(&mut self, packet: &Packet<'_>) -> Result<(), PacketIoError> {
+		self.sender.send(packet.clone().into_owned())?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn recv_cuts_packets_to_the_buffer() {
+		let (sender, mut source) = ChannelSource::pair();
+		sender
+			.send(Packet::owned(vec![7; 32], PacketMeta::default()))
+			.unwrap();
+		sender
+			.send(Packet::owned(vec![7; 8], PacketMeta::default()))
+			.unwrap();
+		drop(sender);
+
+		let mut buffer = [0; 16];
+		let packet = source.recv(&mut buffer).unwrap().unwrap();
+		assert_eq!(packet.data.len(), 16);
+		assert!(packet.meta.truncated);
+		let packet = source.recv(&mut buffer).unwrap().unwrap();
+		assert_eq!(packet.data.as_ref(), [7; 8]);
+		assert!(!packet.meta.truncated);
+		assert!(source.recv(&mut buffer).unwrap().is_none());
+	}
+}
@@ -0,0 +1,133 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use windivert::prelude::*;
+use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
+
+use super::{Packet, PacketIoError, PacketMeta, PacketSink, PacketSource};
+
+/// Packet source receiving from a network layer WinDivert handle
+pub struct WinDivertSource {
+	divert: Arc<WinDivert<NetworkLayer>>,
+	clock: QpcClock,
+}
+
+/// Packet sink re-injecting into a network layer WinDivert handle
+pub struct WinDivertSink {
+	divert: Arc<WinDivert<NetworkLayer>>,
+}
+
+impl WinDivertSource {
+	/// Split a WinDivert handle into its receiving and re-injecting halves
+	pub fn split(divert: WinDivert<NetworkLayer>) -> (WinDivertSource, WinDivertSink) {
+		let divert = Arc::new(divert);
+		(
+			WinDivertSource {
+				divert: Arc::clone(&divert),
+				clock: QpcClock::new(),
+			},
+			WinDivertSink { divert },
+		)
+	}
+}
+
+impl PacketSource for WinDivertSource {
+	fn recv<'a>(&mut self, buffer: &'a mut [u8]) -> Result<Option<Packet<'a>>, PacketIoError> {
+		// Packets larger than the buffer arrive cut short instead of failing the receive
+		let (address, data, truncated) = match self.divert.partial_recv(buffer) {
+			Ok(PacketEither::Full(packet)) => (packet.address, packet.data, false),
+			Ok(PacketEither::Partial(packet)) => (packet.address, packet.data, true),
+			Err(WinDivertError::Recv(WinDivertRecvError::NoData)) => return Ok(None),
+			Err(e) => return Err(e.into()),
+		};
+		let meta = PacketMeta {
+			timestamp: self.clock.to_unix(address.event_timestamp()),
+			outbound: address.outbound(),
+			direction_known: true,
+			loopback: address.loopback(),
+			impostor: address.impostor(),
+			ipv6: address.ipv6(),
+			ip_checksum: address.ip_checksum(),
+			tcp_checksum: address.tcp_checksum(),
+			udp_checksum: address.udp_checksum(),
+			interface_index: address.interface_index(),
+			subinterface_index: address.subinterface_index(),
+			truncated,
+		};
+		Ok(Some(Packet { data, meta }))
+	}
+}
+
+impl PacketSink for WinDivertSink {
+	fn send(&mut self, packet: &Packet<'_>) -> Result<(), PacketIoError> {
+		// SAFETY: every flag and index of the received address is restored below
+		let mut address = unsafe { WinDivertAddress::<NetworkLayer>::new() };
+		address.set_outbound(packet.meta.outbound);
+		address.set_loopback(packet.meta.loopback);
+		address.set_impostor(packet.meta.impostor);
+		address.as_mut().set_ipv6(packet.meta.ipv6);
+		address.set_ip_checksum(packet.meta.ip_checksum);
+		address.set_tcp_checksum(packet.meta.tcp_checksum);
+		address.set_udp_checksum(packet.meta.udp_checksum);
+		address.set_interface_index(packet.meta.interface_index);
+		address.set_subinterface_index(packet.meta.subinterface_index);
+
+		let packet = WinDivertPacket {
+			address,
+			data: Cow::Borrowed(packet.data.as_ref()),
+		};
+		self.divert.send(&packet)?;
+		Ok(())
+	}
+}
+
+/// Converts WinDivert event timestamps, which are performance counter ticks, to wall-clock time
+struct QpcClock {
+	/// Wall-clock time as a duration since the UNIX epoch when `origin` was read
+	epoch: Duration,
+	/// Performance counter value read at `epoch`
+	origin: i64,
+	/// Performance counter ticks per second
+	frequency: i64,
+}
+
+impl QpcClock {
+	fn new() -> Self {
+		let mut origin = 0;
+		let mut frequency = 0;
+		// SAFETY: both calls only write to the valid `i64` they are given
+		let result = unsafe {
+			QueryPerformanceFrequency(&mut frequency).and_then(|()| QueryPerformanceCounter(&mut origin))
+		};
+		if let Err(e) = result {
+			log::error!("Failed to read the performance counter: {}", e);
+		}
+		let epoch = SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.unwrap_or_else(|e| {
+				log::error!("Time went backwards: {}", e);
+				Duration::ZERO
+			});
+		Self {
+			epoch,
+			origin,
+			frequency,
+		}
+	}
+
+	/// Wall-clock time of a performance counter value, as a duration since the UNIX epoch
+	fn to_unix(&self, ticks: i64) -> Duration {
+		if self.frequency <= 0 {
+			return self.epoch;
+		}
+		let elapsed = i128::from(ticks - self.origin);
+		let nanos = elapsed * 1_000_000_000 / i128::from(self.frequency);
+		let offset = Duration::from_nanos(nanos.unsigned_abs().try_into().unwrap_or(u64::MAX));
+		if nanos >= 0 {
+			self.epoch.saturating_add(offset)
+		} else {
+			self.epoch.saturating_sub(offset)
+		}
+	}
+}
@@ -0,0 +1,123 @@
+pub mod channel;
+#[cfg(all(windows, feature = "windivert"))]
+pub mod divert;
+pub mod replay;
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+pub use channel::{ChannelSink, ChannelSource};
+#[cfg(all(windows, feature = "windivert"))]
+pub use divert::{WinDivertSink, WinDivertSource};
+pub use replay::{DiscardSink, PcapReplaySource};
+
+/// Error type shared by every packet source and sink
+pub type PacketIoError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Metadata received alongside a packet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketMeta {
+	/// Receive time as a duration since the UNIX epoch
+	pub timestamp: Duration,
+	/// Whether the packet is leaving this machine
+	pub outbound: bool,
+	/// Whether the source reported `outbound`, which must be inferred otherwise
+	pub direction_known: bool,
+	/// Whether the packet travels over the loopback interface
+	pub loopback: bool,
+	/// Whether the packet was injected by another WinDivert handle
+	pub impostor: bool,
+	/// Whether the packet is IPv6
+	pub ipv6: bool,
+	/// Whether the IPv4 header checksum is known to be valid
+	pub ip_checksum: bool,
+	/// Whether the TCP checksum is known to be valid
+	pub tcp_checksum: bool,
+	/// Whether the UDP checksum is known to be valid
+	pub udp_checksum: bool,
+	/// Interface index the packet arrived on or is to be sent from
+	pub interface_index: u32,
+	/// Sub-interface index for `interface_index`
+	pub subinterface_index: u32,
+	/// Whether the packet did not fit the receive buffer and was cut short
+	pub truncated: bool,
+}
+
+/// A raw IP packet together with its metadata
+#[derive(Debug, Clone)]
+pub struct Packet<'a> {
+	/// Raw IPv4/IPv6 packet, starting at the IP header
+	pub data: Cow<'a, [u8]>,
+	/// Metadata describing where the packet came from
+	pub meta: PacketMeta,
+}
+
+impl<'a> Packet<'a> {
+	/// Create a packet borrowing its data
+	pub fn new(data: &'a [u8], meta: PacketMeta) -> Self {
+		Self {
+			data: Cow::Borrowed(data),
+			meta,
+		}
+	}
+
+	/// Create a packet owning its data
+	pub fn owned(data: Vec<u8>, meta: PacketMeta) -> Packet<'static> {
+		Packet {
+			data: Cow::Owned(data),
+			meta,
+		}
+	}
+
+	/// Whether the packet was cut short, as told by the source or by its IP header
+	pub fn is_truncated(&self) -> bool {
+		self.meta.truncated || ip_length(&self.data).is_some_and(|len| len > self.data.len())
+	}
+
+	/// Detach the packet from the receive buffer
+	pub fn into_owned(self) -> Packet<'static> {
+		Packet {
+			data: Cow::Owned(self.data.into_owned()),
+			meta: self.meta,
+		}
+	}
+}
+
+/// Packet length announced by the IPv4 or IPv6 header at the start of `data`
+fn ip_length(data: &[u8]) -> Option<usize> {
+	let field = |at: usize| {
+		data
+			.get(at..at + 2)
+			.map(|b| usize::from(u16::from_be_bytes([b[0], b[1]])))
+	};
+	match data.first()? >> 4 {
+		4 => field(2),
+		6 => field(4).map(|payload| 40 + payload),
+		_ => None,
+	}
+}
+
+/// Something packets can be received from
+pub trait PacketSource {
+	/// Block until the next packet is available.
+	///
+	/// `buffer` may be used as backing storage for the returned packet.
+	/// Returns `Ok(None)` once the source is closed or exhausted.
+	fn recv<'a>(&mut self, buffer: &'a mut [u8]) -> Result<Option<Packet<'a>>, PacketIoError>;
+}
+
+/// Something packets can be re-injected into
+pub trait PacketSink {
+	/// Re-inject a packet previously received from a [`PacketSource`]
+	fn send(&mut self, packet: &Packet<'_>) -> Result<(), PacketIoError>;
+}
+
+impl<S: PacketSource + ?Sized> PacketSource for &mut S {
+	fn recv<'a>(&mut self, buffer: &'a mut [u8]) -> Result<Option<Packet<'a>>, PacketIoError> {
+		(**self).recv(buffer)
+	}
+}
+
+impl<S: PacketSink + ?Sized> PacketSink for &mut S {
+	fn send(&mut self, packet: &Packet<'_>) -> Result<(), PacketIoError> { (**self).send(packet) }
+}
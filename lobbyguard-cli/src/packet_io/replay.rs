@@ -0,0 +1,317 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
+
+use etherparse::{EtherType, Ethernet2HeaderSlice};
+use flate2::read::MultiGzDecoder;
+use log::debug;
+use pcap_file::DataLink;
+use pcap_file::pcap::PcapReader;
+use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketOption;
+use pcap_file::pcapng::{Block, PcapNgReader};
+
+use super::{Packet, PacketIoError, PacketMeta, PacketSink, PacketSource};
+use crate::capture::{Compression, EPB_INBOUND, EPB_OUTBOUND};
+
+/// Block type of the pcapng section header, which starts every pcapng file
+const PCAPNG_MAGIC: [u8; 4] = [0x0a, 0x0d, 0x0d, 0x0a];
+
+/// Reader of the records of a pcap or pcapng stream
+enum CaptureReader<R: Read> {
+	Pcap {
+		reader: PcapReader<R>,
+		datalink: DataLink,
+	},
+	Pcapng {
+		reader: PcapNgReader<R>,
+		/// Link type of every interface of the current section, by interface ID
+		datalinks: Vec<DataLink>,
+	},
+}
+
+/// Packet source replaying the records of a pcap or pcapng file
+pub struct PcapReplaySource<R: Read> {
+	reader: CaptureReader<R>,
+}
+
+impl PcapReplaySource<BufReader<Box<dyn Read>>> {
+	/// Open a pcap or pcapng file for replay, decompressing it if it ends in `.gz` or `.zst`
+	pub fn open(path: impl AsRef<Path>) -> Result<Self, PacketIoError> {
+		let path = path.as_ref();
+		let file = File::open(path)?;
+		let reader: Box<dyn Read> = match Compression::from_path(path) {
+			Compression::None => Box::new(file),
+			Compression::Gzip => Box::new(MultiGzDecoder::new(BufReader::new(file))),
+			#[cfg(feature = "zstd")]
+			Compression::Zstd => Box::new(zstd::Decoder::new(file)?),
+			#[cfg(not(feature = "zstd"))]
+			Compression::Zstd => {
+				return Err("zstd compressed captures require the `zstd` feature".into());
+			}
+		};
+		Self::new(BufReader::new(reader))
+	}
+}
+
+impl<R: BufRead> PcapReplaySource<R> {
+	/// Create a replay source from any pcap or pcapng stream, told apart by their magic number
+	///
+	/// Raw IP, IPv4, IPv6 and Ethernet link types are supported.
+	pub fn new(mut reader: R) -> Result<Self, PacketIoError> {
+		let reader = if reader.fill_buf()?.starts_with(&PCAPNG_MAGIC) {
+			CaptureReader::Pcapng {
+				reader: PcapNgReader::new(reader)?,
+				datalinks: Vec::new(),
+			}
+		} else {
+			let reader = PcapReader::new(reader)?;
+			let datalink = reader.header().datalink;
+			if !is_supported(datalink) {
+				return Err(format!("Unsupported pcap link type: {:?}", datalink).into());
+			}
+			CaptureReader::Pcap { reader, datalink }
+		};
+		Ok(Self { reader })
+	}
+}
+
+/// Whether packets of `datalink` can be replayed
+fn is_supported(datalink: DataLink) -> bool {
+	matches!(
+		datalink,
+		DataLink::RAW | DataLink::IPV4 | DataLink::IPV6 | DataLink::ETHERNET
+	)
+}
+
+/// Packet record of a capture, whatever its format
+struct Record<'a> {
+	timestamp: Duration,
+	/// Length of the packet before it was cut to the snaplen
+	orig_len: u32,
+	datalink: DataLink,
+	/// Direction given by the pcapng `epb_flags`, unknown in pcap
+	outbound: Option<bool>,
+	data: Cow<'a, [u8]>,
+}
+
+/// Direction of a packet given by its pcapng `epb_flags`, if they tell it
+fn flags_direction(options: &[EnhancedPacketOption<'_>]) -> Option<bool> {
+	options.iter().find_map(|option| match option {
+		EnhancedPacketOption::Flags(flags) => match flags & (EPB_INBOUND | EPB_OUTBOUND) {
+			EPB_INBOUND => Some(false),
+			EPB_OUTBOUND => Some(true),
+			_ => None,
+		},
+		_ => None,
+	})
+}
+
+impl<R: Read> CaptureReader<R> {
+	/// Read the next block, which holds a packet record unless it describes the capture
+	///
+	/// Returns `None` at the end of the capture.
+	fn next_block(&mut self) -> Option<Result<Option<Record<'_>>, PacketIoError>> {
+		match self {
+			CaptureReader::Pcap { reader, datalink } => {
+				let record = match reader.next_packet()? {
+					Ok(record) => record,
+					Err(e) => return Some(Err(e.into())),
+				};
+				Some(Ok(Some(Record {
+					timestamp: record.timestamp,
+					orig_len: record.orig_len,
+					datalink: *datalink,
+					outbound: None,
+					data: record.data,
+				})))
+			}
+			CaptureReader::Pcapng { reader, datalinks } => {
+				let block = match reader.next_block()? {
+					Ok(block) => block,
+					Err(e) => return Some(Err(e.into())),
+				};
+				let (interface_id, timestamp, orig_len, outbound, data) = match block {
+					Block::SectionHeader(_) => {
+						datalinks.clear();
+						return Some(Ok(None));
+					}
+					Block::InterfaceDescription(interface) => {
+						datalinks.push(interface.linktype);
+						return Some(Ok(None));
+					}
+					Block::EnhancedPacket(packet) => (
+						packet.interface_id,
+						packet.timestamp,
+						packet.original_len,
+						flags_direction(&packet.options),
+						packet.data,
+					),
+					// Simple packets have no timestamp and belong to the first interface
+					Block::SimplePacket(packet) => {
+						(0, Duration::ZERO, packet.original_len, None, packet.data)
+					}
+					_ => return Some(Ok(None)),
+				};
+				let Some(datalink) = datalinks.get(interface_id as usize) else {
+					let message = format!("Packet of undeclared pcapng interface {}", interface_id);
+					return Some(Err(message.into()));
+				};
+				Some(Ok(Some(Record {
+					timestamp,
+					orig_len,
+					datalink: *datalink,
+					outbound,
+					data,
+				})))
+			}
+		}
+	}
+}
+
+impl<R: Read> PacketSource for PcapReplaySource<R> {
+	fn recv<'a>(&mut self, buffer: &'a mut [u8]) -> Result<Option<Packet<'a>>, PacketIoError> {
+		loop {
+			let Some(block) = self.reader.next_block() else {
+				return Ok(None);
+			};
+			let Some(record) = block? else {
+				continue;
+			};
+
+			let data: &[u8] = match record.datalink {
+				DataLink::ETHERNET => {
+					let Ok(ethernet) = Ethernet2HeaderSlice::from_slice(&record.data) else {
+						debug!("Skipping truncated Ethernet record");
+						continue;
+					};
+					match ethernet.ether_type() {
+						EtherType::IPV4 | EtherType::IPV6 => &record.data[ethernet.slice().len()..],
+						_ => continue,
+					}
+				}
+				datalink if is_supported(datalink) => &record.data,
+				datalink => {
+					debug!("Skipping record of unsupported link type {:?}", datalink);
+					continue;
+				}
+			};
+
+			let len = data.len().min(buffer.len());
+			buffer[..len].copy_from_slice(&data[..len]);
+			let meta = PacketMeta {
+				timestamp: record.timestamp,
+				outbound: record.outbound.unwrap_or_default(),
+				direction_known: record.outbound.is_some(),
+				truncated: len < data.len() || record.orig_len as usize > record.data.len(),
+				..Default::default()
+			};
+			return Ok(Some(Packet::new(&buffer[..len], meta)));
+		}
+	}
+}
+
+/// Packet sink that drops every packet it is given
+#[derive(Debug, Default)]
+pub struct DiscardSink;
+
+impl PacketSink for DiscardSink {
+	fn send(&mut self, _packet: &Packet<'_>) -> Result<(), PacketIoError> { Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use pcap_file::TsResolution;
+
+	use super::*;
+	use crate::capture::{CaptureFormat, CaptureOutput, CaptureWriter, CapturedPacket};
+	use crate::classifier::{Reason, Verdict};
+
+	/// Minimal IPv4 header announcing a packet of `len` bytes, followed by zeros
+	fn ipv4(len: usize) -> Vec<u8> {
+		let mut data = vec![0; len];
+		data[0] = 0x45;
+		data[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+		data
+	}
+
+	fn write_capture<W: std::io::Write>(writer: W, format: CaptureFormat) -> W {
+		let mut capture = CaptureWriter::new(writer, format, TsResolution::MicroSecond).unwrap();
+		let whole = ipv4(40);
+		let cut = ipv4(1000);
+		let packets = [(&whole[..], 40, 1), (&cut[..100], 1000, 2)];
+		for (data, orig_len, secs) in packets {
+			let packet = CapturedPacket {
+				data,
+				orig_len,
+				meta: PacketMeta {
+					timestamp: Duration::from_secs(secs),
+					..Default::default()
+				},
+				verdict: Verdict::Pass(Reason::Untracked),
+				pid: None,
+				profile: None,
+			};
+			capture.write(&packet).unwrap();
+		}
+		capture.into_inner()
+	}
+
+	fn replay<R: Read>(mut source: PcapReplaySource<R>) -> Vec<(Duration, usize, bool)> {
+		let mut buffer = vec![0; 2048];
+		let mut packets = Vec::new();
+		while let Some(packet) = source.recv(&mut buffer).unwrap() {
+			packets.push((
+				packet.meta.timestamp,
+				packet.data.len(),
+				packet.meta.truncated,
+			));
+		}
+		packets
+	}
+
+	const EXPECTED: [(Duration, usize, bool); 2] = [
+		(Duration::from_secs(1), 40, false),
+		(Duration::from_secs(2), 100, true),
+	];
+
+	#[test]
+	fn replay_pcap_and_pcapng() {
+		for format in [CaptureFormat::Pcap, CaptureFormat::Pcapng] {
+			let capture = write_capture(Vec::new(), format);
+			let source = PcapReplaySource::new(&capture[..]).unwrap();
+			assert_eq!(replay(source), EXPECTED, "{}", format);
+		}
+	}
+
+	#[test]
+	fn replay_compressed_files() {
+		let mut compressions = vec![Compression::Gzip];
+		if cfg!(feature = "zstd") {
+			compressions.push(Compression::Zstd);
+		}
+		for compression in compressions {
+			let name = format!(
+				"lobbyguard-replay-{}.pcapng.{}",
+				std::process::id(),
+				compression.extension().unwrap()
+			);
+			let path = std::env::temp_dir().join(name);
+			let output = CaptureOutput::create(&path, compression).unwrap();
+			write_capture(output, CaptureFormat::Pcapng)
+				.finish()
+				.unwrap();
+			let packets = replay(PcapReplaySource::open(&path).unwrap());
+			std::fs::remove_file(&path).unwrap();
+			assert_eq!(packets, EXPECTED, "{}", compression);
+		}
+	}
+
+	#[test]
+	fn reject_unknown_format() {
+		assert!(PcapReplaySource::new(&b"not a capture file"[..]).is_err());
+	}
+}
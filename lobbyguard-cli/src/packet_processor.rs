@@ -1,64 +1,162 @@
-use std::fs::File;
-use std::net::{IpAddr, SocketAddr};
-use std::path::PathBuf;
+use std::fmt;
 use std::sync::Arc;
 use std::time::Duration;
 
-use etherparse::{NetSlice, SlicedPacket, TransportSlice};
-use log::{debug, error, trace};
-use pcap_file::pcap::{PcapHeader, PcapPacket, PcapWriter};
-use pcap_file::{DataLink, Endianness, TsResolution};
-use windivert::prelude::*;
+use log::{debug, error, info, trace, warn};
 
+use crate::capture::{CaptureSelector, CapturedPacket, FlightRecorder, RotatingCapture};
+use crate::classifier::{PacketInfo, Policy, Reason, Verdict, classify_info};
 use crate::connection_tracker::ConnectionTracker;
+use crate::packet_io::{PacketMeta, PacketSink, PacketSource};
+use crate::stats::Stats;
 
-/// Packet size constants for GTA Online traffic classification
-pub const HEARTBEAT_SIZES: [usize; 3] = [12, 18, 63];
-pub const MATCHMAKING_SIZES: [usize; 4] = [191, 207, 223, 239];
+/// What the packet loop does with the verdicts it computes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Enforcement {
+	/// Re-inject passed packets and discard the others
+	#[default]
+	Enforce,
+	/// Re-inject every packet, logging and counting those that would be dropped
+	DryRun,
+	/// Only observe copies of packets that the network delivers on its own
+	Sniff,
+}
 
-/// Process network packets from WinDivert
-pub fn process_packets(
-	network_divert: WinDivert<NetworkLayer>,
-	tracker: Arc<ConnectionTracker>,
-	pcap_file: Option<PathBuf>,
-) {
-	let mut pcap_writer = None;
-	if let Some(file) = pcap_file {
-		match File::create(&file) {
-			Ok(file_out) => {
-				match PcapWriter::with_header(
-					file_out,
-					PcapHeader {
-						version_major: 2,
-						version_minor: 4,
-						ts_correction: 0,
-						ts_accuracy: 0,
-						snaplen: 65535,
-						datalink: DataLink::RAW,
-						ts_resolution: TsResolution::MicroSecond,
-						endianness: Endianness::native(),
-					},
-				) {
-					Ok(pcap) => pcap_writer = Some(pcap),
-					Err(e) => error!("Error initializing PCAP writer for {:?}: {}", file, e),
-				}
-			}
-			Err(e) => error!("Error creating PCAP file {:?}: {}", file, e),
+impl Enforcement {
+	/// Whether packets with a drop verdict are actually discarded
+	pub fn drops(&self) -> bool { *self == Enforcement::Enforce }
+
+	/// Whether the packet loop must re-inject the packets it receives
+	pub fn reinjects(&self) -> bool { *self != Enforcement::Sniff }
+}
+
+/// Largest IP packet, beyond which a bigger receive buffer is of no use
+pub const MAX_BUFFER_SIZE: usize = 65535;
+
+/// Smallest receive buffer, the minimum IPv6 MTU
+pub const MIN_BUFFER_SIZE: usize = 1280;
+
+/// Largest number of bytes of a packet written to captures
+pub const MAX_SNAPLEN: usize = 65535;
+
+/// Smallest snaplen, enough for the IP and transport headers of most packets
+pub const MIN_SNAPLEN: usize = 64;
+
+/// How the packet loop receives and treats packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopOptions {
+	pub enforcement: Enforcement,
+	/// Bytes received per packet when sniffing; re-injecting modes always receive whole packets
+	pub buffer_size: usize,
+	/// Bytes of each packet kept by captures and the flight recorder
+	pub snaplen: usize,
+}
+
+impl Default for LoopOptions {
+	fn default() -> Self {
+		Self {
+			enforcement: Enforcement::default(),
+			buffer_size: MAX_BUFFER_SIZE,
+			snaplen: MAX_SNAPLEN,
 		}
 	}
+}
+
+/// Parse a receive buffer size between [`MIN_BUFFER_SIZE`] and [`MAX_BUFFER_SIZE`] bytes
+pub fn parse_buffer_size(s: &str) -> Result<usize, String> {
+	s.trim()
+		.parse::<usize>()
+		.ok()
+		.filter(|size| (MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE).contains(size))
+		.ok_or_else(|| {
+			format!(
+				"invalid buffer size `{}`, expected {} to {} bytes",
+				s, MIN_BUFFER_SIZE, MAX_BUFFER_SIZE
+			)
+		})
+}
+
+/// Parse a capture snaplen between [`MIN_SNAPLEN`] and [`MAX_SNAPLEN`] bytes
+pub fn parse_snaplen(s: &str) -> Result<usize, String> {
+	s.trim()
+		.parse::<usize>()
+		.ok()
+		.filter(|size| (MIN_SNAPLEN..=MAX_SNAPLEN).contains(size))
+		.ok_or_else(|| {
+			format!(
+				"invalid snaplen `{}`, expected {} to {} bytes",
+				s, MIN_SNAPLEN, MAX_SNAPLEN
+			)
+		})
+}
 
-	let mut buffer = [0u8; 1500];
+/// Log line describing a classified packet
+struct PacketLine<'a> {
+	info: &'a PacketInfo,
+	meta: &'a PacketMeta,
+	verdict: Verdict,
+	profile: &'a str,
+}
+
+impl fmt::Display for PacketLine<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let direction = match (self.meta.loopback, self.meta.outbound) {
+			(true, _) => "LOOPBACK",
+			(false, true) => "OUT",
+			(false, false) => "IN",
+		};
+		write!(
+			f,
+			"{} PACKET {} {}@{} {} -> {} [L{}] [{}]",
+			self.info.protocol,
+			self.verdict,
+			direction,
+			self.meta.interface_index,
+			self.info.src,
+			self.info.dst,
+			self.info.payload_len,
+			self.profile
+		)
+	}
+}
+
+/// Where the packet loop keeps the packets whose verdict asks for a capture
+#[derive(Default)]
+pub struct CaptureTargets {
+	/// Capture files on disk
+	pub file: Option<RotatingCapture>,
+	/// Ring of recent packets kept in memory until dumped
+	pub recorder: Option<Arc<FlightRecorder>>,
+	/// Which of the packets marked for capture are kept
+	pub selector: CaptureSelector,
+}
+
+/// Process network packets received from `source`, re-injecting passed ones into `sink`
+pub fn process_packets(
+	mut source: impl PacketSource, mut sink: impl PacketSink, tracker: Arc<ConnectionTracker>,
+	policy: Arc<Policy>, stats: Arc<Stats>, options: LoopOptions, mut capture: CaptureTargets,
+) {
+	let LoopOptions {
+		enforcement,
+		buffer_size,
+		snaplen,
+	} = options;
+	// WinDivert cannot re-inject a partial packet, so only a sniffer may receive fewer bytes
+	let buffer_size = if enforcement.reinjects() {
+		MAX_BUFFER_SIZE
+	} else {
+		buffer_size.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE)
+	};
+	let mut buffer = vec![0u8; buffer_size];
+	let mut next_expiry = Duration::ZERO;
+	let mut truncated = 0u64;
 
 	debug!("Start receiving network packet");
 	loop {
-		let result = network_divert.recv_wait(&mut buffer, 0);
-		let packet = match result {
+		let packet = match source.recv(&mut buffer) {
 			Ok(Some(packet)) => packet,
 			Ok(None) => {
-				continue;
-			}
-			Err(WinDivertError::Recv(WinDivertRecvError::NoData)) => {
-				debug!("Network packet handle shutdown");
+				debug!("Network packet source closed");
 				break;
 			}
 			Err(e) => {
@@ -67,100 +165,317 @@ pub fn process_packets(
 			}
 		};
 
-		let Ok(sliced_packet) = SlicedPacket::from_ip(&packet.data) else {
-			error!(
-				"Failed to parse packet headers despite filter match - data length: {}",
-				packet.data.len()
-			);
-			continue;
-		};
-
-		let (src_addr, dst_addr): (IpAddr, IpAddr) = match sliced_packet.net {
-			Some(NetSlice::Ipv4(ip4)) => (
-				ip4.header().source_addr().into(),
-				ip4.header().destination_addr().into(),
-			),
-			Some(NetSlice::Ipv6(ip6)) => (
-				ip6.header().source_addr().into(),
-				ip6.header().destination_addr().into(),
-			),
-			_ => {
-				debug!(
-					"Skipping non-IPv4/IPv6 packet from network layer: {:?}",
-					sliced_packet.net
+		// Fail open: a packet that was not received whole is passed unclassified
+		if packet.is_truncated() {
+			truncated += 1;
+			if truncated.is_power_of_two() {
+				warn!(
+					"{} packets cut short so far were let through unclassified, last one to {} bytes",
+					truncated,
+					packet.data.len()
 				);
-				continue;
 			}
-		};
+			// A sink that cannot re-inject a partial packet loses it
+			if enforcement.reinjects()
+				&& let Err(e) = sink.send(&packet)
+			{
+				stats.record(Verdict::Drop(Reason::Truncated), true);
+				stats.record_error();
+				error!(
+					"Failed to send truncated packet back to network layer: {}",
+					e
+				);
+			} else {
+				stats.record(Verdict::Pass(Reason::Truncated), enforcement.drops());
+			}
+			continue;
+		}
 
-		let (pass, capture) = match sliced_packet.transport {
-			Some(TransportSlice::Udp(udp)) => {
-				let src = SocketAddr::new(src_addr, udp.source_port());
-				let dst = SocketAddr::new(dst_addr, udp.destination_port());
-				let local_port = if !src_addr.is_global() {
-					udp.source_port()
-				} else if !dst_addr.is_global() {
-					udp.destination_port()
+		let info = match PacketInfo::parse(&packet.data) {
+			Ok(info) => info,
+			Err(reason) => {
+				stats.record(Verdict::Drop(reason), enforcement.drops());
+				if reason == Reason::Malformed {
+					error!(
+						"Failed to parse packet headers despite filter match - data length: {}",
+						packet.data.len()
+					);
 				} else {
-					0
-				};
-				let is_process = tracker.is_tracked_udp(local_port);
-				let payload = udp.payload();
-				let size = payload.len();
-
-				let matching_port = local_port == 6672;
-
-				if is_process {
-					if matching_port && HEARTBEAT_SIZES.contains(&size) {
-						debug!("HEARTBEAT PACKET PASSED {} -> {} [L{}]", src, dst, size);
-					} else if matching_port && MATCHMAKING_SIZES.contains(&size) {
-						trace!("MATCHMAKING PACKET BLOCKED {} -> {} [L{}]", src, dst, size);
-					} else {
-						trace!("PROCESS UDP PACKET BLOCKED {} -> {} [L{}]", src, dst, size);
-					}
+					debug!("Skipping non-UDP/TCP or non-IPv4/IPv6 packet from network layer");
 				}
-				(
-					!is_process || matching_port && HEARTBEAT_SIZES.contains(&size),
-					is_process,
-				)
-			}
-			Some(TransportSlice::Tcp(tcp)) => {
-				let src_port = tcp.source_port();
-				let dst_port = tcp.destination_port();
-				let is_process = tracker.is_tracked_tcp(src_port, dst_port);
-				if is_process {
-					let payload = tcp.payload();
-					let size = payload.len();
-					trace!("PROCESS TCP PACKET PASSED {} -> {} [L{}]", src_port, dst_port, size);
+				if enforcement == Enforcement::DryRun
+					&& let Err(e) = sink.send(&packet)
+				{
+					stats.record_error();
+					error!("Failed to send packet back to network layer: {}", e);
 				}
-				(true, is_process)
-			}
-			_ => {
-				debug!(
-					"Skipping non-UDP/TCP packet from network layer: {:?}",
-					sliced_packet.transport
-				);
 				continue;
 			}
 		};
 
-		if capture
-			&& let Some(pcap_writer) = pcap_writer.as_mut() {
-				let timestamp = std::time::SystemTime::now()
-					.duration_since(std::time::SystemTime::UNIX_EPOCH)
-					.unwrap_or_else(|e| {
-						error!("Time went backwards: {}", e);
-						Duration::ZERO
-					});
-				let pcap_packet = PcapPacket::new(timestamp, packet.data.len() as u32, &packet.data);
-				if let Err(e) = pcap_writer.write_packet(&pcap_packet) {
-					error!("Error writing packet to PCAP: {}", e);
+		let classification = classify_info(&info, &packet.meta, &tracker, &policy);
+		let verdict = classification.verdict;
+		stats.record(verdict, enforcement.drops());
+		stats
+			.flows
+			.record(&info, &packet.meta, packet.data.len(), &classification);
+		stats
+			.peers
+			.record(&info, &packet.meta, packet.data.len(), &classification);
+		// Sweep idle flows and peers a few times per timeout, going by packet time
+		let now = packet.meta.timestamp;
+		if now >= next_expiry {
+			stats.flows.expire(now);
+			stats.peers.expire(now);
+			next_expiry = now + stats.flows.idle_timeout() / 4;
+		}
+		let line = PacketLine {
+			info: &info,
+			meta: &packet.meta,
+			verdict,
+			profile: classification.profile.as_deref().unwrap_or("-"),
+		};
+		match verdict.reason() {
+			Reason::Untracked => {}
+			_ if !verdict.is_pass() && !enforcement.drops() => info!("{} (not enforced)", line),
+			Reason::Heartbeat => debug!("{}", line),
+			Reason::Blocklisted => {
+				let note = policy
+					.blocked(info.remote(packet.meta.outbound).ip())
+					.and_then(|peer| peer.note.clone());
+				debug!("{} {}", line, note.as_deref().unwrap_or(""))
+			}
+			_ => trace!("{}", line),
+		}
+
+		if verdict.is_capture()
+			&& capture
+				.selector
+				.matches(&packet.data, &packet.meta, &info, verdict)
+		{
+			let captured = CapturedPacket {
+				data: &packet.data[..packet.data.len().min(snaplen)],
+				orig_len: packet.data.len(),
+				meta: packet.meta,
+				verdict,
+				pid: classification.pid,
+				profile: classification.profile.as_deref(),
+			};
+			if let Some(recorder) = &capture.recorder {
+				recorder.record(&captured);
+			}
+			if let Some(file) = capture.file.as_mut() {
+				match file.write(&captured) {
+					Ok(()) => stats.record_capture(),
+					Err(e) => {
+						stats.record_error();
+						error!("Error writing packet to capture: {}", e);
+					}
 				}
 			}
+		}
 
-		if pass
-			&& let Err(e) = network_divert.send(&packet) {
+		let reinject = verdict.is_pass() || !enforcement.drops();
+		if reinject
+			&& enforcement.reinjects()
+			&& let Err(e) = sink.send(&packet) {
+				stats.record_error();
 				error!("Failed to send packet back to network layer: {}", e);
 			}
 	}
+
+	if let Some(file) = capture.file
+		&& let Err(e) = file.finish()
+	{
+		error!("Error completing capture: {}", e);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+	use std::sync::mpsc::Receiver;
+
+	use etherparse::PacketBuilder;
+
+	use super::*;
+	use crate::classifier::HEARTBEAT_SIZES;
+	use crate::config::GTA5_ENHANCED_PROFILE;
+	use crate::packet_io::{ChannelSink, ChannelSource, Packet, PacketIoError};
+
+	const GAME_PID: u32 = 42;
+	const PEER: [u8; 4] = [203, 0, 113, 7];
+	const LOCAL: [u8; 4] = [192, 168, 1, 2];
+
+	/// Inbound UDP packet from the peer to local port `dst_port` carrying `payload_len` bytes
+	fn inbound_udp(dst_port: u16, payload_len: usize) -> Packet<'static> {
+		let builder = PacketBuilder::ipv4(PEER, LOCAL, 64).udp(6672, dst_port);
+		let mut data = Vec::with_capacity(builder.size(payload_len));
+		builder.write(&mut data, &vec![0; payload_len]).unwrap();
+		Packet::owned(data, PacketMeta::default())
+	}
+
+	fn tracker() -> Arc<ConnectionTracker> {
+		let tracker = ConnectionTracker::new();
+		tracker.add_process(GAME_PID, GTA5_ENHANCED_PROFILE.into());
+		tracker.add_udp_endpoint(GAME_PID, SocketAddr::from(([0, 0, 0, 0], 6672)));
+		Arc::new(tracker)
+	}
+
+	/// Sink refusing partial packets as WinDivert does, forwarding the others to a channel
+	struct DivertSink(ChannelSink);
+
+	impl PacketSink for DivertSink {
+		fn send(&mut self, packet: &Packet<'_>) -> Result<(), PacketIoError> {
+			if packet.is_truncated() {
+				return Err("a partial packet cannot be injected".into());
+			}
+			self.0.send(packet)
+		}
+	}
+
+	/// Run the packet loop over `packets` with `options`, returning the counters
+	fn run_with(
+		packets: Vec<Packet<'static>>, options: LoopOptions, sink: impl PacketSink,
+	) -> Arc<Stats> {
+		let (sender, source) = ChannelSource::pair();
+		for packet in packets {
+			sender.send(packet).unwrap();
+		}
+		drop(sender);
+
+		let stats = Arc::new(Stats::new());
+		process_packets(
+			source,
+			sink,
+			tracker(),
+			Arc::new(Policy::default()),
+			Arc::clone(&stats),
+			options,
+			CaptureTargets::default(),
+		);
+		stats
+	}
+
+	/// Run the packet loop over `packets`, returning the re-injected packets and the counters
+	fn run(
+		packets: Vec<Packet<'static>>, enforcement: Enforcement,
+	) -> (Receiver<Packet<'static>>, Arc<Stats>) {
+		let (sink, reinjected) = ChannelSink::pair();
+		let options = LoopOptions {
+			enforcement,
+			..Default::default()
+		};
+		(reinjected, run_with(packets, options, sink))
+	}
+
+	/// Matchmaking packet of the game cut short, which would be dropped if it were classified
+	fn truncated_matchmaking() -> Packet<'static> {
+		let mut packet = inbound_udp(6672, 191);
+		packet.data.to_mut().truncate(100);
+		packet
+	}
+
+	#[test]
+	fn enforce_passes_heartbeats_and_untracked_traffic() {
+		let heartbeat = inbound_udp(6672, HEARTBEAT_SIZES[0]);
+		let matchmaking = inbound_udp(6672, 191);
+		let untracked = inbound_udp(5000, 191);
+		let (reinjected, stats) = run(
+			vec![heartbeat.clone(), matchmaking, untracked.clone()],
+			Enforcement::Enforce,
+		);
+
+		let reinjected: Vec<_> = reinjected.try_iter().map(|packet| packet.data).collect();
+		assert_eq!(reinjected, [heartbeat.data, untracked.data]);
+		let snapshot = stats.snapshot();
+		assert_eq!(snapshot.received, 3);
+		assert_eq!(snapshot.passed, 2);
+		assert_eq!(snapshot.dropped, 1);
+		assert_eq!(snapshot.would_drop, 0);
+		assert_eq!(snapshot.errors, 0);
+		assert_eq!(stats.reason(Reason::Heartbeat), 1);
+		assert_eq!(stats.reason(Reason::Matchmaking), 1);
+		assert_eq!(stats.reason(Reason::Untracked), 1);
+		assert_eq!(snapshot.flows, 2);
+		assert_eq!(snapshot.peers, 1);
+	}
+
+	#[test]
+	fn dry_run_reinjects_dropped_packets() {
+		let (reinjected, stats) = run(vec![inbound_udp(6672, 191)], Enforcement::DryRun);
+
+		assert_eq!(reinjected.try_iter().count(), 1);
+		let snapshot = stats.snapshot();
+		assert_eq!(snapshot.passed, 1);
+		assert_eq!(snapshot.dropped, 0);
+		assert_eq!(snapshot.would_drop, 1);
+	}
+
+	#[test]
+	fn sniff_reinjects_nothing() {
+		let (reinjected, stats) = run(vec![inbound_udp(5000, 12)], Enforcement::Sniff);
+
+		assert_eq!(reinjected.try_iter().count(), 0);
+		assert_eq!(stats.snapshot().passed, 1);
+	}
+
+	#[test]
+	fn truncated_packets_pass_unclassified() {
+		let oversized = inbound_udp(6672, 1472);
+		let options = LoopOptions {
+			enforcement: Enforcement::Sniff,
+			buffer_size: MIN_BUFFER_SIZE,
+			..Default::default()
+		};
+		let (sink, reinjected) = ChannelSink::pair();
+		let stats = run_with(vec![truncated_matchmaking(), oversized], options, sink);
+
+		assert_eq!(reinjected.try_iter().count(), 0);
+		let snapshot = stats.snapshot();
+		assert_eq!(snapshot.received, 2);
+		assert_eq!(snapshot.passed, 2);
+		assert_eq!(snapshot.dropped, 0);
+		assert_eq!(stats.reason(Reason::Truncated), 2);
+		assert_eq!(stats.reason(Reason::Matchmaking), 0);
+	}
+
+	#[test]
+	fn reinjecting_modes_receive_whole_packets() {
+		for enforcement in [Enforcement::Enforce, Enforcement::DryRun] {
+			let large = inbound_udp(5000, 1472);
+			let options = LoopOptions {
+				enforcement,
+				buffer_size: MIN_BUFFER_SIZE,
+				..Default::default()
+			};
+			let (sink, reinjected) = ChannelSink::pair();
+			let stats = run_with(vec![large.clone()], options, DivertSink(sink));
+
+			let reinjected: Vec<_> = reinjected.try_iter().map(|packet| packet.data).collect();
+			assert_eq!(reinjected, [large.data], "{:?}", enforcement);
+			assert_eq!(stats.reason(Reason::Untracked), 1, "{:?}", enforcement);
+			assert_eq!(stats.snapshot().errors, 0, "{:?}", enforcement);
+		}
+	}
+
+	#[test]
+	fn truncated_packets_that_cannot_be_reinjected_count_as_dropped() {
+		for enforcement in [Enforcement::Enforce, Enforcement::DryRun] {
+			let options = LoopOptions {
+				enforcement,
+				..Default::default()
+			};
+			let (sink, reinjected) = ChannelSink::pair();
+			let stats = run_with(vec![truncated_matchmaking()], options, DivertSink(sink));
+
+			assert_eq!(reinjected.try_iter().count(), 0, "{:?}", enforcement);
+			let snapshot = stats.snapshot();
+			assert_eq!(snapshot.passed, 0, "{:?}", enforcement);
+			assert_eq!(snapshot.dropped, 1, "{:?}", enforcement);
+			assert_eq!(snapshot.would_drop, 0, "{:?}", enforcement);
+			assert_eq!(snapshot.errors, 1, "{:?}", enforcement);
+			assert_eq!(stats.reason(Reason::Truncated), 1, "{:?}", enforcement);
+		}
+	}
 }
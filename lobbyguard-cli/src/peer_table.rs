@@ -0,0 +1,280 @@
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::classifier::{Classification, PacketInfo, Policy, Protocol, Reason};
+use crate::flow_table::{DEFAULT_IDLE_TIMEOUT, serialize_secs};
+use crate::packet_io::PacketMeta;
+
+/// Columns of [`to_csv`], in order
+const CSV_HEADER: &str =
+	"ip,first_seen,last_seen,packets,bytes,heartbeats,matchmaking_blocked,allowlisted,blocklisted";
+
+/// Remote player exchanging game traffic with a tracked process
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Peer {
+	pub ip: IpAddr,
+	/// Timestamp of the first packet, as a duration since the UNIX epoch
+	#[serde(serialize_with = "serialize_secs")]
+	pub first_seen: Duration,
+	/// Timestamp of the latest packet, as a duration since the UNIX epoch
+	#[serde(serialize_with = "serialize_secs")]
+	pub last_seen: Duration,
+	/// Packets in both directions
+	pub packets: u64,
+	/// Sum of the IP packet lengths in both directions
+	pub bytes: u64,
+	/// Packets let through as heartbeats
+	pub heartbeats: u64,
+	/// Matchmaking requests dropped
+	pub matchmaking_blocked: u64,
+	/// Whether the peer is on the allowlist at the time of the snapshot
+	pub allowlisted: bool,
+	/// Whether the peer is on the blocklist at the time of the snapshot
+	pub blocklisted: bool,
+}
+
+impl Peer {
+	fn new(ip: IpAddr, timestamp: Duration) -> Self {
+		Self {
+			ip,
+			first_seen: timestamp,
+			last_seen: timestamp,
+			packets: 0,
+			bytes: 0,
+			heartbeats: 0,
+			matchmaking_blocked: 0,
+			allowlisted: false,
+			blocklisted: false,
+		}
+	}
+}
+
+/// Remote players seen in the tracked UDP traffic, forgotten once idle
+#[derive(Debug)]
+pub struct PeerTable {
+	/// Forget a peer once this long passed since its latest packet
+	idle_timeout: Duration,
+	peers: DashMap<IpAddr, Peer>,
+}
+
+impl PeerTable {
+	/// Empty table forgetting peers idle for `idle_timeout`
+	pub fn new(idle_timeout: Duration) -> Self {
+		Self {
+			idle_timeout,
+			peers: DashMap::new(),
+		}
+	}
+
+	/// Account a packet of `len` bytes, ignoring all but the UDP traffic of tracked processes
+	pub fn record(
+		&self, info: &PacketInfo, meta: &PacketMeta, len: usize, classification: &Classification,
+	) {
+		if info.protocol != Protocol::Udp || classification.pid.is_none() {
+			return;
+		}
+		let ip = info.remote(meta.outbound).ip().to_canonical();
+		let mut peer = self
+			.peers
+			.entry(ip)
+			.or_insert_with(|| Peer::new(ip, meta.timestamp));
+		peer.last_seen = peer.last_seen.max(meta.timestamp);
+		peer.packets += 1;
+		peer.bytes += len as u64;
+		let verdict = classification.verdict;
+		match verdict.reason() {
+			Reason::Heartbeat if verdict.is_pass() => peer.heartbeats += 1,
+			Reason::Matchmaking if !verdict.is_pass() => peer.matchmaking_blocked += 1,
+			_ => {}
+		}
+	}
+
+	/// Forget the peers idle at `now`, returning how many were removed
+	pub fn expire(&self, now: Duration) -> usize {
+		let before = self.peers.len();
+		self
+			.peers
+			.retain(|_, peer| peer.last_seen.saturating_add(self.idle_timeout) >= now);
+		before.saturating_sub(self.peers.len())
+	}
+
+	/// Number of peers held
+	pub fn len(&self) -> usize { self.peers.len() }
+
+	/// Whether no peer is held
+	pub fn is_empty(&self) -> bool { self.peers.is_empty() }
+
+	/// Copy of every peer held, ordered by address, with its standing in `policy`
+	pub fn snapshot(&self, policy: &Policy) -> Vec<Peer> {
+		let mut peers: Vec<_> = self
+			.peers
+			.iter()
+			.map(|peer| Peer {
+				allowlisted: policy.is_allowlisted(peer.ip),
+				blocklisted: policy.blocked(peer.ip).is_some(),
+				..peer.value().clone()
+			})
+			.collect();
+		peers.sort_unstable_by_key(|peer| peer.ip);
+		peers
+	}
+}
+
+impl Default for PeerTable {
+	fn default() -> Self { Self::new(DEFAULT_IDLE_TIMEOUT) }
+}
+
+/// Peers as CSV text with a header row
+pub fn to_csv(peers: &[Peer]) -> String {
+	let mut csv = format!("{}\n", CSV_HEADER);
+	for peer in peers {
+		// Writing to a `String` cannot fail
+		let _ = writeln!(
+			csv,
+			"{},{:.6},{:.6},{},{},{},{},{},{}",
+			peer.ip,
+			peer.first_seen.as_secs_f64(),
+			peer.last_seen.as_secs_f64(),
+			peer.packets,
+			peer.bytes,
+			peer.heartbeats,
+			peer.matchmaking_blocked,
+			peer.allowlisted,
+			peer.blocklisted
+		);
+	}
+	csv
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use super::*;
+	use crate::classifier::{BlockedPeer, Verdict};
+
+	const LOCAL: &str = "192.168.1.2:6672";
+	const PEER: &str = "203.0.113.7:6672";
+
+	fn packet(protocol: Protocol, outbound: bool, secs: u64) -> (PacketInfo, PacketMeta) {
+		let (local, remote) = (LOCAL.parse().unwrap(), PEER.parse().unwrap());
+		let (src, dst) = if outbound {
+			(local, remote)
+		} else {
+			(remote, local)
+		};
+		let info = PacketInfo {
+			protocol,
+			src,
+			dst,
+			payload_len: 0,
+		};
+		let meta = PacketMeta {
+			outbound,
+			timestamp: Duration::from_secs(secs),
+			..Default::default()
+		};
+		(info, meta)
+	}
+
+	fn tracked(verdict: Verdict) -> Classification {
+		Classification {
+			verdict,
+			pid: Some(42),
+			profile: Some(Arc::from("gta5")),
+		}
+	}
+
+	fn record(table: &PeerTable, outbound: bool, secs: u64, len: usize, verdict: Verdict) {
+		let (info, meta) = packet(Protocol::Udp, outbound, secs);
+		table.record(&info, &meta, len, &tracked(verdict));
+	}
+
+	#[test]
+	fn skip_tcp_and_untracked_traffic() {
+		let table = PeerTable::default();
+		let (info, meta) = packet(Protocol::Tcp, false, 1);
+		table.record(
+			&info,
+			&meta,
+			60,
+			&tracked(Verdict::Pass(Reason::TcpPassthrough)),
+		);
+		let (info, meta) = packet(Protocol::Udp, false, 1);
+		let untracked = Classification {
+			verdict: Verdict::Pass(Reason::Untracked),
+			pid: None,
+			profile: None,
+		};
+		table.record(&info, &meta, 60, &untracked);
+		assert!(table.is_empty());
+	}
+
+	#[test]
+	fn count_heartbeats_and_blocked_matchmaking() {
+		let table = PeerTable::default();
+		record(&table, false, 10, 40, Verdict::Pass(Reason::Heartbeat));
+		record(&table, true, 11, 40, Verdict::Pass(Reason::Heartbeat));
+		record(&table, false, 12, 219, Verdict::Drop(Reason::Matchmaking));
+		// Let through in open mode, so not a blocked request
+		record(&table, false, 13, 219, Verdict::Pass(Reason::Matchmaking));
+		record(&table, false, 14, 100, Verdict::Pass(Reason::TrackedUdp));
+
+		let peers = table.snapshot(&Policy::default());
+		let [peer] = peers.as_slice() else {
+			panic!("expected a single peer, got {:?}", peers);
+		};
+		assert_eq!(peer.ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+		assert_eq!((peer.packets, peer.bytes), (5, 618));
+		assert_eq!((peer.heartbeats, peer.matchmaking_blocked), (2, 1));
+		assert_eq!(peer.first_seen, Duration::from_secs(10));
+		assert_eq!(peer.last_seen, Duration::from_secs(14));
+	}
+
+	#[test]
+	fn expire_after_the_idle_timeout() {
+		let table = PeerTable::new(Duration::from_secs(60));
+		record(&table, false, 100, 40, Verdict::Pass(Reason::Heartbeat));
+
+		let deadline = Duration::from_secs(160);
+		assert_eq!(table.expire(deadline), 0);
+		assert_eq!(table.len(), 1);
+		assert_eq!(table.expire(deadline + Duration::from_nanos(1)), 1);
+		assert!(table.is_empty());
+	}
+
+	#[test]
+	fn csv_has_a_header_and_a_row_per_peer() {
+		let table = PeerTable::default();
+		record(&table, false, 10, 40, Verdict::Pass(Reason::Heartbeat));
+		let (mut info, meta) = packet(Protocol::Udp, false, 12);
+		info.src = "[2001:db8::9]:6672".parse().unwrap();
+		table.record(
+			&info,
+			&meta,
+			219,
+			&tracked(Verdict::Drop(Reason::Blocklisted)),
+		);
+		let mut policy = Policy::default();
+		policy.allow("203.0.113.0/24".parse().unwrap());
+		policy.blocklist.insert(
+			"2001:db8::/32".parse().unwrap(),
+			BlockedPeer {
+				expires: None,
+				note: None,
+			},
+		);
+
+		assert_eq!(
+			to_csv(&table.snapshot(&policy)),
+			"ip,first_seen,last_seen,packets,bytes,heartbeats,matchmaking_blocked,allowlisted,blocklisted\n\
+			 203.0.113.7,10.000000,10.000000,1,40,1,0,true,false\n\
+			 2001:db8::9,12.000000,12.000000,1,219,0,0,false,true\n"
+		);
+		assert_eq!(to_csv(&[]), format!("{}\n", CSV_HEADER));
+	}
+}
@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::classifier::{Reason, Verdict};
+use crate::flow_table::FlowTable;
+use crate::peer_table::PeerTable;
+
+/// Packet counters shared between the packet loop and the control API
+#[derive(Debug, Default)]
+pub struct Stats {
+	/// Packets received from the packet source
+	pub received: AtomicU64,
+	/// Packets let through to their destination
+	pub passed: AtomicU64,
+	/// Packets discarded
+	pub dropped: AtomicU64,
+	/// Packets with a drop verdict let through because enforcement is off
+	pub would_drop: AtomicU64,
+	/// Packets written to the capture
+	pub captured: AtomicU64,
+	/// Packets that failed to be re-injected or captured
+	pub errors: AtomicU64,
+	/// Packets per verdict reason, indexed like [`Reason::ALL`]
+	reasons: [AtomicU64; Reason::ALL.len()],
+	/// Packets and bytes per flow
+	pub flows: FlowTable,
+	/// Remote players of the tracked game traffic
+	pub peers: PeerTable,
+}
+
+impl Stats {
+	/// Create zeroed counters
+	pub fn new() -> Self { Self::default() }
+
+	/// Create zeroed counters forgetting flows and peers idle for `idle_timeout`
+	pub fn with_flow_timeout(idle_timeout: Duration) -> Self {
+		Self {
+			flows: FlowTable::new(idle_timeout),
+			peers: PeerTable::new(idle_timeout),
+			..Self::default()
+		}
+	}
+
+	/// Count a packet that received `verdict`, discarded only if `enforced`
+	pub fn record(&self, verdict: Verdict, enforced: bool) {
+		self.received.fetch_add(1, Ordering::Relaxed);
+		if verdict.is_pass() {
+			self.passed.fetch_add(1, Ordering::Relaxed);
+		} else if enforced {
+			self.dropped.fetch_add(1, Ordering::Relaxed);
+		} else {
+			self.passed.fetch_add(1, Ordering::Relaxed);
+			self.would_drop.fetch_add(1, Ordering::Relaxed);
+		}
+		self.reasons[verdict.reason() as usize].fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Count a packet written to the capture
+	pub fn record_capture(&self) { self.captured.fetch_add(1, Ordering::Relaxed); }
+
+	/// Count a packet that failed to be re-injected or captured
+	pub fn record_error(&self) { self.errors.fetch_add(1, Ordering::Relaxed); }
+
+	/// Packets that received a verdict for `reason`
+	pub fn reason(&self, reason: Reason) -> u64 {
+		self.reasons[reason as usize].load(Ordering::Relaxed)
+	}
+
+	/// Point-in-time copy of every counter
+	pub fn snapshot(&self) -> StatsSnapshot {
+		StatsSnapshot {
+			received: self.received.load(Ordering::Relaxed),
+			passed: self.passed.load(Ordering::Relaxed),
+			dropped: self.dropped.load(Ordering::Relaxed),
+			would_drop: self.would_drop.load(Ordering::Relaxed),
+			captured: self.captured.load(Ordering::Relaxed),
+			errors: self.errors.load(Ordering::Relaxed),
+			flows: self.flows.len(),
+			peers: self.peers.len(),
+			reasons: Reason::ALL
+				.into_iter()
+				.map(|reason| (reason.as_str(), self.reason(reason)))
+				.collect(),
+		}
+	}
+}
+
+/// Values of the [`Stats`] counters at one point in time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatsSnapshot {
+	pub received: u64,
+	pub passed: u64,
+	pub dropped: u64,
+	pub would_drop: u64,
+	pub captured: u64,
+	pub errors: u64,
+	/// Flows seen and not yet expired
+	pub flows: usize,
+	/// Peers seen and not yet expired
+	pub peers: usize,
+	/// Packets per verdict reason name
+	pub reasons: BTreeMap<&'static str, u64>,
+}
@@ -1,6 +1,18 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+
+/// Parse a textual address reported by WMI, dropping the `%zone` suffix of link-local ones
+pub fn parse_ip(address: &str) -> Option<IpAddr> {
+	address.split('%').next().unwrap_or_default().parse().ok()
+}
+
+/// Deserialize an address that may carry a `%zone` suffix
+fn deserialize_ip<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IpAddr, D::Error> {
+	let address = String::deserialize(deserializer)?;
+	parse_ip(&address)
+		.ok_or_else(|| serde::de::Error::custom(format!("invalid IP address `{}`", address)))
+}
 
 #[derive(Deserialize, Debug)]
 #[serde(rename = "__InstanceCreationEvent")]
@@ -28,7 +40,10 @@ pub struct Process {
 #[serde(rename = "MSFT_NetTCPConnection")]
 #[serde(rename_all = "PascalCase")]
 pub struct NetTCPConnection {
+	#[serde(deserialize_with = "deserialize_ip")]
+	pub local_address: IpAddr,
 	pub local_port: u16,
+	#[serde(deserialize_with = "deserialize_ip")]
 	pub remote_address: IpAddr,
 	pub remote_port: u16,
 	pub owning_process: u32,
@@ -38,10 +53,35 @@ pub struct NetTCPConnection {
 #[serde(rename = "MSFT_NetUDPEndpoint")]
 #[serde(rename_all = "PascalCase")]
 pub struct NetUDPEndpoint {
+	#[serde(deserialize_with = "deserialize_ip")]
+	pub local_address: IpAddr,
 	pub local_port: u16,
 	pub owning_process: u32,
 }
 
+impl NetTCPConnection {
+	/// Address and port of this machine's end
+	pub fn local(&self) -> SocketAddr { SocketAddr::new(self.local_address, self.local_port) }
+
+	/// Address and port of the peer's end
+	pub fn remote(&self) -> SocketAddr { SocketAddr::new(self.remote_address, self.remote_port) }
+}
+
+impl NetUDPEndpoint {
+	/// Address and port the endpoint is bound to
+	pub fn local(&self) -> SocketAddr { SocketAddr::new(self.local_address, self.local_port) }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename = "MSFT_NetIPAddress")]
+#[serde(rename_all = "PascalCase")]
+pub struct NetIPAddress {
+	/// Textual address, with a `%zone` suffix on some link-local addresses
+	#[serde(rename = "IPAddress")]
+	pub ip_address: String,
+	pub interface_index: u32,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename = "__InstanceCreationEvent")]
 #[serde(rename_all = "PascalCase")]
@@ -1,30 +1,37 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 
 use futures::StreamExt;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 
-use crate::connection_tracker::ConnectionTracker;
+use crate::config::Config;
+use crate::connection_tracker::{ConnectionTracker, Correction, TcpConnection};
 use crate::wmi::models::*;
 
 /// Initialize WMI connections and query existing processes/connections
 pub fn initialize_wmi(
-	tracker: Arc<ConnectionTracker>,
+	tracker: Arc<ConnectionTracker>, config: &Config,
 ) -> Result<(wmi::WMIConnection, wmi::WMIConnection), Box<dyn std::error::Error>> {
 	let default_con = wmi::WMIConnection::new()?;
 	let standard_con = wmi::WMIConnection::with_namespace_path("ROOT\\StandardCIMV2")?;
 
-	// Find existing GTA processes
-	let mut filters = HashMap::new();
-	filters.insert(
-		"Name".to_owned(),
-		wmi::FilterValue::Str("GTA5_Enhanced.exe"),
-	);
-	if let Ok(processes) = default_con.filtered_query::<Process>(&filters) {
-		for process in processes {
-			info!("Found process: {} ({})", process.name, process.process_id);
-			tracker.add_process(process.process_id);
+	// Find existing game processes of every active profile
+	for (profile_name, profile) in config.active() {
+		let profile_name: Arc<str> = Arc::from(profile_name);
+		for name in &profile.process_names {
+			let mut filters = HashMap::new();
+			filters.insert("Name".to_owned(), wmi::FilterValue::String(name.clone()));
+			if let Ok(processes) = default_con.filtered_query::<Process>(&filters) {
+				for process in processes {
+					info!(
+						"Found process: {} ({}) [{}]",
+						process.name, process.process_id, profile_name
+					);
+					tracker.add_process(process.process_id, Arc::clone(&profile_name));
+				}
+			}
 		}
 	}
 
@@ -34,11 +41,7 @@ pub fn initialize_wmi(
 		debug!("Queried {} TCP connections from WMI", count);
 		for tcp in tcps {
 			if tracker.contains_process(tcp.owning_process) {
-				tracker.add_tcp_connection(
-					tcp.owning_process,
-					tcp.local_port,
-					tcp.remote_port,
-				);
+				tracker.add_tcp_connection(tcp.owning_process, tcp.local(), tcp.remote());
 			}
 		}
 	}
@@ -49,7 +52,7 @@ pub fn initialize_wmi(
 		debug!("Queried {} UDP endpoints from WMI", count);
 		for udp in udps {
 			if tracker.contains_process(udp.owning_process) {
-				tracker.add_udp_endpoint(udp.owning_process, udp.local_port);
+				tracker.add_udp_endpoint(udp.owning_process, udp.local());
 			}
 		}
 	}
@@ -57,10 +60,84 @@ pub fn initialize_wmi(
 	Ok((default_con, standard_con))
 }
 
-/// Run the WMI event monitoring loop
+/// Query the addresses assigned to every network interface of this machine
+pub fn query_local_addresses() -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+	let standard_con = wmi::WMIConnection::with_namespace_path("ROOT\\StandardCIMV2")?;
+	let addresses = standard_con.query::<NetIPAddress>()?;
+	debug!("Queried {} interface addresses from WMI", addresses.len());
+	let addresses = addresses
+		.iter()
+		.filter_map(|address| parse_ip(&address.ip_address));
+	Ok(addresses.collect())
+}
+
+/// Re-query every game process and connection and repair the tracker where it drifted
+///
+/// Runs the same queries as [`initialize_wmi`]; returns the number of corrections made.
+pub async fn reconcile(
+	default_con: &wmi::WMIConnection, standard_con: &wmi::WMIConnection,
+	tracker: &ConnectionTracker, config: &Config,
+) -> Result<usize, Box<dyn std::error::Error>> {
+	let mut processes = HashMap::new();
+	for (profile_name, profile) in config.active() {
+		let profile_name: Arc<str> = Arc::from(profile_name);
+		for name in &profile.process_names {
+			let mut filters = HashMap::new();
+			filters.insert("Name".to_owned(), wmi::FilterValue::String(name.clone()));
+			for process in default_con.async_filtered_query::<Process>(&filters).await? {
+				processes.insert(process.process_id, (process.name, Arc::clone(&profile_name)));
+			}
+		}
+	}
+	let tcps: HashSet<(u32, TcpConnection)> = standard_con
+		.async_query::<NetTCPConnection>()
+		.await?
+		.iter()
+		.map(|tcp| (tcp.owning_process, TcpConnection::new(tcp.local(), tcp.remote())))
+		.collect();
+	let udps: HashSet<(u32, SocketAddr)> = standard_con
+		.async_query::<NetUDPEndpoint>()
+		.await?
+		.iter()
+		.map(|udp| (udp.owning_process, udp.local()))
+		.collect();
+
+	let profiles: HashMap<_, _> = processes
+		.iter()
+		.map(|(pid, (_, profile))| (*pid, Arc::clone(profile)))
+		.collect();
+	let corrections = tracker.corrections(&profiles, &tcps, &udps);
+	for correction in &corrections {
+		match correction {
+			Correction::Untrack { pid } => warn!("Untracking PID {} whose exit was missed", pid),
+			Correction::Track { pid, profile } => {
+				let name = processes.get(pid).map_or("?", |(name, _)| name.as_str());
+				warn!("Tracking missed process {} ({}) [{}]", name, pid, profile)
+			}
+			Correction::RemoveTcp { pid, connection } => warn!(
+				"Removing stale TCP connection of PID {}: local:{} <=> remote:{}",
+				pid, connection.local, connection.remote
+			),
+			Correction::RemoveUdp { pid, local } => {
+				warn!("Removing stale UDP endpoint of PID {}: local:{}", pid, local)
+			}
+			Correction::AddTcp { pid, connection } => warn!(
+				"Adding missed TCP connection of PID {}: local:{} <=> remote:{}",
+				pid, connection.local, connection.remote
+			),
+			Correction::AddUdp { pid, local } => {
+				warn!("Adding missed UDP endpoint of PID {}: local:{}", pid, local)
+			}
+		}
+		tracker.apply(correction);
+	}
+	Ok(corrections.len())
+}
+
+/// Run the WMI event monitoring loop, reconciling the tracker every `reconcile_interval`
 pub async fn run_wmi_monitor(
 	default_con: wmi::WMIConnection, standard_con: wmi::WMIConnection,
-	tracker: Arc<ConnectionTracker>,
+	tracker: Arc<ConnectionTracker>, config: &Config, reconcile_interval: Duration,
 ) -> Result<(), Box<dyn std::error::Error>> {
 	// Set up process event streams
 	let mut filters = HashMap::new();
@@ -113,6 +190,13 @@ pub async fn run_wmi_monitor(
 			Some(Duration::from_secs(1)),
 		)?;
 
+	// Events can be missed or dropped, so the tracker is checked against a full query now and then
+	let mut sweep = tokio::time::interval_at(
+		tokio::time::Instant::now() + reconcile_interval,
+		reconcile_interval,
+	);
+	sweep.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
 	info!("Press Ctrl-C to exit.");
 
 	// Notes: 
@@ -124,17 +208,17 @@ pub async fn run_wmi_monitor(
 				let process = event.target_instance;
 				let process_id = process.process_id;
 				let process_name = process.name;
-				if process_name == "GTA5_Enhanced.exe" {
-					info!("Process {} ({}) created", process_name, process_id);
-					tracker.add_process(process_id);
+				if let Some(profile) = config.profile_for_process(&process_name) {
+					info!("Process {} ({}) created [{}]", process_name, process_id, profile);
+					tracker.add_process(process_id, Arc::from(profile));
 				}
 			}
 			Some(Ok(event)) = process_delete_events.next() => {
 				let process = event.target_instance;
 				let process_id = process.process_id;
 				let process_name = process.name;
-				if process_name == "GTA5_Enhanced.exe" {
-					info!("Process {} ({}) deleted", process_name, process_id);
+				if let Some(profile) = config.profile_for_process(&process_name) {
+					info!("Process {} ({}) deleted [{}]", process_name, process_id, profile);
 				}
 				tracker.remove_process(process_id);
 			}
@@ -142,14 +226,14 @@ pub async fn run_wmi_monitor(
 				let udp = event.target_instance;
 				if tracker.contains_process(udp.owning_process) {
 					trace!("UDP connection created for PID {:?}", udp);
-					tracker.add_udp_endpoint(udp.owning_process, udp.local_port);
+					tracker.add_udp_endpoint(udp.owning_process, udp.local());
 				}
 			}
 			Some(Ok(event)) = udp_delete_events.next() => {
 				let udp = event.target_instance;
 				if tracker.contains_process(udp.owning_process) {
 					trace!("UDP connection deleted for PID {:?}", udp);
-					tracker.remove_udp_endpoint(udp.owning_process, udp.local_port);
+					tracker.remove_udp_endpoint(udp.owning_process, udp.local());
 				}
 			}
 			Some(Ok(event)) = udp_update_events.next() => {
@@ -157,9 +241,9 @@ pub async fn run_wmi_monitor(
 				let previous_udp = event.previous_instance;
 				if tracker.contains_process(previous_udp.owning_process) {
 					trace!("UDP connection updated for PID {:?}->{:?}", previous_udp, udp);
-					tracker.remove_udp_endpoint(previous_udp.owning_process, previous_udp.local_port);
+					tracker.remove_udp_endpoint(previous_udp.owning_process, previous_udp.local());
 					if tracker.contains_process(udp.owning_process) {
-						tracker.add_udp_endpoint(udp.owning_process, udp.local_port);
+						tracker.add_udp_endpoint(udp.owning_process, udp.local());
 					}
 				}
 			}
@@ -167,22 +251,14 @@ pub async fn run_wmi_monitor(
 				let tcp = event.target_instance;
 				if tracker.contains_process(tcp.owning_process) {
 					trace!("TCP connection created for PID {:?}", tcp);
-					tracker.add_tcp_connection(
-						tcp.owning_process,
-						tcp.local_port,
-						tcp.remote_port,
-					);
+					tracker.add_tcp_connection(tcp.owning_process, tcp.local(), tcp.remote());
 				}
 			}
 			Some(Ok(event)) = tcp_delete_events.next() => {
 				let tcp = event.target_instance;
 				if tracker.contains_process(tcp.owning_process) {
 					trace!("TCP connection deleted for PID {:?}", tcp);
-					tracker.remove_tcp_connection(
-						tcp.owning_process,
-						tcp.local_port,
-						tcp.remote_port,
-					);
+					tracker.remove_tcp_connection(tcp.owning_process, tcp.local(), tcp.remote());
 				}
 			}
 			Some(Ok(event)) = tcp_update_events.next() => {
@@ -190,20 +266,19 @@ pub async fn run_wmi_monitor(
 				let previous_tcp = event.previous_instance;
 				if previous_tcp.owning_process != tcp.owning_process && tracker.contains_process(previous_tcp.owning_process) {
 					trace!("TCP connection updated for PID {:?}->{:?}", previous_tcp, tcp);
-						tracker.remove_tcp_connection(
-							previous_tcp.owning_process,
-							previous_tcp.local_port,
-							previous_tcp.remote_port,
-						);
+						tracker.remove_tcp_connection(previous_tcp.owning_process, previous_tcp.local(), previous_tcp.remote());
 					if tracker.contains_process(tcp.owning_process) {
-						tracker.add_tcp_connection(
-							tcp.owning_process,
-							tcp.local_port,
-							tcp.remote_port,
-						);
+						tracker.add_tcp_connection(tcp.owning_process, tcp.local(), tcp.remote());
 					}
 				}
 			}
+			_ = sweep.tick() => {
+				match reconcile(&default_con, &standard_con, &tracker, config).await {
+					Ok(0) => trace!("Tracker matches WMI"),
+					Ok(corrections) => info!("Repaired {} drifts of the tracker from WMI", corrections),
+					Err(e) => warn!("Failed to reconcile the tracker with WMI: {}", e),
+				}
+			}
 			_ = tokio::signal::ctrl_c() => {
 				info!("Ctrl-C received! Exiting gracefully.");
 				break;